@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use crate::constants::TOM;
+use crate::instruction::{decode, Instruction, Operand};
+
+/**
+ * A straight run of instructions that is only ever entered at `start`
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start:u16,
+    pub end:u16,            // address just past the last instruction
+    pub successors:Vec<u16>,    // blocks control can go to next. Calls aren't included, see `calls`
+    pub calls:Vec<u16>,     // literal `call` targets inside the block
+}
+
+/**
+ * A `call` target (or an entry point) and the blocks reachable from it without
+ * following calls. Blocks can be shared between functions
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub entry:u16,
+    pub blocks:Vec<u16>,    // start addresses, sorted
+    pub calls:Vec<u16>,     // entries of the functions this one calls, sorted
+}
+
+/**
+ * The result of following control flow through an image. Anything that isn't part of
+ * a decoded instruction is considered data
+ */
+pub struct Analysis {
+    instructions:BTreeMap<u16, (Instruction, u16)>,
+    blocks:BTreeMap<u16, BasicBlock>,
+    functions:BTreeMap<u16, Function>,
+}
+
+/**
+ * Where control can go after an instruction that ends at `next`
+ */
+struct Flow {
+    successors:Vec<u16>,
+    call:Option<u16>,
+    ends_block:bool,
+}
+
+fn flow(instruction:&Instruction, next:u16) -> Flow {
+    use Instruction::*;
+
+    let (successors, call, ends_block) = match *instruction {
+        Halt | Ret => (vec![], None, true),
+        Jmp(Operand::Literal(target)) => (vec![target], None, true),
+        Jmp(Operand::Register(_)) => (vec![], None, true),
+        Jt(_, Operand::Literal(target)) | Jf(_, Operand::Literal(target)) => (vec![next, target], None, true),
+        Jt(_, Operand::Register(_)) | Jf(_, Operand::Register(_)) => (vec![next], None, true),
+        Call(Operand::Literal(target)) => (vec![next], Some(target), false),
+        _ => (vec![next], None, false),
+    };
+    Flow { successors, call, ends_block }
+}
+
+/**
+ * Follows `jmp`, `jt`, `jf`, `call` and fall-through edges from address 0 and `entries`,
+ * decoding every instruction it reaches. Jumps and calls through registers can't be
+ * followed, so code that's only reached that way needs to be passed in `entries`
+ */
+pub fn analyze(words:&[u16], entries:&[u16]) -> Analysis {
+    let len = words.len().min(TOM);
+    let words = &words[..len];
+    let mut instructions:BTreeMap<u16, (Instruction, u16)> = BTreeMap::new();
+    let mut owner:Vec<Option<u16>> = vec![None; len];  // start of the instruction covering each word
+    let mut leaders:BTreeSet<u16> = BTreeSet::new();
+    let mut function_entries:BTreeSet<u16> = BTreeSet::new();
+    let mut work:Vec<u16> = Vec::new();
+
+    for entry in std::iter::once(0).chain(entries.iter().copied()) {
+        if (entry as usize) < len {
+            leaders.insert(entry);
+            function_entries.insert(entry);
+            work.push(entry);
+        }
+    }
+
+    while let Some(addr) = work.pop() {
+        if owner[addr as usize].is_some() {
+            continue;   // already decoded, or the middle of another instruction
+        }
+        let (instruction, size) = match decode(words, addr) {
+            Ok(decoded) => decoded,
+            Err(_) => continue,
+        };
+        let next = addr as usize + size as usize;
+        if owner[addr as usize..next].iter().any(|o| o.is_some()) {
+            continue;
+        }
+        for o in owner[addr as usize..next].iter_mut() {
+            *o = Some(addr);
+        }
+        instructions.insert(addr, (instruction, size));
+
+        let flow = flow(&instruction, next as u16);
+        for target in flow.successors {
+            if (target as usize) < len {
+                if flow.ends_block {
+                    leaders.insert(target);
+                }
+                work.push(target);
+            }
+        }
+        if let Some(target) = flow.call {
+            if (target as usize) < len {
+                leaders.insert(target);
+                function_entries.insert(target);
+                work.push(target);
+            }
+        }
+    }
+
+    let mut blocks:BTreeMap<u16, BasicBlock> = BTreeMap::new();
+    for &start in leaders.iter().filter(|addr| instructions.contains_key(addr)) {
+        let mut block = BasicBlock { start, end: start, successors: vec![], calls: vec![] };
+        let mut addr = start;
+        while let Some((instruction, size)) = instructions.get(&addr) {
+            let next = addr + size;
+            let flow = flow(instruction, next);
+            block.end = next;
+            if let Some(target) = flow.call.filter(|target| instructions.contains_key(target)) {
+                block.calls.push(target);
+            }
+            if flow.ends_block || leaders.contains(&next) || !instructions.contains_key(&next) {
+                block.successors = flow.successors.into_iter().filter(|target| instructions.contains_key(target)).collect();
+                block.successors.dedup();
+                break;
+            }
+            addr = next;
+        }
+        blocks.insert(start, block);
+    }
+
+    let mut functions:BTreeMap<u16, Function> = BTreeMap::new();
+    for &entry in function_entries.iter().filter(|addr| blocks.contains_key(addr)) {
+        let mut reached:BTreeSet<u16> = BTreeSet::new();
+        let mut calls:BTreeSet<u16> = BTreeSet::new();
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            if !reached.insert(start) {
+                continue;
+            }
+            let block = &blocks[&start];
+            calls.extend(block.calls.iter().copied());
+            work.extend(block.successors.iter().copied());
+        }
+        functions.insert(entry, Function {
+            entry,
+            blocks: reached.into_iter().collect(),
+            calls: calls.into_iter().collect(),
+        });
+    }
+
+    Analysis { instructions, blocks, functions }
+}
+
+impl Analysis {
+    /**
+     * The instruction that starts at `addr`, if the analysis reached one there
+     */
+    pub fn instruction_at(&self, addr:u16) -> Option<Instruction> {
+        self.instructions.get(&addr).map(|(instruction, _)| *instruction)
+    }
+
+    /**
+     * Whether `addr` is part of a reached instruction (opcode or operand)
+     */
+    pub fn is_code(&self, addr:u16) -> bool {
+        match self.instructions.range(..=addr).next_back() {
+            Some((start, (_, size))) => (addr as usize) < *start as usize + *size as usize,
+            None => false,
+        }
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values()
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.functions.values()
+    }
+
+    pub fn block_containing(&self, addr:u16) -> Option<&BasicBlock> {
+        self.blocks.range(..=addr).next_back()
+            .map(|(_, block)| block)
+            .filter(|block| addr < block.end)
+    }
+
+    /**
+     * Every function whose body includes `addr`. Most addresses belong to one function,
+     * but code that several functions jump into is shared between them
+     */
+    pub fn functions_containing(&self, addr:u16) -> Vec<&Function> {
+        let block = match self.block_containing(addr) {
+            Some(block) => block.start,
+            None => return vec![],
+        };
+        self.functions.values()
+            .filter(|function| function.blocks.binary_search(&block).is_ok())
+            .collect()
+    }
+
+    /**
+     * Graphviz source for the control-flow graph. Each basic block is a node listing its
+     * instructions, solid edges are jumps and fall-through, dashed edges are calls
+     */
+    pub fn cfg_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if self.functions.contains_key(&block.start) {
+                write!(label, "sub_{:04X}:\\l", block.start).unwrap();
+            }
+            for (addr, (instruction, _)) in self.instructions.range(block.start..block.end) {
+                write!(label, "{:#06X}  {}\\l", addr, instruction.to_string().replace('\t', " ")).unwrap();
+            }
+            writeln!(dot, "    b_{:04X} [label=\"{}\"];", block.start, label).unwrap();
+        }
+        for block in self.blocks.values() {
+            for target in block.successors.iter() {
+                writeln!(dot, "    b_{:04X} -> b_{:04X};", block.start, target).unwrap();
+            }
+            for target in block.calls.iter() {
+                writeln!(dot, "    b_{:04X} -> b_{:04X} [style=dashed];", block.start, target).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /**
+     * Graphviz source for the call graph, one node per function
+     */
+    pub fn call_graph_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for function in self.functions.values() {
+            writeln!(dot, "    sub_{:04X};", function.entry).unwrap();
+        }
+        for function in self.functions.values() {
+            for target in function.calls.iter() {
+                writeln!(dot, "    sub_{:04X} -> sub_{:04X};", function.entry, target).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
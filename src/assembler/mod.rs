@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use crate::constants::{TOM, NUM_REG};
+use crate::errors::{AsmError, AsmErrorKind};
+use crate::instruction::{Instruction, Operand, OPCODES};
+use crate::utils::words_to_bytes;
+
+/**
+ * A piece of a source line
+ */
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(Vec<u8>),
+    Char(u8),
+}
+
+/**
+ * A token along with the (1-based) column it starts at
+ */
+struct Spanned {
+    token:Token,
+    column:usize,
+}
+
+/**
+ * One line of source after the label has been peeled off
+ */
+struct Statement {
+    line:usize,
+    column:usize,
+    name:String,        // mnemonic or directive
+    args:Vec<Spanned>,
+}
+
+/**
+ * Assembles `source` into a list of words, starting at address 0.
+ *
+ * The syntax follows the opcode listing in `arch-spec`:
+ *
+ * ```text
+ * ; comments run to the end of the line
+ * start:  set r0 'A'          ; registers are r0-r7, char literals are quoted
+ *         add r0, r0, 1       ; operands can be separated by commas
+ *         out r0
+ *         jmp start           ; labels can be used anywhere a number can
+ * .data 1, 0x20, 'c', start   ; raw words
+ * .string "hi\n"              ; one word per character
+ * .org 0x0100                 ; pad with zeros up to an address
+ * ```
+ */
+pub fn assemble(source:&str) -> Result<Vec<u16>, AsmError> {
+    let mut statements:Vec<Statement> = Vec::new();
+    let mut labels:HashMap<String, u16> = HashMap::new();
+    let mut addr:usize = 0;
+
+    // first pass: find out where every label lives
+    for (n, text) in source.lines().enumerate() {
+        let line = n + 1;
+        let mut tokens = tokenize(text, line)?.into_iter().peekable();
+
+        while let Some(Spanned { token: Token::Word(word), column }) = tokens.peek() {
+            if !word.ends_with(':') {
+                break;
+            }
+            let label = &word[..word.len() - 1];
+            if !is_identifier(label) {
+                return Err(AsmError::new(line, *column, AsmErrorKind::Syntax(format!("invalid label `{}`", label))));
+            }
+            if labels.insert(label.to_string(), addr as u16).is_some() {
+                return Err(AsmError::new(line, *column, AsmErrorKind::DuplicateLabel(label.to_string())));
+            }
+            tokens.next();
+        }
+
+        let (name, column) = match tokens.next() {
+            None => continue,
+            Some(Spanned { token: Token::Word(word), column }) => (word.to_lowercase(), column),
+            Some(Spanned { column, .. }) => {
+                return Err(AsmError::new(line, column, AsmErrorKind::Syntax("expected a mnemonic or directive".to_string())));
+            },
+        };
+        let statement = Statement { line, column, name, args: tokens.collect() };
+        if statement.name == ".org" {
+            addr = org_target(&statement, addr)?;
+        } else {
+            addr += statement_size(&statement)?;
+        }
+        if addr > TOM {
+            return Err(AsmError::new(line, column, AsmErrorKind::ImageTooLarge));
+        }
+        statements.push(statement);
+    }
+
+    // second pass: encode
+    let mut words:Vec<u16> = Vec::with_capacity(addr);
+    for statement in statements.iter() {
+        match statement.name.as_str() {
+            ".org" => {
+                let target = org_target(statement, words.len())?;
+                words.resize(target, 0);
+            },
+            ".data" => {
+                for arg in statement.args.iter() {
+                    words.push(data_word(arg, statement.line, &labels)?);
+                }
+            },
+            ".string" => {
+                for arg in statement.args.iter() {
+                    match &arg.token {
+                        Token::Str(bytes) => words.extend(bytes.iter().map(|b| *b as u16)),
+                        _ => return Err(AsmError::new(statement.line, arg.column, AsmErrorKind::Syntax("expected a string".to_string()))),
+                    }
+                }
+            },
+            _ => words.extend(encode_instruction(statement, &labels)?.encode()),
+        }
+    }
+
+    Ok(words)
+}
+
+/**
+ * Assembles `source` into the 16-bit little-endian image format of `challenge.bin`
+ */
+pub fn assemble_to_bytes(source:&str) -> Result<Vec<u8>, AsmError> {
+    Ok(words_to_bytes(&assemble(source)?))
+}
+
+/**
+ * Number of words a statement will occupy, checking mnemonics and operand counts on the way
+ */
+fn statement_size(statement:&Statement) -> Result<usize, AsmError> {
+    match statement.name.as_str() {
+        ".data" => Ok(statement.args.len()),
+        ".string" => Ok(statement.args.iter().map(|arg| match &arg.token {
+            Token::Str(bytes) => bytes.len(),
+            _ => 1,
+        }).sum()),
+        name => {
+            let opcode = opcode_for(name)
+                .ok_or_else(|| AsmError::new(statement.line, statement.column, AsmErrorKind::UnknownMnemonic(name.to_string())))?;
+            let expected = OPCODES[opcode as usize].1;
+            if statement.args.len() != expected {
+                return Err(AsmError::new(statement.line, statement.column, AsmErrorKind::OperandCount {
+                    mnemonic: name.to_string(),
+                    expected,
+                    found: statement.args.len(),
+                }));
+            }
+            Ok(1 + expected)
+        },
+    }
+}
+
+/**
+ * Address an `.org` directive moves to. It can only move forward from `addr`
+ */
+fn org_target(statement:&Statement, addr:usize) -> Result<usize, AsmError> {
+    let arg = match statement.args.as_slice() {
+        [arg] => arg,
+        _ => return Err(AsmError::new(statement.line, statement.column, AsmErrorKind::Syntax("`.org` takes one address".to_string()))),
+    };
+    let target = number(arg, statement.line, &HashMap::new())?;
+    if target < addr as i64 || target > TOM as i64 {
+        return Err(AsmError::new(statement.line, arg.column, AsmErrorKind::LiteralOutOfRange(target)));
+    }
+    Ok(target as usize)
+}
+
+fn opcode_for(mnemonic:&str) -> Option<u16> {
+    OPCODES.iter().position(|(name, _)| *name == mnemonic).map(|opcode| opcode as u16)
+}
+
+fn encode_instruction(statement:&Statement, labels:&HashMap<String, u16>) -> Result<Instruction, AsmError> {
+    // statement_size already checked the mnemonic and operand count
+    let opcode = opcode_for(&statement.name).unwrap_or(0);
+    let mut operands:Vec<Operand> = Vec::with_capacity(3);
+    for arg in statement.args.iter() {
+        operands.push(operand(arg, statement.line, labels)?);
+    }
+    Instruction::from_parts(opcode, &operands)
+        .ok_or_else(|| AsmError::new(statement.line, statement.column, AsmErrorKind::UnknownMnemonic(statement.name.clone())))
+}
+
+/**
+ * Parses an instruction operand: a register, a literal 0..32767, or a label
+ */
+fn operand(arg:&Spanned, line:usize, labels:&HashMap<String, u16>) -> Result<Operand, AsmError> {
+    if let Token::Word(word) = &arg.token {
+        if let Some(reg) = register(word) {
+            return Ok(Operand::Register(reg));
+        }
+    }
+
+    let value = number(arg, line, labels)?;
+    if value >= TOM as i64 || value < 0 {
+        return Err(AsmError::new(line, arg.column, AsmErrorKind::LiteralOutOfRange(value)));
+    }
+    Ok(Operand::Literal(value as u16))
+}
+
+/**
+ * Parses a `.data` word, which can be anything that fits in 16 bits, including registers
+ */
+fn data_word(arg:&Spanned, line:usize, labels:&HashMap<String, u16>) -> Result<u16, AsmError> {
+    if let Token::Word(word) = &arg.token {
+        if let Some(reg) = register(word) {
+            return Ok(Operand::Register(reg).to_word());
+        }
+    }
+
+    let value = number(arg, line, labels)?;
+    if !(0..=0xFFFF).contains(&value) {
+        return Err(AsmError::new(line, arg.column, AsmErrorKind::LiteralOutOfRange(value)));
+    }
+    Ok(value as u16)
+}
+
+fn register(word:&str) -> Option<u8> {
+    let lower = word.to_lowercase();
+    let digits = lower.strip_prefix('r')?;
+    match digits.parse::<u8>() {
+        Ok(reg) if (reg as usize) < NUM_REG && digits.len() == 1 => Some(reg),
+        _ => None,
+    }
+}
+
+fn number(arg:&Spanned, line:usize, labels:&HashMap<String, u16>) -> Result<i64, AsmError> {
+    let word = match &arg.token {
+        Token::Char(c) => return Ok(*c as i64),
+        Token::Str(_) => return Err(AsmError::new(line, arg.column, AsmErrorKind::Syntax("unexpected string".to_string()))),
+        Token::Word(word) => word,
+    };
+
+    let parsed = if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if word.starts_with(|c:char| c.is_ascii_digit() || c == '-') {
+        word.parse::<i64>().ok()
+    } else if is_identifier(word) {
+        return labels.get(word.as_str())
+            .map(|addr| *addr as i64)
+            .ok_or_else(|| AsmError::new(line, arg.column, AsmErrorKind::UnknownLabel(word.clone())));
+    } else {
+        None
+    };
+
+    match parsed {
+        Some(value) if (-0x10000..=0x10000).contains(&value) => Ok(value),
+        Some(value) => Err(AsmError::new(line, arg.column, AsmErrorKind::LiteralOutOfRange(value))),
+        None => Err(AsmError::new(line, arg.column, AsmErrorKind::Syntax(format!("invalid number `{}`", word)))),
+    }
+}
+
+fn is_identifier(word:&str) -> bool {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/**
+ * Splits a line into words, quoted strings and char literals, dropping commas and comments
+ */
+fn tokenize(text:&str, line:usize) -> Result<Vec<Spanned>, AsmError> {
+    let chars:Vec<char> = text.chars().collect();
+    let mut tokens:Vec<Spanned> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() || c == ',' {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut bytes:Vec<u8> = Vec::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(AsmError::new(line, column, AsmErrorKind::Syntax("unterminated literal".to_string()))),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        let (escaped, len) = match chars.get(i + 1) {
+                            Some('n') => (b'\n', 2),
+                            Some('t') => (b'\t', 2),
+                            Some('r') => (b'\r', 2),
+                            Some('0') => (0, 2),
+                            Some('\\') => (b'\\', 2),
+                            Some('\'') => (b'\'', 2),
+                            Some('"') => (b'"', 2),
+                            // \xNN, as the disassembler writes bytes that aren't printable
+                            Some('x') => match chars.get(i + 2..i + 4).filter(|hex| hex.iter().all(|c| c.is_ascii_hexdigit())) {
+                                Some(hex) => (u8::from_str_radix(&hex.iter().collect::<String>(), 16).unwrap(), 4),
+                                None => return Err(AsmError::new(line, i + 1, AsmErrorKind::Syntax("\\x needs two hex digits".to_string()))),
+                            },
+                            _ => return Err(AsmError::new(line, i + 1, AsmErrorKind::Syntax("invalid escape".to_string()))),
+                        };
+                        bytes.push(escaped);
+                        i += len;
+                    },
+                    Some(&other) if other.is_ascii() => {
+                        bytes.push(other as u8);
+                        i += 1;
+                    },
+                    Some(_) => return Err(AsmError::new(line, i + 1, AsmErrorKind::Syntax("only ASCII is supported".to_string()))),
+                }
+            }
+            i += 1;
+
+            if c == '"' {
+                tokens.push(Spanned { token: Token::Str(bytes), column });
+            } else if bytes.len() == 1 {
+                tokens.push(Spanned { token: Token::Char(bytes[0]), column });
+            } else {
+                return Err(AsmError::new(line, column, AsmErrorKind::Syntax("a char literal holds exactly one character".to_string())));
+            }
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() && chars[i] != ',' && chars[i] != ';' {
+                i += 1;
+            }
+            tokens.push(Spanned { token: Token::Word(chars[start..i].iter().collect()), column });
+        }
+    }
+
+    Ok(tokens)
+}
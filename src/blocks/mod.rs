@@ -0,0 +1,148 @@
+use std::rc::Rc;
+use crate::constants::{NUM_REG, TOM};
+use crate::errors::Error;
+use crate::instruction::OPCODES;
+
+const MAX_BLOCK_OPS:usize = 64;
+const MAX_BLOCK_WORDS:usize = MAX_BLOCK_OPS * 4;
+
+/**
+ * An instruction decoded ahead of time, with its operands checked, for `Machine::run_fast`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Op {
+    pub pc:u16,
+    pub opcode:u16,
+    pub args:[u16; 3],  // as encoded, so registers are TOM..TOM+7
+    pub next:u16,       // the address after the instruction
+}
+
+impl Op {
+    /**
+     * Decodes the instruction at `pc`, with the same errors as `decode_with`
+     */
+    pub fn decode(mem:&[u16], pc:u16) -> Result<Op, Error> {
+        let opcode = *mem.get(pc as usize).ok_or(Error::MemoryInvalid { pc, opcode: 0, operand: pc })?;
+        let count = OPCODES.get(opcode as usize).ok_or(Error::UnknownOpcode { pc, opcode })?.1;
+        let mut args = [0u16; 3];
+        for (n, arg) in args.iter_mut().enumerate().take(count) {
+            let addr = pc + 1 + n as u16;
+            let word = *mem.get(addr as usize).ok_or(Error::MemoryInvalid { pc, opcode, operand: addr })?;
+            if word as usize >= TOM + NUM_REG {
+                return Err(Error::MemoryInvalid { pc, opcode, operand: word });
+            }
+            *arg = word;
+        }
+        Ok(Op { pc, opcode, args, next: pc + 1 + count as u16 })
+    }
+
+    /**
+     * `out` and `in`, which have I/O to do and go through `fetch_and_execute`
+     */
+    pub fn is_io(&self) -> bool {
+        self.opcode == 19 || self.opcode == 20
+    }
+
+    /**
+     * Halt, jumps, call and ret: whatever runs next isn't necessarily at `next`
+     */
+    fn ends_block(&self) -> bool {
+        matches!(self.opcode, 0 | 6 | 7 | 8 | 17 | 18)
+    }
+}
+
+/**
+ * A straight run of instructions, ending at the first jump, call, ret or halt
+ */
+#[derive(Debug)]
+pub(crate) struct Block {
+    pub start:u16,
+    pub end:u16,    // exclusive
+    pub ops:Vec<Op>,
+}
+
+/**
+ * Blocks decoded so far, by start address. Writing to memory a block was decoded from drops
+ * the block, and bumps `generation` so a block that's running can tell it changed under it
+ */
+#[derive(Debug, Default)]
+pub(crate) struct BlockCache {
+    blocks:Vec<Option<Rc<Block>>>,  // TOM of them once anything is cached
+    covered:Vec<u16>,               // how many blocks each address is part of
+    generation:u64,
+}
+
+impl BlockCache {
+    /**
+     * The block starting at `pc`, decoding it if it isn't cached. `None` if the instruction
+     * at `pc` does I/O or doesn't decode, so it has to be run the slow way
+     */
+    pub fn get(&mut self, mem:&[u16], pc:u16) -> Option<Rc<Block>> {
+        if let Some(Some(block)) = self.blocks.get(pc as usize) {
+            return Some(Rc::clone(block));
+        }
+
+        let mut ops:Vec<Op> = Vec::new();
+        let mut addr = pc;
+        while ops.len() < MAX_BLOCK_OPS {
+            let op = match Op::decode(mem, addr) {
+                Ok(op) if !op.is_io() => op,
+                _ => break,
+            };
+            ops.push(op);
+            addr = op.next;
+            if op.ends_block() {
+                break;
+            }
+        }
+        if ops.is_empty() {
+            return None;
+        }
+
+        if self.blocks.is_empty() {
+            self.blocks = vec![None; TOM];
+            self.covered = vec![0; TOM];
+        }
+        for count in self.covered[pc as usize..addr as usize].iter_mut() {
+            *count += 1;
+        }
+        let block = Rc::new(Block { start: pc, end: addr, ops });
+        self.blocks[pc as usize] = Some(Rc::clone(&block));
+        Some(block)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /**
+     * Drops every block that `addr` is part of, after a write to it
+     */
+    #[inline]
+    pub fn invalidate(&mut self, addr:u16) {
+        if self.covered.get(addr as usize).is_none_or(|count| *count == 0) {
+            return;
+        }
+        let first = (addr as usize).saturating_sub(MAX_BLOCK_WORDS - 1);
+        for start in first..=addr as usize {
+            let hit = matches!(&self.blocks[start], Some(block) if addr < block.end);
+            if hit {
+                if let Some(block) = self.blocks[start].take() {
+                    for count in self.covered[block.start as usize..block.end as usize].iter_mut() {
+                        *count -= 1;
+                    }
+                }
+            }
+        }
+        self.generation += 1;
+    }
+
+    /**
+     * Drops everything, after memory was replaced wholesale
+     */
+    pub fn clear(&mut self) {
+        self.blocks = Vec::new();
+        self.covered = Vec::new();
+        self.generation += 1;
+    }
+}
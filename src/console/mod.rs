@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/**
+ * Where the guest's `in` instruction gets its bytes from
+ */
+pub trait InputSource {
+    /**
+     * Returns the next byte of guest input, or `None` if there's no more input available
+     */
+    fn read_byte(&mut self) -> Option<u8>;
+
+    /**
+     * Returns `true` if a person is typing the input. The hypervisor escape (`.`) is only
+     * honored on interactive sources, so scripted input can contain any character
+     */
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+/**
+ * Where the guest's `out` instruction sends its bytes to
+ */
+pub trait OutputSink {
+    fn write_byte(&mut self, byte:u8);
+
+    fn flush(&mut self) {}
+}
+
+/**
+ * Reads guest input from STDIN
+ */
+#[derive(Default)]
+pub struct StdinInput;
+
+impl InputSource for StdinInput {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8; 1];
+        match io::stdin().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+/**
+ * Writes guest output to STDOUT
+ */
+#[derive(Default)]
+pub struct StdoutOutput;
+
+impl OutputSink for StdoutOutput {
+    fn write_byte(&mut self, byte:u8) {
+        print!("{}", byte as char);
+    }
+
+    fn flush(&mut self) {
+        io::stdout().flush().unwrap();
+    }
+}
+
+/**
+ * Feeds a fixed buffer of bytes to the guest, then reports end of input
+ */
+pub struct MemoryInput {
+    bytes:Vec<u8>,
+    position:usize,
+}
+
+impl MemoryInput {
+    pub fn new(bytes:&[u8]) -> Self {
+        MemoryInput { bytes: bytes.to_vec(), position: 0 }
+    }
+}
+
+impl InputSource for MemoryInput {
+    fn read_byte(&mut self) -> Option<u8> {
+        let byte = self.bytes.get(self.position).copied();
+        if byte.is_some() {
+            self.position += 1;
+        }
+        byte
+    }
+}
+
+/**
+ * Collects guest output in memory. Clones share the same buffer, so keep one
+ * around to inspect what the guest printed after handing the other to the `Machine`
+ */
+#[derive(Clone, Default)]
+pub struct MemoryOutput {
+    buffer:Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryOutput {
+    pub fn new() -> Self {
+        MemoryOutput::default()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    /**
+     * Returns the output so far, with any bytes that aren't valid UTF-8 replaced
+     */
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.lock().unwrap()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.buffer.lock().unwrap().clear();
+    }
+}
+
+impl OutputSink for MemoryOutput {
+    fn write_byte(&mut self, byte:u8) {
+        self.buffer.lock().unwrap().push(byte);
+    }
+}
+
+/**
+ * A queue of input lines that can be topped up while the guest is running.
+ * Clones share the same queue
+ */
+#[derive(Clone, Default)]
+pub struct ScriptedInput {
+    queue:Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl ScriptedInput {
+    pub fn new() -> Self {
+        ScriptedInput::default()
+    }
+
+    /**
+     * Queues `line` followed by a newline, which is what the guest expects at the end
+     * of every command
+     */
+    pub fn push_line(&self, line:&str) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(line.bytes());
+        queue.push_back(b'\n');
+    }
+
+    pub fn push_bytes(&self, bytes:&[u8]) {
+        self.queue.lock().unwrap().extend(bytes.iter().copied());
+    }
+
+    pub fn pending(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
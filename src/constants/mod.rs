@@ -14,3 +14,10 @@ pub const HALT_BIT:u16 = 0;
 pub const MAX_RECENTMEMACCESS_SIZE:u8 = 255; // prevents recentMemAccess from growing past this size
 pub const RECENTMEMACCESS_READ_BIT:u8 = 1;
 pub const RECENTMEMACCESS_WRITE_BIT:u8 = 2;
+
+pub const RECENT_OUTPUT_SIZE:usize = 1024;  // bytes of guest output kept around for snapshots
+pub const SNAPSHOT_OUTPUT_LINES:usize = 5;  // lines of guest output saved with a snapshot
+pub const DEFAULT_SNAPSHOT_DIR:&str = "snapshots";
+pub const DEFAULT_TRACE_SIZE:usize = 10000; // instructions kept by the hypervisor's `trace on`
+pub const DEFAULT_TIMELINE_SIZE:usize = 1_000_000;  // instructions that can be undone after `record on`
+pub const CHECKPOINT_INTERVAL:u64 = 100_000;    // instructions between full snapshots in the undo log
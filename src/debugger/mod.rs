@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use crate::constants::{TOM, NUM_REG};
+use crate::machine::Machine;
+use crate::replay::Divergence;
+
+/**
+ * Something a condition can look at: a register, `pc`, a memory cell or a number
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Register(u8),
+    Pc,
+    Memory(u16),
+    Literal(u16),
+}
+
+impl Value {
+    fn get(&self, m0:&Machine) -> u16 {
+        match *self {
+            Value::Register(reg) => m0.register(reg as usize).unwrap_or(0),
+            Value::Pc => m0.pc(),
+            Value::Memory(addr) => m0.read_word(addr).unwrap_or(0),
+            Value::Literal(value) => value,
+        }
+    }
+}
+
+impl FromStr for Value {
+    type Err = String;
+
+    /**
+     * `r0`..`r7`, `pc`, `[NNNN]` for the memory cell at NNNN (hex), or a number in decimal
+     * or `0x` hex
+     */
+    fn from_str(token:&str) -> Result<Self, Self::Err> {
+        if token == "pc" {
+            return Ok(Value::Pc);
+        }
+        if let Some(reg) = parse_register(token) {
+            return Ok(Value::Register(reg));
+        }
+        if let Some(addr) = token.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            return match u16::from_str_radix(addr, 16) {
+                Ok(addr) if (addr as usize) < TOM => Ok(Value::Memory(addr)),
+                _ => Err(format!("`{}` isn't a memory address in hex", addr)),
+            };
+        }
+        let number = match token.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => token.parse::<u16>(),
+        };
+        number.map(Value::Literal).map_err(|_| format!("`{}` isn't a register, [address] or number", token))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Register(reg) => write!(f, "r{}", reg),
+            Value::Pc => write!(f, "pc"),
+            Value::Memory(addr) => write!(f, "[{:04X}]", addr),
+            Value::Literal(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    const ALL:[(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    fn symbol(&self) -> &'static str {
+        Comparison::ALL.iter().find(|(_, op)| op == self).map_or("?", |(symbol, _)| symbol)
+    }
+}
+
+/**
+ * A comparison between two values, e.g. `r7 != 0` or `[0AAC] == 0x1234`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub lhs:Value,
+    pub op:Comparison,
+    pub rhs:Value,
+}
+
+impl Condition {
+    pub fn evaluate(&self, m0:&Machine) -> bool {
+        let (lhs, rhs) = (self.lhs.get(m0), self.rhs.get(m0));
+        match self.op {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    /**
+     * Parses `VALUE OP VALUE`. Spaces around the operator are optional
+     */
+    fn from_str(text:&str) -> Result<Self, Self::Err> {
+        for (symbol, op) in Comparison::ALL.iter() {
+            if let Some((lhs, rhs)) = text.split_once(symbol) {
+                return Ok(Condition { lhs: lhs.trim().parse()?, op: *op, rhs: rhs.trim().parse()? });
+            }
+        }
+        Err(format!("`{}` isn't a condition like `r7 != 0`", text))
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.lhs, self.op.symbol(), self.rhs)
+    }
+}
+
+/**
+ * Stops execution before the instruction at `addr` runs, if `condition` holds (or there isn't one)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr:u16,
+    pub condition:Option<Condition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    Memory(u16),
+    Register(u8),
+}
+
+impl WatchTarget {
+    /**
+     * The address the machine uses for the target, registers being TOM..TOM+7
+     */
+    fn addr(&self) -> u16 {
+        match *self {
+            WatchTarget::Memory(addr) => addr,
+            WatchTarget::Register(reg) => TOM as u16 + reg as u16,
+        }
+    }
+}
+
+impl FromStr for WatchTarget {
+    type Err = String;
+
+    /**
+     * `r0`..`r7` or a memory address in hex
+     */
+    fn from_str(token:&str) -> Result<Self, Self::Err> {
+        if let Some(reg) = parse_register(token) {
+            return Ok(WatchTarget::Register(reg));
+        }
+        match u16::from_str_radix(token, 16) {
+            Ok(addr) if (addr as usize) < TOM => Ok(WatchTarget::Memory(addr)),
+            _ => Err(format!("`{}` isn't a register or a memory address in hex", token)),
+        }
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchTarget::Memory(addr) => write!(f, "{:#06X}", addr),
+            WatchTarget::Register(reg) => write!(f, "r{}", reg),
+        }
+    }
+}
+
+/**
+ * The kind of access a watchpoint fires on
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn includes(&self, access:Access) -> bool {
+        *self == Access::ReadWrite || *self == access
+    }
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(token:&str) -> Result<Self, Self::Err> {
+        match token {
+            "r" | "read" => Ok(Access::Read),
+            "w" | "write" => Ok(Access::Write),
+            "rw" => Ok(Access::ReadWrite),
+            _ => Err(format!("`{}` isn't r, w or rw", token)),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::ReadWrite => write!(f, "read/write"),
+        }
+    }
+}
+
+/**
+ * Stops execution right after an instruction reads or writes `target`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub target:WatchTarget,
+    pub access:Access,
+}
+
+/**
+ * Why the machine handed control back to the debugger
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// About to execute the instruction at `addr`
+    Breakpoint { id:u32, addr:u16 },
+    /// The instruction at `pc` read or wrote the watched target; `value` is what was read or written
+    Watchpoint { id:u32, target:WatchTarget, access:Access, value:u16, pc:u16 },
+    /// A `step` or `next` is done
+    Stepped,
+    /// A `finish` is done, the current function returned to `addr`
+    Finished { addr:u16 },
+    /// Someone asked for the hypervisor. `pc` is on an instruction that hasn't run yet
+    Interrupted,
+    /// A replay read or printed something different from the recording
+    Diverged(Divergence),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint { id, addr } => write!(f, "breakpoint {} at {:#06X}", id, addr),
+            StopReason::Watchpoint { id, target, access, value, pc } => {
+                let verb = if *access == Access::Read { "read" } else { "written" };
+                write!(f, "watchpoint {}: {} {} ({:#06X}) by the instruction at {:#06X}", id, target, verb, value, pc)
+            },
+            StopReason::Stepped => write!(f, "stepped"),
+            StopReason::Finished { addr } => write!(f, "returned to {:#06X}", addr),
+            StopReason::Interrupted => write!(f, "interrupted"),
+            StopReason::Diverged(divergence) => write!(f, "replay diverged: {}", divergence),
+        }
+    }
+}
+
+/**
+ * How far to let the guest run, see `Machine::set_run_mode`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    Continue,
+    Step(u64),  // this many instructions
+    Next,       // one instruction, treating a `call` and everything it does as one
+    Finish,     // until the current function returns
+}
+
+/**
+ * When to stop next, apart from breakpoints and watchpoints
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StopWhen {
+    Never,
+    Steps(u64),                         // after this many more instructions
+    ReachedWith { addr:u16, depth:usize },   // `pc` is `addr` and the stack has `depth` entries, i.e. a `call` returned
+    Returned { depth:usize },           // a `ret` left fewer than `depth` entries on the stack
+}
+
+/**
+ * Breakpoints, watchpoints and the stepping state of a machine. The checks happen in
+ * `Machine::fetch_and_execute`, which returns `StepOutcome::Stopped` when one fires
+ */
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    breakpoints:BTreeMap<u32, Breakpoint>,
+    watchpoints:BTreeMap<u32, Watchpoint>,
+    next_id:u32,
+    pub(crate) stop_when:StopWhen,
+    pub(crate) resume_from:Option<u16>,    // where execution last stopped, so resuming doesn't stop on the same breakpoint again
+    hit:Option<StopReason>,             // the first watchpoint hit by the instruction being executed
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            next_id: 1,
+            stop_when: StopWhen::Never,
+            resume_from: None,
+            hit: None,
+        }
+    }
+}
+
+impl Debugger {
+    /**
+     * Adds a breakpoint and returns its id
+     */
+    pub fn add_breakpoint(&mut self, addr:u16, condition:Option<Condition>) -> u32 {
+        let id = self.take_id();
+        self.breakpoints.insert(id, Breakpoint { addr, condition });
+        id
+    }
+
+    /**
+     * Adds a watchpoint and returns its id
+     */
+    pub fn add_watchpoint(&mut self, target:WatchTarget, access:Access) -> u32 {
+        let id = self.take_id();
+        self.watchpoints.insert(id, Watchpoint { target, access });
+        id
+    }
+
+    /**
+     * Removes the breakpoint or watchpoint `id`. Returns `false` if there isn't one
+     */
+    pub fn remove(&mut self, id:u32) -> bool {
+        self.breakpoints.remove(&id).is_some() || self.watchpoints.remove(&id).is_some()
+    }
+
+    /**
+     * Removes every breakpoint and watchpoint
+     */
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u32, &Breakpoint)> {
+        self.breakpoints.iter().map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (u32, &Watchpoint)> {
+        self.watchpoints.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    /**
+     * Nothing to check on any instruction: no breakpoints, watchpoints or stepping
+     */
+    pub(crate) fn is_idle(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchpoints.is_empty() && matches!(self.stop_when, StopWhen::Never) && self.hit.is_none()
+    }
+
+    fn take_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /**
+     * The breakpoints at `addr`, lowest id first
+     */
+    pub(crate) fn breakpoints_at(&self, addr:u16) -> Vec<(u32, Breakpoint)> {
+        if self.breakpoints.is_empty() {
+            return vec![];
+        }
+        self.breakpoints.iter()
+            .filter(|(_, breakpoint)| breakpoint.addr == addr)
+            .map(|(id, breakpoint)| (*id, *breakpoint))
+            .collect()
+    }
+
+    /**
+     * Called for every read and write of memory or a register (`addr` TOM..TOM+7) made by
+     * the instruction at `pc`. Remembers the first watchpoint that matches
+     */
+    pub(crate) fn access(&mut self, addr:u16, access:Access, value:u16, pc:u16) {
+        if self.watchpoints.is_empty() || self.hit.is_some() || addr as usize >= TOM + NUM_REG {
+            return;
+        }
+        if let Some((id, watchpoint)) = self.watchpoints.iter().find(|(_, w)| w.target.addr() == addr && w.access.includes(access)) {
+            self.hit = Some(StopReason::Watchpoint { id: *id, target: watchpoint.target, access, value, pc });
+        }
+    }
+
+    pub(crate) fn take_hit(&mut self) -> Option<StopReason> {
+        self.hit.take()
+    }
+}
+
+/**
+ * `r0`..`r7`
+ */
+fn parse_register(token:&str) -> Option<u8> {
+    match token.strip_prefix('r').map(|reg| reg.parse::<u8>()) {
+        Some(Ok(reg)) if (reg as usize) < NUM_REG => Some(reg),
+        _ => None,
+    }
+}
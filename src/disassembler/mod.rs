@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::analysis::Analysis;
+use crate::constants::TOM;
+use crate::instruction::{decode, Instruction, Operand};
+use crate::machine::Machine;
+
+const MIN_STRING_LENGTH:usize = 4;  // shorter runs of printable data words are left as `.data`
+const DATA_WORDS_PER_LINE:usize = 8;
+const COMMENT_COLUMN:usize = 32;
+
+/**
+ * One decoded item of the image: either an instruction or a word that doesn't decode
+ */
+enum Item {
+    Code(Instruction),
+    Data(u16),
+}
+
+/**
+ * Disassembles `words[start..=end]` into text that the assembler turns back into the
+ * same words. Registers are shown as `r0`-`r7`, `out` literals as quoted characters,
+ * runs of `out` get the string they print as a comment, and targets of `jmp`, `jt`, `jf`
+ * and `call` inside the range get labels. Words that don't decode become `.data`
+ * (or `.string` for runs of printable characters)
+ */
+pub fn disassemble(words:&[u16], start:u16, end:u16) -> String {
+    let mut items:Vec<(u16, Item)> = Vec::new();
+    let mut addr = start as usize;
+    while addr <= end as usize && addr < words.len().min(TOM) {
+        match decode(words, addr as u16) {
+            Ok((instruction, size)) => {
+                items.push((addr as u16, Item::Code(instruction)));
+                addr += size as usize;
+            },
+            Err(_) => {
+                items.push((addr as u16, Item::Data(words[addr])));
+                addr += 1;
+            },
+        }
+    }
+
+    render_items(&items, start)
+}
+
+/**
+ * Like `disassemble`, but only words that `analysis` reached as instructions are shown as
+ * code, so data tables in between don't throw the listing out of step
+ */
+pub fn disassemble_with(words:&[u16], start:u16, end:u16, analysis:&Analysis) -> String {
+    let mut items:Vec<(u16, Item)> = Vec::new();
+    let mut addr = start as usize;
+    while addr <= end as usize && addr < words.len().min(TOM) {
+        match analysis.instruction_at(addr as u16) {
+            Some(instruction) => {
+                items.push((addr as u16, Item::Code(instruction)));
+                addr += instruction.size() as usize;
+            },
+            None => {
+                items.push((addr as u16, Item::Data(words[addr])));
+                addr += 1;
+            },
+        }
+    }
+
+    render_items(&items, start)
+}
+
+fn render_items(items:&[(u16, Item)], start:u16) -> String {
+    let labels = find_labels(items);
+    let mut text = String::new();
+    if start != 0 {
+        writeln!(text, ".org {:#06X}", start).unwrap();
+    }
+
+    let mut n = 0;
+    while n < items.len() {
+        let (addr, item) = &items[n];
+        if let Some(label) = labels.get(addr) {
+            writeln!(text, "{}:", label).unwrap();
+        }
+
+        match item {
+            Item::Code(instruction) => {
+                let continues_run = n > 0 && !labels.contains_key(addr) && is_literal_out(&items[n - 1].1);
+                if !continues_run {
+                    if let Some(string) = out_run(&items[n..], &labels) {
+                        for piece in string.split_inclusive('\n') {
+                            writeln!(text, "    ; \"{}\"", escape(piece, '"')).unwrap();
+                        }
+                    }
+                }
+                line(&mut text, &render(instruction, &labels), *addr);
+                n += 1;
+            },
+            Item::Data(_) => {
+                // gather up the data words that follow, stopping at code or a label
+                let mut run:Vec<u16> = Vec::new();
+                while let Some((run_addr, Item::Data(word))) = items.get(n) {
+                    if !run.is_empty() && labels.contains_key(run_addr) {
+                        break;
+                    }
+                    run.push(*word);
+                    n += 1;
+                }
+                data(&mut text, &run, *addr);
+            },
+        }
+    }
+
+    text
+}
+
+/**
+ * Disassembles `start..=end` of the machine's memory
+ */
+pub fn disassemble_machine(m0:&Machine, start:u16, end:u16) -> String {
+    disassemble(&machine_words(m0), start, end)
+}
+
+/**
+ * Copies the machine's memory out
+ */
+pub fn machine_words(m0:&Machine) -> Vec<u16> {
+    (0..TOM as u16).map(|addr| m0.read_word(addr).unwrap_or(0)).collect()
+}
+
+/**
+ * Names every literal jump or call target that lands on the start of an item
+ */
+fn find_labels(items:&[(u16, Item)]) -> BTreeMap<u16, String> {
+    let starts:Vec<u16> = items.iter().map(|(addr, _)| *addr).collect();
+    let mut labels:BTreeMap<u16, String> = BTreeMap::new();
+
+    for (_, item) in items.iter() {
+        let (target, is_call) = match item {
+            Item::Code(Instruction::Call(Operand::Literal(target))) => (*target, true),
+            Item::Code(Instruction::Jmp(Operand::Literal(target)))
+                | Item::Code(Instruction::Jt(_, Operand::Literal(target)))
+                | Item::Code(Instruction::Jf(_, Operand::Literal(target))) => (*target, false),
+            _ => continue,
+        };
+        if starts.binary_search(&target).is_err() {
+            continue;
+        }
+        if is_call {
+            labels.insert(target, format!("sub_{:04X}", target));
+        } else {
+            labels.entry(target).or_insert_with(|| format!("loc_{:04X}", target));
+        }
+    }
+
+    labels
+}
+
+/**
+ * If `items` starts a run of at least two `out` instructions with literal operands,
+ * returns the string they print. A label ends the run
+ */
+fn out_run(items:&[(u16, Item)], labels:&BTreeMap<u16, String>) -> Option<String> {
+    let mut string = String::new();
+    for (n, (addr, item)) in items.iter().enumerate() {
+        if n > 0 && labels.contains_key(addr) {
+            break;
+        }
+        match item {
+            Item::Code(Instruction::Out(Operand::Literal(c))) => string.push(*c as u8 as char),
+            _ => break,
+        }
+    }
+
+    if string.chars().count() >= 2 {
+        Some(string)
+    } else {
+        None
+    }
+}
+
+fn is_literal_out(item:&Item) -> bool {
+    matches!(item, Item::Code(Instruction::Out(Operand::Literal(_))))
+}
+
+fn render(instruction:&Instruction, labels:&BTreeMap<u16, String>) -> String {
+    let mut text = format!("{:<6}", instruction.mnemonic());
+    let operands = instruction.operands();
+    let target = match instruction {
+        Instruction::Jmp(_) | Instruction::Call(_) => Some(0),
+        Instruction::Jt(..) | Instruction::Jf(..) => Some(1),
+        _ => None,
+    };
+
+    for (n, operand) in operands.iter().enumerate() {
+        text.push(' ');
+        match operand {
+            Operand::Literal(val) if Some(n) == target && labels.contains_key(val) => text.push_str(&labels[val]),
+            Operand::Literal(val) if matches!(instruction, Instruction::Out(_)) && is_printable(*val) => {
+                text.push_str(&format!("'{}'", escape(&(*val as u8 as char).to_string(), '\'')));
+            },
+            _ => text.push_str(&operand.to_string()),
+        }
+    }
+
+    text.trim_end().to_string()
+}
+
+/**
+ * Writes `.data` and `.string` lines for a run of words that didn't decode
+ */
+fn data(text:&mut String, run:&[u16], addr:u16) {
+    let mut n = 0;
+    while n < run.len() {
+        let printable = run[n..].iter().take_while(|word| is_printable(**word)).count();
+        if printable >= MIN_STRING_LENGTH {
+            let string:String = run[n..n + printable].iter().map(|word| *word as u8 as char).collect();
+            line(text, &format!(".string \"{}\"", escape(&string, '"')), addr + n as u16);
+            n += printable;
+        } else {
+            // stop a `.data` line early if a string starts partway through it
+            let mut count = 0;
+            while n + count < run.len() && count < DATA_WORDS_PER_LINE {
+                let ahead = run[n + count..].iter().take_while(|word| is_printable(**word)).count();
+                if count > 0 && ahead >= MIN_STRING_LENGTH {
+                    break;
+                }
+                count += 1;
+            }
+            let words:Vec<String> = run[n..n + count].iter().map(|word| format!("{:#06X}", word)).collect();
+            line(text, &format!(".data {}", words.join(", ")), addr + n as u16);
+            n += count;
+        }
+    }
+}
+
+/**
+ * Writes one indented line with the address it was found at as a comment
+ */
+fn line(text:&mut String, body:&str, addr:u16) {
+    writeln!(text, "    {:<width$} ; {:#06X}", body, addr, width = COMMENT_COLUMN).unwrap();
+}
+
+fn is_printable(word:u16) -> bool {
+    (0x20..=0x7E).contains(&word) || word == '\n' as u16
+}
+
+/**
+ * Escapes `string` so it can be put between `quote`s in assembler source
+ */
+fn escape(string:&str, quote:char) -> String {
+    let mut escaped = String::new();
+    for c in string.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\\' => escaped.push_str("\\\\"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            c if (' '..='~').contains(&c) => escaped.push(c),
+            c => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+        }
+    }
+    escaped
+}
@@ -1,15 +1,14 @@
 extern crate sdl2;
 
 use self::sdl2::pixels::Color;
-use std::time::Duration;
 use self::sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use self::sdl2::rect::{Point, Rect};
-use self::sdl2::render::{TextureCreator, Canvas, Texture, CanvasBuilder};
-use self::sdl2::video::{WindowContext, Window};
-use synacor_cpu::{utils, constants::*, Machine};
+use self::sdl2::render::{TextureCreator, Canvas};
+use self::sdl2::video::Window;
+use synacor_cpu::{utils, constants::*, Machine, StepOutcome};
 
-pub fn frontpanelRun(m0:&mut Machine) -> Result<(), String> {
+pub fn frontpanel_run(m0:&mut Machine) -> Result<(), String> {
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
 
@@ -37,7 +36,7 @@ pub fn frontpanelRun(m0:&mut Machine) -> Result<(), String> {
         texture_canvas.clear();
         texture_canvas.set_draw_color(Color::RGB(255,0,0));
         texture_canvas.fill_rect(Rect::new(1, 1, 100, 100)).unwrap();
-        texture_canvas.draw_point(Point::new(0,0));
+        texture_canvas.draw_point(Point::new(0,0)).unwrap();
         }
     ).map_err(|e| e.to_string())?;
 
@@ -59,12 +58,15 @@ pub fn frontpanelRun(m0:&mut Machine) -> Result<(), String> {
                     }
                 },
                 Event::KeyDown { keycode: Some(Keycode::Equals), .. } => {
-                    if speed >= 0xFFFF {
+                    if speed == 0xFFFF {
                         speed = 0xFFFF;
                     } else {
                         speed += 127;
                     }
                 },
+                Event::KeyDown { keycode: Some(Keycode::H), .. } => {
+                    m0.interrupt();     // the hypervisor opens before the next instruction
+                },
                 Event::KeyDown { keycode: Some(Keycode::Space), .. } => {
                     if m0.is_halted() {
                         utils::clear_bit(&mut m0.status, HALT_BIT);
@@ -84,24 +86,31 @@ pub fn frontpanelRun(m0:&mut Machine) -> Result<(), String> {
             } else {
                 canvas.set_draw_color(Color::RGB(0, 0, 255));
             }
-            canvas.fill_rect(Rect::new(10*i as i32, 0, 10, 20));
+            canvas.fill_rect(Rect::new(10*i as i32, 0, 10, 20))?;
         }
 
-        if x % speed == 0 {
+        if x.is_multiple_of(speed) {
             canvas.present();
-            draw_empty_cells(TOM as u16, &mut canvas, &m0);
+            draw_empty_cells(TOM as u16, &mut canvas, m0);
         }
-        for access_location in m0.recentMemAccess.iter() {
+        for access_location in m0.recent_mem_access.iter() {
             if access_location.1 == RECENTMEMACCESS_READ_BIT {
                 draw_filled_cell(access_location.0, Color::RGB(0, 255, 0), &mut canvas);
             } else if access_location.1 == RECENTMEMACCESS_WRITE_BIT {
                 draw_filled_cell(access_location.0, Color::RGB(255, 0, 0), &mut canvas);
             }
         }
-        m0.recentMemAccess.clear();
+        m0.recent_mem_access.clear();
 
         if !m0.is_halted() {
-            m0.fetch_and_execute();
+            match m0.fetch_and_execute() {
+                Ok(StepOutcome::Stopped(reason)) => m0.debug_break(reason),
+                Ok(_) => {},
+                Err(e) => {
+                    println!("\n**** {} ****", e);
+                    m0.halt();
+                },
+            }
         }
         x = x.wrapping_add(1);
     }
@@ -109,15 +118,14 @@ pub fn frontpanelRun(m0:&mut Machine) -> Result<(), String> {
     Ok(())
 }
 
-fn draw_empty_cells(up_to:u16, canvas:&mut Canvas<Window>, machine:&Machine) {
-    const y_offset:i32 = 20;
-    const rect_width:u32 = 40;
-    const rect_height:u32 = 3;
+fn draw_empty_cells(_up_to:u16, canvas:&mut Canvas<Window>, machine:&Machine) {
+    const Y_OFFSET:i32 = 20;
+    const RECT_WIDTH:u32 = 40;
+    const RECT_HEIGHT:u32 = 3;
     canvas.set_draw_color(Color::RGB(128, 128, 128));
     for y in 0..(TOM as i32) / 64 {
         for x in 0..64 {
-            let val:u16 = utils::swap_endian(machine.mem[((y*64)+x) as usize]);
-            // let val:u16 = machine.mem[((y*64)+x) as usize];
+            let val:u16 = machine.read_word(((y*64)+x) as u16).unwrap_or(0);
 
             if val > 0x001F && val < 0x007F {
                 canvas.set_draw_color(Color::RGB(0, 0, (val % 255) as u8));
@@ -125,26 +133,26 @@ fn draw_empty_cells(up_to:u16, canvas:&mut Canvas<Window>, machine:&Machine) {
                 canvas.set_draw_color(Color::RGB((val / 255) as u8, (val / 255) as u8,(val / 255) as u8));
             }
             // canvas.set_draw_color(Color::RGB((machine.mem[(y*x) as usize] >> 8) as u8, 0, 0));
-            canvas.fill_rect(Rect::new(x * rect_width as i32, (y * rect_height as i32) + y_offset, rect_width, rect_height));
+            let _ = canvas.fill_rect(Rect::new(x * RECT_WIDTH as i32, (y * RECT_HEIGHT as i32) + Y_OFFSET, RECT_WIDTH, RECT_HEIGHT));
         }
     }
 }
 
 fn draw_filled_cell(mem_location:u16, color:Color, canvas:&mut Canvas<Window>) {
-    const y_offset:i32 = 20;
-    const rect_width:i32 = 40;
-    const rect_height:i32 = 3;
+    const Y_OFFSET:i32 = 20;
+    const RECT_WIDTH:i32 = 40;
+    const RECT_HEIGHT:i32 = 3;
     canvas.set_draw_color(color);
 
     let mut x:i32 = (mem_location % 64) as i32;
-    x *= rect_width;
+    x *= RECT_WIDTH;
 
     let mut y:i32 = (mem_location / 64) as i32;
-    y *= rect_height;
-    y += y_offset;
+    y *= RECT_HEIGHT;
+    y += Y_OFFSET;
 
     //canvas.fill_rect(Rect::new(x * rect_width as i32, (y * rect_height as i32) + y_offset, rect_width, rect_height));
-    canvas.fill_rect(Rect::new(x, y, rect_width as u32, rect_height as u32));
+    let _ = canvas.fill_rect(Rect::new(x, y, RECT_WIDTH as u32, RECT_HEIGHT as u32));
 }
 
 /*fn dummy_texture<'a>(canvas: &mut Canvas<Window>, texture_creator: &'a TextureCreator<WindowContext>) -> Result<(Texture<'a>, Texture<'a>), String> {
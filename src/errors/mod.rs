@@ -1,11 +1,17 @@
-use std::fmt;
-#[derive(Debug)]
+use std::{fmt, io};
 
+/**
+ * A fault raised by the machine. Faults that happen while executing an instruction
+ * carry the address of that instruction (`pc`), its `opcode`, and the `operand` that
+ * caused the problem, so the caller can inspect the machine and decide what to do
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
-    MemoryInvalid,
-    UnknownOpcode,
-    EmptyStack,
-    FailedToReadLine,
+    MemoryInvalid { pc:u16, opcode:u16, operand:u16 },
+    UnknownOpcode { pc:u16, opcode:u16 },
+    EmptyStack { pc:u16, opcode:u16 },
+    DivideByZero { pc:u16, opcode:u16 },
+    AddressOutOfRange(u16),
 }
 
 impl fmt::Display for Error {
@@ -13,12 +19,167 @@ impl fmt::Display for Error {
         use Error::*;
 
         match self {
-            MemoryInvalid => write!(f, "Invalid memory access"),
-            UnknownOpcode => write!(f, "Unknown opcode"),
-            EmptyStack => write!(f, "Attempted to pop off of an empty stack"),
-            FailedToReadLine => write!(f, "Failed to read line from STDIN"),
+            MemoryInvalid { pc, opcode, operand } => write!(f, "Invalid memory access to {:#06X} by opcode {} at {:#06X}", operand, opcode, pc),
+            UnknownOpcode { pc, opcode } => write!(f, "Unknown opcode {} at {:#06X}", opcode, pc),
+            EmptyStack { pc, opcode } => write!(f, "Attempted to pop off of an empty stack (opcode {} at {:#06X})", opcode, pc),
+            DivideByZero { pc, opcode } => write!(f, "Division by zero (opcode {} at {:#06X})", opcode, pc),
+            AddressOutOfRange(addr) => write!(f, "Address {:#06X} is outside of memory", addr),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+/**
+ * What went wrong while assembling a line of source
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    OperandCount { mnemonic:String, expected:usize, found:usize },
+    LiteralOutOfRange(i64),
+    UnknownLabel(String),
+    DuplicateLabel(String),
+    ImageTooLarge,
+    Syntax(String),
+}
+
+/**
+ * An assembler error, with the (1-based) line and column it was found at
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line:usize,
+    pub column:usize,
+    pub kind:AsmErrorKind,
+}
+
+impl AsmError {
+    pub fn new(line:usize, column:usize, kind:AsmErrorKind) -> Self {
+        AsmError { line, column, kind }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        use AsmErrorKind::*;
+
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match &self.kind {
+            UnknownMnemonic(name) => write!(f, "unknown mnemonic `{}`", name),
+            OperandCount { mnemonic, expected, found } => write!(f, "`{}` takes {} operand(s), found {}", mnemonic, expected, found),
+            LiteralOutOfRange(value) => write!(f, "literal {} is out of range", value),
+            UnknownLabel(label) => write!(f, "unknown label `{}`", label),
+            DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            ImageTooLarge => write!(f, "program doesn't fit in {} words of memory", crate::constants::TOM),
+            Syntax(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/**
+ * Why an image couldn't be loaded
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    Io(String),
+    OddLength(usize),       // number of bytes in a binary image
+    TooLarge(usize),        // number of words in the image
+    InvalidWord { addr:u16, value:u16 },    // only in strict mode
+    Syntax { line:usize, message:String },  // in a decimal or Intel HEX image
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        use LoadError::*;
+
+        match self {
+            Io(message) => write!(f, "{}", message),
+            OddLength(len) => write!(f, "image is {} bytes long, which isn't a whole number of 16-bit words", len),
+            TooLarge(len) => write!(f, "image is {} words long, but memory only holds {}", len, crate::constants::TOM),
+            InvalidWord { addr, value } => write!(f, "word {:#06X} at {:#06X} is outside of the valid range 0..32775", value, addr),
+            Syntax { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e:io::Error) -> Self {
+        LoadError::Io(e.to_string())
+    }
+}
+
+/**
+ * The binary files read back by this crate, for `FormatError`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Snapshot,
+    TraceLog,
+    Recording,
+}
+
+impl FileFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileFormat::Snapshot => "snapshot",
+            FileFormat::TraceLog => "trace log",
+            FileFormat::Recording => "input recording",
+        }
+    }
+
+    pub fn error(self, kind:FormatErrorKind) -> FormatError {
+        FormatError { format: self, kind }
+    }
+
+    pub fn io(self, e:io::Error) -> FormatError {
+        self.error(FormatErrorKind::Io(e.to_string()))
+    }
+}
+
+/**
+ * What's wrong with a file, or with the name of a snapshot slot
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatErrorKind {
+    Io(String),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt(String),
+    NotFound(String),       // no save slot with this name
+    InvalidName(String),
+}
+
+/**
+ * Why a snapshot, trace log or input recording couldn't be read (or a snapshot slot written)
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatError {
+    pub format:FileFormat,
+    pub kind:FormatErrorKind,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        use FormatErrorKind::*;
+
+        let name = self.format.name();
+        match &self.kind {
+            Io(message) => write!(f, "{}", message),
+            BadMagic if name.starts_with(['a', 'e', 'i', 'o', 'u']) => write!(f, "not an {}", name),
+            BadMagic => write!(f, "not a {}", name),
+            UnsupportedVersion(version) => write!(f, "{} version {} isn't supported", name, version),
+            Truncated => write!(f, "{} is truncated", name),
+            Corrupt(message) => write!(f, "{} is corrupt: {}", name, message),
+            NotFound(slot) => write!(f, "there's no {} named `{}`", name, slot),
+            InvalidName(slot) => write!(f, "`{}` isn't a valid {} name (use letters, digits, `-`, `_` and `.`)", slot, name),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
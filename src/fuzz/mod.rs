@@ -0,0 +1,202 @@
+use std::panic::{self, AssertUnwindSafe};
+use crate::console::{MemoryInput, MemoryOutput};
+use crate::constants::TOM;
+use crate::instruction::{decode, Instruction, Operand, OPCODES};
+use crate::loader::{self, ImageFormat, LoadOptions};
+use crate::machine::{Machine, StepOutcome};
+use crate::snapshot::Snapshot;
+use crate::utils::{words_from_bytes, Rng};
+
+/**
+ * Instructions `execute` and `snapshot` run each input for
+ */
+pub const STEP_BUDGET:u64 = 10_000;
+
+/**
+ * A fuzz target: takes any bytes, and panics only if it finds a bug
+ */
+pub type Target = fn(&[u8]);
+
+/**
+ * Every target by name, for `fuzz/` and the offline driver
+ */
+pub const TARGETS:[(&str, Target); 4] = [
+    ("load_image", load_image),
+    ("decode", decode_image),
+    ("execute", execute),
+    ("snapshot", snapshot),
+];
+
+/**
+ * Parses the input as an image in every format, strictly and not, and loads whatever parses
+ */
+pub fn load_image(data:&[u8]) {
+    let formats = [None, Some(ImageFormat::Binary), Some(ImageFormat::Decimal), Some(ImageFormat::IntelHex)];
+    for format in formats.iter() {
+        for strict in [false, true].iter() {
+            let options = LoadOptions { format: *format, strict: *strict };
+            let words = match loader::parse_image(data, &options) {
+                Ok(words) => words,
+                Err(_) => continue,
+            };
+            assert!(words.len() <= TOM, "{} words parsed", words.len());
+            if *strict {
+                assert!(words.iter().all(|word| Operand::from_word(*word).is_some()));
+            }
+            let mut m0 = quiet_machine(b"");
+            m0.load_image_with(data, &options).expect("parsed, but didn't load");
+            assert_eq!(&m0.mem[..words.len()], &words[..]);
+            assert_eq!(m0.pc, 0);
+        }
+    }
+}
+
+/**
+ * Decodes an instruction at every address of the input as a binary image. Anything that
+ * decodes has to encode back to the same words
+ */
+pub fn decode_image(data:&[u8]) {
+    let words = words_from_bytes(data);
+    for addr in 0..words.len() {
+        if let Ok((instruction, size)) = decode(&words, addr as u16) {
+            assert_eq!(size, instruction.size());
+            assert_eq!(instruction.encode(), &words[addr..addr + size as usize]);
+            assert_eq!(OPCODES[instruction.opcode() as usize].1 + 1, size as usize);
+        }
+    }
+}
+
+/**
+ * The first byte is how much of what follows is input for the guest, the rest is a binary
+ * image. Runs it for `STEP_BUDGET` instructions, checking that arithmetic never leaves a
+ * register outside 0..32767
+ */
+pub fn execute(data:&[u8]) {
+    let (input, image) = split_input(data);
+    let mut m0 = quiet_machine(input);
+    let mut words = words_from_bytes(image);
+    words.truncate(TOM);
+    m0.mem[..words.len()].copy_from_slice(&words);
+    run(&mut m0);
+}
+
+/**
+ * Reads the input as a snapshot. One that reads has to write back the same, and is run for
+ * `STEP_BUDGET` instructions like `execute`
+ */
+pub fn snapshot(data:&[u8]) {
+    let snapshot = match Snapshot::from_bytes(data) {
+        Ok(snapshot) => snapshot,
+        Err(_) => return,
+    };
+    for compress in [false, true].iter() {
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes(*compress)).as_ref(), Ok(&snapshot));
+    }
+    let mut m0 = quiet_machine(b"");
+    m0.restore(&snapshot);
+    run(&mut m0);
+}
+
+fn quiet_machine(input:&[u8]) -> Machine {
+    Machine::with_io(Box::new(MemoryInput::new(input)), Box::new(MemoryOutput::new()))
+}
+
+fn split_input(data:&[u8]) -> (&[u8], &[u8]) {
+    match data.split_first() {
+        Some((len, rest)) => rest.split_at((*len as usize).min(rest.len())),
+        None => (&[], &[]),
+    }
+}
+
+fn run(m0:&mut Machine) {
+    for _ in 0..STEP_BUDGET {
+        let instruction = decode(&m0.mem, m0.pc).ok().map(|(instruction, _)| instruction);
+        match m0.fetch_and_execute() {
+            Ok(StepOutcome::Running) => {},
+            _ => return,
+        }
+        if let Some(Instruction::Eq(Operand::Register(reg), _, _))
+            | Some(Instruction::Gt(Operand::Register(reg), _, _))
+            | Some(Instruction::Add(Operand::Register(reg), _, _))
+            | Some(Instruction::Mult(Operand::Register(reg), _, _))
+            | Some(Instruction::Mod(Operand::Register(reg), _, _))
+            | Some(Instruction::And(Operand::Register(reg), _, _))
+            | Some(Instruction::Or(Operand::Register(reg), _, _))
+            | Some(Instruction::Not(Operand::Register(reg), _)) = instruction {
+            let value = m0.registers[reg as usize];
+            assert!((value as usize) < TOM, "{:?} left r{} = {}", instruction, reg, value);
+        }
+    }
+}
+
+/**
+ * Changes `input` a little: flips a bit, drops in a word that's on an edge (the largest
+ * literal, a register, an invalid word, an opcode), or inserts, removes or repeats a chunk
+ */
+pub fn mutate(rng:&mut Rng, input:&mut Vec<u8>, max_len:usize) {
+    const INTERESTING:[u16; 10] = [0, 1, 0x7FFF, 0x8000, 0x8007, 0x8008, 0xFFFF, 18, 20, 22];
+
+    let len = input.len() as u64;
+    match rng.below(6) {
+        0 if len > 0 => {
+            let at = rng.below(len) as usize;
+            input[at] ^= 1 << rng.below(8);
+        },
+        1 if len > 1 => {
+            let at = rng.below(len - 1) as usize & !1;
+            let word = if rng.chance(50) { INTERESTING[rng.below(INTERESTING.len() as u64) as usize] } else { rng.word() };
+            input[at..at + 2].copy_from_slice(&word.to_le_bytes());
+        },
+        2 => {
+            let at = rng.below(len + 1) as usize;
+            let bytes:Vec<u8> = (0..1 + rng.below(8)).map(|_| rng.word() as u8).collect();
+            input.splice(at..at, bytes);
+        },
+        3 if len > 0 => {
+            let at = rng.below(len) as usize;
+            let end = (at + 1 + rng.below(16) as usize).min(input.len());
+            input.drain(at..end);
+        },
+        4 if len > 0 => {
+            let at = rng.below(len) as usize;
+            let end = (at + 1 + rng.below(64) as usize).min(input.len());
+            let chunk:Vec<u8> = input[at..end].to_vec();
+            let to = rng.below(len + 1) as usize;
+            input.splice(to..to, chunk);
+        },
+        _ => {
+            let at = rng.below(len + 1) as usize;
+            input.truncate(at);
+        },
+    }
+    input.truncate(max_len);
+}
+
+/**
+ * Runs `target` on `runs` inputs, each a seed with a few mutations, without libFuzzer. Returns
+ * the first input that panicked, along with the panic message
+ */
+pub fn fuzz(target:Target, seeds:&[Vec<u8>], runs:u64, rng:&mut Rng) -> Result<(), (Vec<u8>, String)> {
+    let mut inputs:Vec<Vec<u8>> = seeds.to_vec();
+    if inputs.is_empty() {
+        inputs.push(Vec::new());
+    }
+    let max_len = inputs.iter().map(|input| input.len()).max().unwrap_or(0).max(256) * 2;
+
+    for _ in 0..runs {
+        let mut input = inputs[rng.below(inputs.len() as u64) as usize].clone();
+        for _ in 0..1 + rng.below(4) {
+            mutate(rng, &mut input, max_len);
+        }
+        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| target(&input))) {
+            let message = e.downcast_ref::<String>().cloned()
+                .or_else(|| e.downcast_ref::<&str>().map(|message| message.to_string()))
+                .unwrap_or_default();
+            return Err((input, message));
+        }
+        if inputs.len() < 64 && rng.chance(5) {
+            inputs.push(input);     // a poor man's corpus: keep some mutants to mutate further
+        }
+    }
+    Ok(())
+}
@@ -1,134 +1,818 @@
 use crate::machine::Machine;
-use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::{fs, io};
-use crate::utils::swap_endian;
-use std::io::{Read, Write};
-use std::str::Split;
-use crate::constants::TOM;
-
-pub fn write_memory(m0:&mut Machine) {
-    println!("write memory");
-
-    let mut buffer:String = String::new();
-    std::io::stdin().read_line(&mut buffer).unwrap();
-    buffer.pop(); // remove \n
-    let tokens:Vec<&str> = buffer.split(" ").collect();
-    if tokens.len() < 3 {
-        println!("Usage: w NNNN v
-        NNNN - memory location in HEX
-        v - value in HEX");
-    } else {
+use std::io::Write;
+use std::fmt::Write as _;
+use crate::constants::*;
+use crate::debugger::{Access, Condition, RunMode, StopReason, WatchTarget};
+use crate::instruction::{decode, Operand};
+use crate::snapshot::SlotStore;
+use crate::timeline::Timeline;
+use crate::trace::{TraceFilter, Tracer};
+use crate::utils::{format_timestamp, get_bit};
+use crate::{analysis, disassembler};
+
+/**
+ * The name `save` and `load` use when they aren't given one
+ */
+const QUICK_SLOT:&str = "quick";
+
+const PROMPT:&str = "hv> ";
+const HISTORY_SIZE:usize = 100;
+
+/**
+ * What the hypervisor does after a command: wait for the next one or hand control back
+ * to the guest
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Stay,
+    Resume,
+}
+
+/**
+ * A hypervisor command. It can be typed in full, as any unambiguous prefix of `name`,
+ * or as `alias`
+ */
+struct Command {
+    name:&'static str,
+    alias:&'static str,     // "" if there isn't one
+    args:&'static str,
+    help:&'static str,
+    run:fn(&mut Machine, &[&str]) -> Result<Flow, String>,
+}
+
+static COMMANDS:[Command; 30] = [
+    Command { name: "help", alias: "h", args: "", help: "list the commands", run: help },
+    Command { name: "continue", alias: "r", args: "", help: "Return to the guest", run: continue_guest },
+    Command { name: "step", alias: "", args: "[N]", help: "execute N instructions (1 by default)", run: step },
+    Command { name: "next", alias: "n", args: "", help: "step over a call", run: next },
+    Command { name: "finish", alias: "", args: "", help: "run until the current function returns", run: finish },
+    Command { name: "step-back", alias: "", args: "[N]", help: "undo N instructions (1 by default), needs record", run: step_back },
+    Command { name: "reverse-continue", alias: "rc", args: "", help: "run backwards to a breakpoint or a watched write", run: reverse_continue },
+    Command { name: "goto", alias: "g", args: "NNNN", help: "Goto NNNN and run", run: goto_and_run },
+    Command { name: "print", alias: "p", args: "", help: "Print registers, status flags and the top of the stack", run: print_regs },
+    Command { name: "examine", alias: "x", args: "SSSS EEEE", help: "eXamine memory", run: examine_memory },
+    Command { name: "write", alias: "w", args: "NNNN|rN v", help: "Write v to memory or a register", run: write_memory },
+    Command { name: "set", alias: "", args: "NNNN|rN v", help: "like write, but a plain number v is decimal", run: set_value },
+    Command { name: "disassemble", alias: "d", args: "SSSS EEEE", help: "Disassemble", run: disassemble },
+    Command { name: "break", alias: "b", args: "NNNN [if COND]", help: "set a Breakpoint", run: add_breakpoint },
+    Command { name: "watch", alias: "", args: "NNNN|rN [r|w|rw]", help: "stop when memory or a register is read and/or written (w by default)", run: add_watchpoint },
+    Command { name: "info", alias: "", args: "", help: "list breakpoints and watchpoints", run: list_breakpoints },
+    Command { name: "clear", alias: "", args: "[ID]", help: "remove a breakpoint or watchpoint, or all of them", run: clear_breakpoints },
+    Command { name: "save", alias: "s", args: "[NAME]", help: "Save state", run: save_state },
+    Command { name: "load", alias: "l", args: "[NAME]", help: "Load state", run: load_state },
+    Command { name: "list", alias: "", args: "", help: "list saved states", run: list_states },
+    Command { name: "delete", alias: "", args: "NAME", help: "delete a saved state", run: delete_state },
+    Command { name: "entry", alias: "e", args: "NNNN", help: "add an Entry point for code analysis", run: add_entry_point },
+    Command { name: "function", alias: "f", args: "NNNN", help: "Find the function containing NNNN", run: find_function },
+    Command { name: "graph", alias: "c", args: "FILE [calls]", help: "export the Control-flow graph, or the call graph", run: export_graph },
+    Command { name: "label", alias: "", args: "[NAME [NNNN]]", help: "name an address (pc by default), or list the names", run: label },
+    Command { name: "history", alias: "", args: "", help: "list previous commands, rerun one with !N", run: history },
+    Command { name: "record", alias: "", args: "[on [SIZE]|off]", help: "keep an undo log of the last SIZE instructions, or say how much there is", run: record },
+    Command { name: "who-wrote", alias: "", args: "NNNN|rN", help: "find the instruction that last wrote memory or a register", run: who_wrote },
+    Command { name: "trace", alias: "", args: "[N|on [SIZE] FILTER..|off]", help: "show the last N traced instructions (20 by default), or start or stop tracing", run: trace },
+    Command { name: "debug", alias: "D", args: "", help: "toggle Debug output", run: toggle_debug },
+];
+
+const ARGUMENT_HELP:&str = "\
+NNNN, SSSS, EEEE and v are hex (0AAC or 0x0AAC), decimal after a # (#2732), a register's
+value (r0..r7), pc, a name from `label`, or sub_NNNN
+N, SIZE and ID are decimal
+FILTER is addr SSSS[-EEEE], op NAME (e.g. call) or window N-[M] (instruction counts)
+COND is a comparison like r7 != 0 or [0AAC] >= 0x10: registers, pc, [NNNN] memory, numbers
+NAME is letters, digits, - _ and ., quick if it's left out of save and load
+An empty line repeats the last command";
+
+/**
+ * What the hypervisor remembers between visits: command history and address names
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    history:Vec<String>,
+    pub labels:BTreeMap<String, u16>,
+}
+
+impl Session {
+    /**
+     * Turns what was typed into the command to run: an empty line or `!!` is the last
+     * command, `!N` is command N from `history`. The result is added to the history
+     */
+    pub fn expand(&mut self, line:&str) -> Result<String, String> {
+        let line = line.trim();
+        let command = match line {
+            "" | "!!" => match self.history.last() {
+                Some(last) => last.clone(),
+                None if line.is_empty() => return Ok(String::new()),
+                None => return Err("there's no previous command".to_string()),
+            },
+            _ => match line.strip_prefix('!') {
+                Some(n) => {
+                    let n = n.parse::<usize>().map_err(|_| format!("`{}` isn't !N or !!", line))?;
+                    n.checked_sub(1).and_then(|i| self.history.get(i)).cloned()
+                        .ok_or_else(|| format!("there's no command {} in the history", n))?
+                },
+                None => line.to_string(),
+            },
+        };
+        if self.history.last() != Some(&command) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.remove(0);
+            }
+            self.history.push(command.clone());
+        }
+        Ok(command)
+    }
+}
+
+/**
+ * Reads and runs commands from STDIN until one of them resumes the guest or STDIN ends
+ */
+pub fn command_loop(m0:&mut Machine) {
+    loop {
+        print!("{}", PROMPT);
         io::stdout().flush().unwrap();
 
-        let loc:u16 = i64::from_str_radix(tokens[1], 16).unwrap() as u16;
-        let val:u16 = i64::from_str_radix(tokens[2], 16).unwrap() as u16;
-        if (loc <= TOM as u16) {
-            m0.mem[loc as usize] = swap_endian(val);
-        } else {
-            println!("Invalid params");
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            println!();
+            break;
         }
+        match m0.session.expand(&line).and_then(|line| execute(m0, &line)) {
+            Ok(Flow::Resume) => break,
+            Ok(Flow::Stay) => {},
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/**
+ * Runs a hypervisor command from line `line` of an input script, showing it as if it had
+ * been typed
+ */
+pub fn run_directive(m0:&mut Machine, line:usize, command:&str) {
+    println!("{}{}", PROMPT, command);
+    if let Err(e) = execute(m0, command) {
+        println!("script line {}: {}", line, e);
     }
+}
 
+/**
+ * Runs one command line
+ */
+pub fn execute(m0:&mut Machine, line:&str) -> Result<Flow, String> {
+    let words:Vec<&str> = line.split_whitespace().collect();
+    let (word, args) = match words.split_first() {
+        Some((word, args)) => (*word, args),
+        None => return Ok(Flow::Stay),
+    };
+    let command = find_command(word)?;
+    (command.run)(m0, args).map_err(|e| format!("{}\nusage: {} {}", e, command.name, command.args))
 }
 
+/**
+ * Looks up a command by alias or full name, then by unambiguous prefix. A prefix of a
+ * command and of longer names that start with it, like `step` and `step-back`, is the command
+ */
+fn find_command(word:&str) -> Result<&'static Command, String> {
+    if let Some(command) = COMMANDS.iter().find(|command| command.alias == word || command.name == word) {
+        return Ok(command);
+    }
+    let matches:Vec<&Command> = COMMANDS.iter().filter(|command| command.name.starts_with(word)).collect();
+    // `st` is step rather than step-back
+    if let Some(command) = matches.iter().find(|command| matches.iter().all(|other| other.name.starts_with(command.name))) {
+        return Ok(command);
+    }
+    match matches.as_slice() {
+        [] => Err(format!("unknown command `{}` (h for help)", word)),
+        _ => Err(format!("`{}` could be {}", word, matches.iter().map(|command| command.name).collect::<Vec<&str>>().join(", "))),
+    }
+}
+
+/**
+ * Parses a word typed in the hypervisor: a label, or anything `parse_number` takes
+ */
+pub fn parse_word(m0:&Machine, token:&str) -> Result<u16, String> {
+    match m0.session.labels.get(token) {
+        Some(addr) => Ok(*addr),
+        None => parse_number(m0, token),
+    }
+}
 
-pub fn disassemble(m0:&mut Machine) {
-    println!("disassemble");
+/**
+ * Hex with or without `0x`, decimal after a `#`, the value of a register or pc, or `sub_NNNN`
+ */
+fn parse_number(m0:&Machine, token:&str) -> Result<u16, String> {
+    if token == "pc" {
+        return Ok(m0.pc());
+    }
+    if let Some(reg) = register_name(token) {
+        return Ok(m0.register(reg).unwrap_or(0));
+    }
+    let parsed = match token.strip_prefix('#') {
+        Some(decimal) => decimal.parse::<u16>(),
+        None => u16::from_str_radix(token.strip_prefix("0x").or_else(|| token.strip_prefix("sub_")).unwrap_or(token), 16),
+    };
+    parsed.map_err(|_| format!("`{}` isn't a number, register or label", token))
+}
 
-    let mut buffer:String = String::new();
-    std::io::stdin().read_line(&mut buffer).unwrap();
-    buffer.pop(); // remove \n
-    let tokens:Vec<&str> = buffer.split(" ").collect();
-    if tokens.len() < 3 {
-        println!("Usage: d SSSS EEEE
-        SSSS - starting address in HEX
-        EEEE - ending address in HEX");
-    } else {
-        io::stdout().flush().unwrap();
+/**
+ * `r0`..`r7`
+ */
+fn register_name(token:&str) -> Option<usize> {
+    match token.strip_prefix('r').map(|reg| reg.parse::<usize>()) {
+        Some(Ok(reg)) if reg < NUM_REG => Some(reg),
+        _ => None,
+    }
+}
 
-        let start:u16 = i64::from_str_radix(tokens[1], 16).unwrap() as u16;
-        let end:u16 = i64::from_str_radix(tokens[2], 16).unwrap() as u16;
-        if (start <= end) && (end <= TOM as u16) {
-            disassemble_range(m0, start, end);
-        } else {
-            println!("Invalid params");
-        }
+/**
+ * Like `parse_word`, for a memory address
+ */
+pub fn parse_addr(m0:&Machine, token:&str) -> Result<u16, String> {
+    match parse_word(m0, token)? {
+        addr if (addr as usize) < TOM => Ok(addr),
+        addr => Err(format!("{:#06X} is outside of memory", addr)),
     }
 }
 
+/**
+ * The `n`th argument, or an error naming what's missing
+ */
+fn arg<'a>(args:&[&'a str], n:usize, what:&str) -> Result<&'a str, String> {
+    args.get(n).copied().ok_or_else(|| format!("missing {}", what))
+}
+
+fn no_more_args(args:&[&str], expected:usize) -> Result<(), String> {
+    match args.get(expected) {
+        Some(extra) => Err(format!("unexpected `{}`", extra)),
+        None => Ok(()),
+    }
+}
+
+fn help(_m0:&mut Machine, _args:&[&str]) -> Result<Flow, String> {
+    for command in COMMANDS.iter() {
+        let alias = if command.alias.is_empty() { String::new() } else { format!("{} ", command.alias) };
+        let synopsis = format!("{}{} {}", alias, command.name, command.args);
+        println!("{:<32} {}", synopsis, command.help);
+    }
+    println!("\n{}", ARGUMENT_HELP);
+    Ok(Flow::Stay)
+}
+
+fn continue_guest(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    m0.set_run_mode(RunMode::Continue);
+    println!("returning execution to guest...\n***\n\n");
+    Ok(Flow::Resume)
+}
+
+/**
+ * `w NNNN v` writes memory, `w rN v` writes a register
+ */
+pub fn write_memory(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let target = arg(args, 0, "NNNN")?;
+    let val = parse_word(m0, arg(args, 1, "v")?)?;
+    no_more_args(args, 2)?;
+    write_target(m0, target, val)
+}
+
+/**
+ * `set r7 25734`, the way the spec writes numbers. Anything that isn't all digits is
+ * parsed like in `write`
+ */
+pub fn set_value(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let target = arg(args, 0, "NNNN")?;
+    let token = arg(args, 1, "v")?;
+    no_more_args(args, 2)?;
+    let val = match token.parse::<u16>() {
+        Ok(val) => val,
+        Err(_) if token.bytes().all(|byte| byte.is_ascii_digit()) => return Err(format!("`{}` is too big", token)),
+        Err(_) => parse_word(m0, token)?,
+    };
+    write_target(m0, target, val)
+}
+
+fn write_target(m0:&mut Machine, target:&str, val:u16) -> Result<Flow, String> {
+    if val as usize >= TOM + NUM_REG {
+        return Err(format!("{:#06X} isn't a valid word (0..0x8007)", val));
+    }
+    match register_name(target) {
+        Some(reg) => m0.set_register(reg, val).map_err(|e| e.to_string())?,
+        None => m0.write_word(parse_addr(m0, target)?, val).map_err(|e| e.to_string())?,
+    }
+    Ok(Flow::Stay)
+}
+
+pub fn disassemble(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let start = parse_addr(m0, arg(args, 0, "SSSS")?)?;
+    let end = parse_addr(m0, arg(args, 1, "EEEE")?)?;
+    no_more_args(args, 2)?;
+    if start > end {
+        return Err("SSSS is after EEEE".to_string());
+    }
+    disassemble_range(m0, start, end);
+    Ok(Flow::Stay)
+}
+
 pub fn disassemble_range(m0:&Machine, start:u16, end:u16) {
-    let mut addr:u16 = start;
-    while addr <= end {
-        let opcode:u16 = swap_endian(m0.mem[addr as usize]);
-        let a1:u16 = swap_endian(m0.mem[(addr+1) as usize]);
-        let a2:u16 = swap_endian(m0.mem[(addr+2) as usize]);
-        let a3:u16 = swap_endian(m0.mem[(addr+3) as usize]);
-
-        match opcode {
-            0x0000 => { println!("{:#06X}:\thalt", addr);                               addr += 2; },
-            0x0001 => { println!("{:#06X}:\tset\t\t{:#06X}\t{:#06X}", addr, a1, a2);    addr += 3; },
-            0x0002 => { println!("{:#06X}:\tpush\t{:#06X}", addr, a1);                  addr += 2; },
-            0x0003 => { println!("{:#06X}:\tpop\t\t{:#06X}", addr, a1);                 addr += 2; },
-            0x0004 => { println!("{:#06X}:\teq\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3); addr += 4 },
-            0x0005 => { println!("{:#06X}:\tgt\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3); addr += 4 },
-            0x0006 => { println!("{:#06X}:\tjmp\t\t{:#06X}", addr, a1);                 addr += 2 },
-            0x0007 => { println!("{:#06X}:\tjt\t\t{:#06X}\t{:#06X}", addr, a1, a2);     addr += 3 },
-            0x0008 => { println!("{:#06X}:\tjf\t\t{:#06X}\t{:#06X}", addr, a1, a2);     addr += 3 },
-            0x0009 => { println!("{:#06X}:\tadd\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3);     addr += 4 },
-            0x000A => { println!("{:#06X}:\tmult\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3);    addr += 4 },
-            0x000B => { println!("{:#06X}:\tmod\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3);     addr += 4 },
-            0x000C => { println!("{:#06X}:\tand\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3);     addr += 4 },
-            0x000D => { println!("{:#06X}:\tor\t\t{:#06X}\t{:#06X}\t{:#06X}", addr, a1, a2, a3);      addr += 4 },
-            0x000E => { println!("{:#06X}:\tnot\t\t{:#06X}\t{:#06X}", addr, a1, a2);     addr += 3 },
-            0x000F => { println!("{:#06X}:\trmem\t{:#06X}\t{:#06X}", addr, a1, a2);     addr += 3 },
-            0x0010 => { println!("{:#06X}:\twmem\t{:#06X}\t{:#06X}", addr, a1, a2);     addr += 3 },
-            0x0011 => { println!("{:#06X}:\tcall\t{:#06X}", addr, a1);      addr += 2 },
-            0x0012 => { println!("{:#06X}:\tret", addr);                    addr += 1 },
-            0x0013 => { println!("{:#06X}:\tout\t\t{:#06X}", addr, a1);     addr += 2 },
-            0x0014 => { println!("{:#06X}:\tin\t\t{:#06X}", addr, a1);      addr += 2 },
-            0x0015 => { println!("{:#06X}:\tnop", addr);                    addr += 1; },
-             _ => { println!("{:#06X}:\t??? ({:#06X})", addr, opcode);      addr += 1; },
+    let words = disassembler::machine_words(m0);
+    let analysis = analysis::analyze(&words, &m0.entry_points);
+    print!("{}", disassembler::disassemble_with(&words, start, end, &analysis));
+}
+
+pub fn add_entry_point(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let addr = parse_addr(m0, arg(args, 0, "NNNN")?)?;
+    no_more_args(args, 1)?;
+    if !m0.entry_points.contains(&addr) {
+        m0.entry_points.push(addr);
+    }
+    let list:Vec<String> = m0.entry_points.iter().map(|addr| format!("{:#06X}", addr)).collect();
+    println!("entry points: 0x0000 {}", list.join(" "));
+    Ok(Flow::Stay)
+}
+
+pub fn find_function(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let addr = parse_addr(m0, arg(args, 0, "NNNN")?)?;
+    no_more_args(args, 1)?;
+
+    let analysis = analysis::analyze(&disassembler::machine_words(m0), &m0.entry_points);
+    let functions = analysis.functions_containing(addr);
+    if functions.is_empty() {
+        println!("{:#06X} isn't in any reached code (try adding an entry point with e)", addr);
+    }
+    for function in functions {
+        let end = function.blocks.iter().map(|start| analysis.block_containing(*start).map_or(*start, |block| block.end)).max().unwrap_or(function.entry);
+        println!("{:#06X} is in sub_{:04X} ({} blocks, up to {:#06X}, calls {})",
+                 addr, function.entry, function.blocks.len(), end,
+                 function.calls.iter().map(|target| format!("sub_{:04X}", target)).collect::<Vec<String>>().join(" "));
+    }
+    Ok(Flow::Stay)
+}
+
+pub fn export_graph(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let path = arg(args, 0, "FILE")?;
+    let calls = match args.get(1) {
+        Some(&"calls") => true,
+        Some(other) => return Err(format!("unexpected `{}`", other)),
+        None => false,
+    };
+    no_more_args(args, 2)?;
+
+    let analysis = analysis::analyze(&disassembler::machine_words(m0), &m0.entry_points);
+    let dot = if calls { analysis.call_graph_dot() } else { analysis.cfg_dot() };
+    fs::write(path, dot).map_err(|e| format!("couldn't write {}: {}", path, e))?;
+    println!("wrote {}", path);
+    Ok(Flow::Stay)
+}
+
+pub fn save_state(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let name = args.first().copied().unwrap_or(QUICK_SLOT);
+    no_more_args(args, 1)?;
+    SlotStore::new(&m0.snapshot_dir).save(name, &m0.snapshot()).map_err(|e| format!("couldn't save {}: {}", name, e))?;
+    println!("saved state as {}", name);
+    Ok(Flow::Stay)
+}
+
+pub fn load_state(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let name = args.first().copied().unwrap_or(QUICK_SLOT);
+    no_more_args(args, 1)?;
+    let snapshot = SlotStore::new(&m0.snapshot_dir).load(name).map_err(|e| format!("couldn't load {}: {}", name, e))?;
+    m0.restore(&snapshot);
+    println!("loaded state {}", name);
+    Ok(Flow::Stay)
+}
+
+/**
+ * Lists the saved snapshots, oldest first, with the last lines of output each one was
+ * saved after
+ */
+pub fn list_states(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    let slots = SlotStore::new(&m0.snapshot_dir).list().map_err(|e| format!("couldn't list {}: {}", m0.snapshot_dir.display(), e))?;
+    if slots.is_empty() {
+        println!("no snapshots in {}", m0.snapshot_dir.display());
+    }
+    for (name, snapshot) in slots {
+        println!("{:<20} {}  {:>12} instructions  pc {:#06X}",
+                 name, format_timestamp(snapshot.saved_at), snapshot.executed, snapshot.pc);
+        for line in String::from_utf8_lossy(&snapshot.recent_output).lines() {
+            println!("    | {}", line);
         }
     }
+    Ok(Flow::Stay)
 }
 
-pub fn save_state(m0:&mut Machine) {
-    println!("saving state");
-    let serialized = serde_json::to_string(&m0).unwrap();
-    fs::write("state0.bin", serialized).unwrap();
+pub fn delete_state(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let name = arg(args, 0, "NAME")?;
+    no_more_args(args, 1)?;
+    SlotStore::new(&m0.snapshot_dir).delete(name).map_err(|e| format!("couldn't delete {}: {}", name, e))?;
+    println!("deleted {}", name);
+    Ok(Flow::Stay)
 }
 
-pub fn load_state(m0:&mut Machine) {
-    println!("loading state");
-    let str:String = fs::read_to_string("state0.bin").unwrap();
-    let deserialized:Machine = serde_json::from_str(&str).unwrap();
+/**
+ * Names of the status register bits, lowest first
+ */
+const STATUS_FLAGS:[(&str, u16); 6] = [
+    ("HLT", HALT_BIT),
+    ("M1", M1_BIT),
+    ("MEMR", MEMR_BIT),
+    ("MEMW", MEMW_BIT),
+    ("OUT", OUT_BIT),
+    ("IN", IN_BIT),
+];
 
-    for x in 0..deserialized.mem.len() {
-        m0.mem[x] = deserialized.mem[x];
+const STACK_ENTRIES_SHOWN:usize = 8;
+const WORDS_PER_ROW:usize = 8;
+
+pub fn print_regs(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    print!("{}", registers_report(m0));
+    Ok(Flow::Stay)
+}
+
+/**
+ * The registers in hex and decimal, pc, the status flags by name, and the top of the stack
+ */
+pub fn registers_report(m0:&Machine) -> String {
+    let mut report = String::new();
+    for reg in 0..NUM_REG {
+        let value = m0.register(reg).unwrap_or(0);
+        write!(report, "r{} {:#06X} {:>5}", reg, value, value).unwrap();
+        report.push_str(if reg % 4 == 3 { "\n" } else { "    " });
     }
-    m0.status = deserialized.status;
-    m0.recentMemAccess = deserialized.recentMemAccess;
+
+    let flags:Vec<&str> = STATUS_FLAGS.iter()
+        .filter(|(_, bit)| get_bit(&m0.status, *bit))
+        .map(|(name, _)| *name)
+        .collect();
+    writeln!(report, "pc {:#06X}    status {:#06X} [{}]", m0.pc(), m0.status, flags.join(" ")).unwrap();
+
+    let stack = m0.stack();
+    let top:Vec<String> = stack.iter().rev().take(STACK_ENTRIES_SHOWN).map(|word| format!("{:#06X}", word)).collect();
+    let more = if stack.len() > STACK_ENTRIES_SHOWN { " ..." } else { "" };
+    writeln!(report, "stack depth {}: {}{}", stack.len(), top.join(" "), more).unwrap();
+    report
 }
 
-pub fn print_regs(m0:&mut Machine) {
-    println!("printing registers");
+pub fn goto_and_run(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let addr = parse_addr(m0, arg(args, 0, "NNNN")?)?;
+    no_more_args(args, 1)?;
+    m0.set_pc(addr);
+    m0.set_run_mode(RunMode::Continue);
+    println!("running from {:#06X}...\n***\n\n", addr);
+    Ok(Flow::Resume)
 }
 
-pub fn goto_and_run(m0:&mut Machine) {
-    println!("goto and run");
+pub fn examine_memory(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let start = parse_addr(m0, arg(args, 0, "SSSS")?)?;
+    let end = parse_addr(m0, arg(args, 1, "EEEE")?)?;
+    no_more_args(args, 2)?;
+    if start > end {
+        return Err("SSSS is after EEEE".to_string());
+    }
+    print!("{}", hexdump(m0, start, end));
+    Ok(Flow::Stay)
 }
 
-pub fn examine_memory(m0:&mut Machine) {
-    println!("examine memory");
+/**
+ * Dumps `start..=end`, eight words to a row, with a column of the words as ASCII characters.
+ * Each row ends with notes on words that name registers and on pc or registers that point
+ * into the row
+ */
+pub fn hexdump(m0:&Machine, start:u16, end:u16) -> String {
+    let mut dump = String::new();
+    for row in (start as usize..=end as usize).step_by(WORDS_PER_ROW) {
+        let addrs = row..(row + WORDS_PER_ROW).min(end as usize + 1);
+        let words:Vec<u16> = addrs.clone().map(|addr| m0.read_word(addr as u16).unwrap_or(0)).collect();
+
+        write!(dump, "{:04X}:", row).unwrap();
+        for word in words.iter() {
+            write!(dump, " {:04X}", word).unwrap();
+        }
+        dump.push_str(&"     ".repeat(WORDS_PER_ROW - words.len()));
+        dump.push_str("  ");
+        for word in words.iter() {
+            dump.push(if (0x20..=0x7E).contains(word) { *word as u8 as char } else { '.' });
+        }
+
+        let mut notes:Vec<String> = Vec::new();
+        for (addr, word) in addrs.clone().zip(words.iter()) {
+            if let Some(Operand::Register(reg)) = Operand::from_word(*word) {
+                notes.push(format!("{:04X}=r{}", addr, reg));
+            }
+        }
+        if addrs.contains(&(m0.pc() as usize)) {
+            notes.push(format!("pc->{:04X}", m0.pc()));
+        }
+        for reg in 0..NUM_REG {
+            let value = m0.register(reg).unwrap_or(0);
+            if addrs.contains(&(value as usize)) {
+                notes.push(format!("r{}->{:04X}", reg, value));
+            }
+        }
+        if !notes.is_empty() {
+            dump.push_str(&" ".repeat(WORDS_PER_ROW - words.len()));
+            write!(dump, "  ; {}", notes.join(" ")).unwrap();
+        }
+        dump.push('\n');
+    }
+    dump
 }
 
-pub fn toggle_debug(m0:&mut Machine) {
+pub fn toggle_debug(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
     print!("toggling debug output ");
     m0.debug ^= true;
-    if (m0.debug) {
+    if m0.debug {
         println!("on");
     } else {
         println!("off");
     }
+    Ok(Flow::Stay)
 }
 
+/**
+ * `record on` starts an undo log, `record off` drops it, `record` says what's in it
+ */
+pub fn record(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    match args.first().copied() {
+        Some("on") => {
+            let size = match args.get(1) {
+                Some(token) => match token.parse::<usize>() {
+                    Ok(size) if size > 0 => size,
+                    _ => return Err(format!("`{}` isn't a number of instructions", token)),
+                },
+                None => DEFAULT_TIMELINE_SIZE,
+            };
+            no_more_args(args, 2)?;
+            m0.set_timeline(Some(Timeline::new(size, CHECKPOINT_INTERVAL)));
+        },
+        Some("off") => {
+            no_more_args(args, 1)?;
+            m0.set_timeline(None);
+        },
+        Some(other) => return Err(format!("unexpected `{}`", other)),
+        None => {},
+    }
+    match m0.timeline() {
+        Some(timeline) => println!("recording the last {} instructions: {} so far, back to #{} ({} checkpoints)",
+                                   timeline.capacity(), timeline.len(), timeline.oldest().unwrap_or(m0.executed()), timeline.checkpoints()),
+        None => println!("not recording"),
+    }
+    Ok(Flow::Stay)
+}
+
+fn timeline_len(m0:&Machine) -> Result<usize, String> {
+    match m0.timeline() {
+        Some(timeline) if !timeline.is_empty() => Ok(timeline.len()),
+        Some(_) => Err("nothing has been recorded yet".to_string()),
+        None => Err("not recording, start with `record on`".to_string()),
+    }
+}
+
+pub fn step_back(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let steps = match args.first() {
+        Some(token) => match token.parse::<usize>() {
+            Ok(steps) if steps > 0 => steps,
+            _ => return Err(format!("`{}` isn't a number of instructions", token)),
+        },
+        None => 1,
+    };
+    no_more_args(args, 1)?;
+    timeline_len(m0)?;
+    let undone = m0.step_back(steps);
+    if undone < steps {
+        println!("only {} instructions were recorded", undone);
+    }
+    report_stop(m0, StopReason::Stepped);
+    Ok(Flow::Stay)
+}
+
+pub fn reverse_continue(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    timeline_len(m0)?;
+    match m0.reverse_continue() {
+        Some(reason) => report_stop(m0, reason),
+        None => {
+            println!("reached the start of the recording");
+            report_stop(m0, StopReason::Stepped);
+        },
+    }
+    Ok(Flow::Stay)
+}
+
+pub fn who_wrote(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let token = arg(args, 0, "NNNN|rN")?;
+    no_more_args(args, 1)?;
+    let addr = match register_name(token) {
+        Some(reg) => (TOM + reg) as u16,
+        None => parse_addr(m0, token)?,
+    };
+    let name = match register_name(token) {
+        Some(reg) => format!("r{}", reg),
+        None => format!("{:#06X}", addr),
+    };
+    timeline_len(m0)?;
+    match m0.last_write(addr) {
+        Some(write) => {
+            println!("{} was last written by #{} at {:#06X}: {:#06X} -> {:#06X}", name, write.executed, write.pc, write.old, write.new);
+            if let Ok((instruction, _)) = decode(&disassembler::machine_words(m0), write.pc) {
+                println!("{:#06X}:\t{}", write.pc, instruction);
+            }
+        },
+        None => println!("{} hasn't been written since #{}", name, m0.timeline().and_then(|timeline| timeline.oldest()).unwrap_or(0)),
+    }
+    Ok(Flow::Stay)
+}
+
+/**
+ * `trace on` records into a ring buffer of SIZE instructions, `trace off` stops, and
+ * `trace [N]` shows the last N records
+ */
+pub fn trace(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    match args.first().copied() {
+        Some("on") => {
+            let (size, filters) = match args.get(1).map(|token| token.parse::<usize>()) {
+                Some(Ok(size)) if size > 0 => (size, &args[2..]),
+                Some(Ok(_)) => return Err("SIZE has to be at least 1".to_string()),
+                _ => (DEFAULT_TRACE_SIZE, &args[1..]),
+            };
+            let mut filter = TraceFilter::default();
+            for pair in filters.chunks(2) {
+                filter.add(pair[0], arg(pair, 1, "a value for the filter")?)?;
+            }
+            m0.set_tracer(Some(Tracer::ring(size).with_filter(filter)));
+        },
+        Some("off") => {
+            no_more_args(args, 1)?;
+            m0.set_tracer(None);
+            println!("tracing off");
+            return Ok(Flow::Stay);
+        },
+        Some(token) => {
+            no_more_args(args, 1)?;
+            let count = token.parse::<usize>().map_err(|_| format!("`{}` isn't a number of instructions", token))?;
+            return show_trace(m0, count);
+        },
+        None => return show_trace(m0, 20),
+    }
+    if let Some(tracer) = m0.tracer() {
+        println!("{}", tracer.describe());
+    }
+    Ok(Flow::Stay)
+}
+
+fn show_trace(m0:&Machine, count:usize) -> Result<Flow, String> {
+    let tracer = m0.tracer().ok_or("not tracing, start with `trace on`")?;
+    let records = tracer.records();
+    for record in records.iter().skip(records.len().saturating_sub(count)) {
+        println!("{}", record);
+    }
+    println!("{}", tracer.describe());
+    Ok(Flow::Stay)
+}
+
+/**
+ * Prints why execution stopped and the instruction at `pc`
+ */
+pub fn report_stop(m0:&Machine, reason:StopReason) {
+    println!("\n**** {} ****", reason);
+    match decode(&disassembler::machine_words(m0), m0.pc()) {
+        Ok((instruction, _)) => println!("{:#06X}:\t{}", m0.pc(), instruction),
+        Err(e) => println!("{:#06X}:\t{}", m0.pc(), e),
+    }
+}
+
+pub fn add_breakpoint(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let addr = parse_addr(m0, arg(args, 0, "NNNN")?)?;
+    let condition = match &args[1..] {
+        [] => None,
+        ["if", condition @ ..] if !condition.is_empty() => Some(condition.join(" ").parse::<Condition>()?),
+        _ => return Err("expected `if COND` after the address".to_string()),
+    };
+    let id = m0.debugger_mut().add_breakpoint(addr, condition);
+    println!("breakpoint {} at {:#06X}", id, addr);
+    Ok(Flow::Stay)
+}
+
+pub fn add_watchpoint(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let token = arg(args, 0, "NNNN or a register")?;
+    let target = match register_name(token) {
+        Some(reg) => WatchTarget::Register(reg as u8),
+        None => WatchTarget::Memory(parse_addr(m0, token)?),
+    };
+    let access = match args.get(1) {
+        Some(token) => token.parse::<Access>()?,
+        None => Access::Write,
+    };
+    no_more_args(args, 2)?;
+    let id = m0.debugger_mut().add_watchpoint(target, access);
+    println!("watchpoint {} on {} ({})", id, target, access);
+    Ok(Flow::Stay)
+}
+
+pub fn list_breakpoints(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    let debugger = m0.debugger();
+    if debugger.breakpoints().next().is_none() && debugger.watchpoints().next().is_none() {
+        println!("no breakpoints or watchpoints");
+    }
+    for (id, breakpoint) in debugger.breakpoints() {
+        match breakpoint.condition {
+            Some(condition) => println!("{:>3}  break {:#06X} if {}", id, breakpoint.addr, condition),
+            None => println!("{:>3}  break {:#06X}", id, breakpoint.addr),
+        }
+    }
+    for (id, watchpoint) in debugger.watchpoints() {
+        println!("{:>3}  watch {} ({})", id, watchpoint.target, watchpoint.access);
+    }
+    Ok(Flow::Stay)
+}
+
+pub fn clear_breakpoints(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 1)?;
+    match args.first() {
+        None => {
+            m0.debugger_mut().clear();
+            println!("removed all breakpoints and watchpoints");
+        },
+        Some(token) => {
+            let id = token.parse::<u32>().map_err(|_| format!("`{}` isn't an ID", token))?;
+            if !m0.debugger_mut().remove(id) {
+                return Err(format!("there's no breakpoint or watchpoint {}", id));
+            }
+            println!("removed {}", id);
+        },
+    }
+    Ok(Flow::Stay)
+}
+
+pub fn step(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    let steps = match args.first() {
+        Some(token) => match token.parse::<u64>() {
+            Ok(steps) if steps > 0 => steps,
+            _ => return Err(format!("`{}` isn't a number of instructions", token)),
+        },
+        None => 1,
+    };
+    no_more_args(args, 1)?;
+    m0.set_run_mode(RunMode::Step(steps));
+    Ok(Flow::Resume)
+}
+
+pub fn next(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    m0.set_run_mode(RunMode::Next);
+    Ok(Flow::Resume)
+}
+
+pub fn finish(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    if m0.stack().is_empty() {
+        return Err("the stack is empty, there's nothing to return to".to_string());
+    }
+    m0.set_run_mode(RunMode::Finish);
+    Ok(Flow::Resume)
+}
+
+/**
+ * `label` lists the names, `label NAME [NNNN]` names NNNN (or pc)
+ */
+pub fn label(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 2)?;
+    let name = match args.first() {
+        Some(name) => *name,
+        None => {
+            for (name, addr) in m0.session.labels.iter() {
+                println!("{:#06X}  {}", addr, name);
+            }
+            return Ok(Flow::Stay);
+        },
+    };
+
+    let valid = name.starts_with(|c:char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("`{}` isn't a valid name (letters, digits and _)", name));
+    }
+    if parse_number(m0, name).is_ok() {
+        return Err(format!("`{}` already means a number or register", name));
+    }
+    let addr = match args.get(1) {
+        Some(token) => parse_addr(m0, token)?,
+        None => m0.pc(),
+    };
+    m0.session.labels.insert(name.to_string(), addr);
+    println!("{} = {:#06X}", name, addr);
+    Ok(Flow::Stay)
+}
+
+pub fn history(m0:&mut Machine, args:&[&str]) -> Result<Flow, String> {
+    no_more_args(args, 0)?;
+    for (n, line) in m0.session.history.iter().enumerate() {
+        println!("{:>4}  {}", n + 1, line);
+    }
+    Ok(Flow::Stay)
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use crate::constants::{TOM, NUM_REG};
+use crate::errors::Error;
+
+/**
+ * Mnemonic and number of operands of every instruction, indexed by opcode.
+ * The mnemonics match the opcode listing in `arch-spec`
+ */
+pub const OPCODES:[(&str, usize); 22] = [
+    ("halt", 0),
+    ("set", 2),
+    ("push", 1),
+    ("pop", 1),
+    ("eq", 3),
+    ("gt", 3),
+    ("jmp", 1),
+    ("jt", 2),
+    ("jf", 2),
+    ("add", 3),
+    ("mult", 3),
+    ("mod", 3),
+    ("and", 3),
+    ("or", 3),
+    ("not", 2),
+    ("rmem", 2),
+    ("wmem", 2),
+    ("call", 1),
+    ("ret", 0),
+    ("out", 1),
+    ("in", 1),
+    ("noop", 0),
+];
+
+/**
+ * A single instruction argument: either a literal 0..32767 or one of the eight registers
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Literal(u16),
+    Register(u8),
+}
+
+impl Operand {
+    /**
+     * Interprets a word from memory as an operand. Returns `None` for the invalid
+     * range 32776..65535
+     */
+    pub fn from_word(word:u16) -> Option<Operand> {
+        if word < TOM as u16 {
+            Some(Operand::Literal(word))
+        } else if word < (TOM + NUM_REG) as u16 {
+            Some(Operand::Register((word - TOM as u16) as u8))
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Returns the word this operand is encoded as in memory
+     */
+    pub fn to_word(self) -> u16 {
+        match self {
+            Operand::Literal(val) => val,
+            Operand::Register(reg) => TOM as u16 + reg as u16,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Literal(val) => write!(f, "{:#06X}", val),
+            Operand::Register(reg) => write!(f, "r{}", reg),
+        }
+    }
+}
+
+/**
+ * A decoded instruction. Operands are in the same order as in the opcode listing,
+ * e.g. `Add(a, b, c)` assigns into `a` the sum of `b` and `c`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Halt,
+    Set(Operand, Operand),
+    Push(Operand),
+    Pop(Operand),
+    Eq(Operand, Operand, Operand),
+    Gt(Operand, Operand, Operand),
+    Jmp(Operand),
+    Jt(Operand, Operand),
+    Jf(Operand, Operand),
+    Add(Operand, Operand, Operand),
+    Mult(Operand, Operand, Operand),
+    Mod(Operand, Operand, Operand),
+    And(Operand, Operand, Operand),
+    Or(Operand, Operand, Operand),
+    Not(Operand, Operand),
+    Rmem(Operand, Operand),
+    Wmem(Operand, Operand),
+    Call(Operand),
+    Ret,
+    Out(Operand),
+    In(Operand),
+    Noop,
+}
+
+impl Instruction {
+    /**
+     * Builds the instruction for `opcode` from `operands`. Returns `None` if the opcode is
+     * unknown or the number of operands is wrong for it
+     */
+    pub fn from_parts(opcode:u16, operands:&[Operand]) -> Option<Instruction> {
+        use Instruction::*;
+
+        let &(_, count) = OPCODES.get(opcode as usize)?;
+        if operands.len() != count {
+            return None;
+        }
+        let op = |n:usize| operands[n];
+
+        Some(match opcode {
+            0 => Halt,
+            1 => Set(op(0), op(1)),
+            2 => Push(op(0)),
+            3 => Pop(op(0)),
+            4 => Eq(op(0), op(1), op(2)),
+            5 => Gt(op(0), op(1), op(2)),
+            6 => Jmp(op(0)),
+            7 => Jt(op(0), op(1)),
+            8 => Jf(op(0), op(1)),
+            9 => Add(op(0), op(1), op(2)),
+            10 => Mult(op(0), op(1), op(2)),
+            11 => Mod(op(0), op(1), op(2)),
+            12 => And(op(0), op(1), op(2)),
+            13 => Or(op(0), op(1), op(2)),
+            14 => Not(op(0), op(1)),
+            15 => Rmem(op(0), op(1)),
+            16 => Wmem(op(0), op(1)),
+            17 => Call(op(0)),
+            18 => Ret,
+            19 => Out(op(0)),
+            20 => In(op(0)),
+            _ => Noop,
+        })
+    }
+
+    pub fn opcode(&self) -> u16 {
+        use Instruction::*;
+
+        match self {
+            Halt => 0,
+            Set(..) => 1,
+            Push(..) => 2,
+            Pop(..) => 3,
+            Eq(..) => 4,
+            Gt(..) => 5,
+            Jmp(..) => 6,
+            Jt(..) => 7,
+            Jf(..) => 8,
+            Add(..) => 9,
+            Mult(..) => 10,
+            Mod(..) => 11,
+            And(..) => 12,
+            Or(..) => 13,
+            Not(..) => 14,
+            Rmem(..) => 15,
+            Wmem(..) => 16,
+            Call(..) => 17,
+            Ret => 18,
+            Out(..) => 19,
+            In(..) => 20,
+            Noop => 21,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        OPCODES[self.opcode() as usize].0
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        use Instruction::*;
+
+        match *self {
+            Halt | Ret | Noop => vec![],
+            Push(a) | Pop(a) | Jmp(a) | Call(a) | Out(a) | In(a) => vec![a],
+            Set(a, b) | Jt(a, b) | Jf(a, b) | Not(a, b) | Rmem(a, b) | Wmem(a, b) => vec![a, b],
+            Eq(a, b, c) | Gt(a, b, c) | Add(a, b, c) | Mult(a, b, c)
+                | Mod(a, b, c) | And(a, b, c) | Or(a, b, c) => vec![a, b, c],
+        }
+    }
+
+    /**
+     * Number of words the instruction occupies in memory, opcode included
+     */
+    pub fn size(&self) -> u16 {
+        1 + OPCODES[self.opcode() as usize].1 as u16
+    }
+
+    /**
+     * Returns the words the instruction is stored as in memory
+     */
+    pub fn encode(&self) -> Vec<u16> {
+        let mut words = vec![self.opcode()];
+        words.extend(self.operands().iter().map(|op| op.to_word()));
+        words
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        for operand in self.operands() {
+            write!(f, "\t{}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Decodes the instruction at `addr` in `mem`.
+ * Returns the instruction and the number of words it occupies
+ */
+pub fn decode(mem:&[u16], addr:u16) -> Result<(Instruction, u16), Error> {
+    decode_with(|a| {
+        if a < TOM as u16 {
+            mem.get(a as usize).copied()
+        } else {
+            None
+        }
+    }, addr)
+}
+
+/**
+ * Decodes the instruction at `addr`, reading each word through `fetch`, which returns
+ * the word at an address or `None` if the address is out of range
+ *
+ * Fails with `UnknownOpcode` for an opcode outside of the listing, and with `MemoryInvalid`
+ * if the instruction runs off the end of memory or an operand is in the invalid range
+ */
+pub fn decode_with<F>(fetch:F, addr:u16) -> Result<(Instruction, u16), Error>
+    where F: Fn(u16) -> Option<u16> {
+    let opcode = fetch(addr).ok_or(Error::MemoryInvalid { pc: addr, opcode: 0, operand: addr })?;
+    let count = match OPCODES.get(opcode as usize) {
+        Some(&(_, count)) => count,
+        None => return Err(Error::UnknownOpcode { pc: addr, opcode }),
+    };
+
+    let mut operands = [Operand::Literal(0); 3];
+    for (n, operand) in operands.iter_mut().enumerate().take(count) {
+        let operand_addr = addr.wrapping_add(1 + n as u16);
+        let word = fetch(operand_addr).ok_or(Error::MemoryInvalid { pc: addr, opcode, operand: operand_addr })?;
+        *operand = Operand::from_word(word).ok_or(Error::MemoryInvalid { pc: addr, opcode, operand: word })?;
+    }
+
+    // the opcode and operand count were checked above, so this can't fail
+    let instruction = Instruction::from_parts(opcode, &operands[..count])
+        .ok_or(Error::UnknownOpcode { pc: addr, opcode })?;
+    Ok((instruction, 1 + count as u16))
+}
@@ -1,7 +1,27 @@
+pub mod analysis;
+pub mod assembler;
+mod blocks;
 mod hypervisor_controller;
 mod machine;
+pub mod console;
 pub mod constants;
-mod errors;
+pub mod debugger;
+pub mod disassembler;
+pub mod errors;
+pub mod fuzz;
+pub mod instruction;
+pub mod loader;
+pub mod reference;
+pub mod replay;
+pub mod script;
+pub mod snapshot;
+pub mod timeline;
+pub mod trace;
 pub mod utils;
 
-pub use machine::Machine;
+pub use machine::{Engine, Machine, StepOutcome};
+pub use errors::{Error, FileFormat, FormatError, FormatErrorKind, LoadError};
+pub use snapshot::Snapshot;
+
+#[cfg(test)]
+mod tests;
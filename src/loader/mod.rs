@@ -0,0 +1,159 @@
+use std::str::FromStr;
+use crate::constants::{TOM, NUM_REG};
+use crate::errors::LoadError;
+use crate::utils::words_from_bytes;
+
+/**
+ * The formats an image can be loaded from
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Binary,     // 16-bit little-endian words, like challenge.bin
+    Decimal,    // words separated by commas, like `9,32768,32769,4,19,32768` in arch-spec
+    IntelHex,   // byte image of a binary, in Intel HEX records
+}
+
+impl ImageFormat {
+    /**
+     * Guesses the format from the contents: Intel HEX starts with `:`, a decimal list only
+     * has digits, commas and whitespace, anything else is a binary
+     */
+    pub fn detect(bytes:&[u8]) -> ImageFormat {
+        let text = bytes.iter().skip_while(|b| b.is_ascii_whitespace());
+        match text.clone().next() {
+            Some(b':') => ImageFormat::IntelHex,
+            Some(_) if text.clone().all(|b| b.is_ascii_digit() || *b == b',' || b.is_ascii_whitespace()) => ImageFormat::Decimal,
+            _ => ImageFormat::Binary,
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(name:&str) -> Result<Self, Self::Err> {
+        match name {
+            "bin" | "binary" => Ok(ImageFormat::Binary),
+            "dec" | "decimal" => Ok(ImageFormat::Decimal),
+            "hex" | "ihex" => Ok(ImageFormat::IntelHex),
+            _ => Err(format!("unknown image format `{}` (expected bin, dec or hex)", name)),
+        }
+    }
+}
+
+/**
+ * How to load an image
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadOptions {
+    pub format:Option<ImageFormat>,     // detected from the contents if `None`
+    pub strict:bool,                    // reject words in the invalid range 32776..65535
+}
+
+/**
+ * Parses an image into words, to be loaded starting at address 0
+ */
+pub fn parse_image(bytes:&[u8], options:&LoadOptions) -> Result<Vec<u16>, LoadError> {
+    let words = match options.format.unwrap_or_else(|| ImageFormat::detect(bytes)) {
+        ImageFormat::Binary => parse_binary(bytes)?,
+        ImageFormat::Decimal => parse_decimal(bytes)?,
+        ImageFormat::IntelHex => parse_intel_hex(bytes)?,
+    };
+
+    if words.len() > TOM {
+        return Err(LoadError::TooLarge(words.len()));
+    }
+    if options.strict {
+        if let Some((addr, value)) = words.iter().enumerate().find(|(_, word)| **word as usize >= TOM + NUM_REG) {
+            return Err(LoadError::InvalidWord { addr: addr as u16, value: *value });
+        }
+    }
+    Ok(words)
+}
+
+pub fn parse_binary(bytes:&[u8]) -> Result<Vec<u16>, LoadError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(LoadError::OddLength(bytes.len()));
+    }
+    Ok(words_from_bytes(bytes))
+}
+
+/**
+ * Parses words 0..65535 separated by commas and/or whitespace
+ */
+pub fn parse_decimal(bytes:&[u8]) -> Result<Vec<u16>, LoadError> {
+    let text = std::str::from_utf8(bytes).map_err(|_| LoadError::Syntax { line: 1, message: "not a text file".to_string() })?;
+    let mut words:Vec<u16> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        for token in line.split(|c:char| c == ',' || c.is_whitespace()).filter(|token| !token.is_empty()) {
+            let word = token.parse::<u16>().map_err(|_| LoadError::Syntax {
+                line: n + 1,
+                message: format!("`{}` isn't a word (0..65535)", token),
+            })?;
+            words.push(word);
+        }
+    }
+    Ok(words)
+}
+
+/**
+ * Parses Intel HEX records (data, end of file, and extended segment/linear addresses) into
+ * the byte image they describe, then reads that like a binary. Gaps are filled with zeros
+ */
+pub fn parse_intel_hex(bytes:&[u8]) -> Result<Vec<u16>, LoadError> {
+    let text = std::str::from_utf8(bytes).map_err(|_| LoadError::Syntax { line: 1, message: "not a text file".to_string() })?;
+    let mut image:Vec<u8> = Vec::new();
+    let mut base:usize = 0;
+
+    for (n, line) in text.lines().enumerate() {
+        let line_number = n + 1;
+        let syntax = |message:&str| LoadError::Syntax { line: line_number, message: message.to_string() };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let digits = line.strip_prefix(':').ok_or_else(|| syntax("a record starts with `:`"))?;
+        if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+            return Err(syntax("a record is made of pairs of hex digits"));
+        }
+        let record = (0..digits.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| syntax("a record is made of pairs of hex digits"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(syntax("record length doesn't match its byte count"));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(syntax("bad checksum"));
+        }
+
+        let addr = ((record[1] as usize) << 8) | record[2] as usize;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => {
+                let start = base + addr;
+                let end = start + data.len();
+                if end > TOM * 2 {
+                    return Err(LoadError::TooLarge(end.div_ceil(2)));
+                }
+                if image.len() < end {
+                    image.resize(end, 0);
+                }
+                image[start..end].copy_from_slice(data);
+            },
+            0x01 => break,
+            0x02 | 0x04 => {
+                if data.len() != 2 {
+                    return Err(syntax(&format!("a type {:02X} record holds 2 bytes, not {}", record[3], data.len())));
+                }
+                let segment = ((data[0] as usize) << 8) | data[1] as usize;
+                base = if record[3] == 0x02 { segment << 4 } else { segment << 16 };
+            },
+            0x03 | 0x05 => {},  // start address, which doesn't mean anything here
+            _ => return Err(syntax("unsupported record type")),
+        }
+    }
+
+    parse_binary(&image)
+}
@@ -1,141 +1,389 @@
-use std::io;
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::constants::*;
-use std::ops::{Index, IndexMut};
-use crate::errors::Error;
-use std::io::{Read, Write};
+use crate::errors::{Error, FileFormat, FormatError, LoadError};
+use crate::snapshot::Snapshot;
+use crate::debugger::{Access, Debugger, RunMode, StopReason, StopWhen};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::loader::{self, LoadOptions};
+use std::io::Write;
+use crate::console::{InputSource, OutputSink, StdinInput, StdoutOutput};
 use crate::hypervisor_controller as hc;
 use crate::utils::*;
-use serde::{Serialize, Deserialize, Serializer, Deserializer};
-use crate::hypervisor_controller::disassemble_range;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use crate::instruction::{Instruction, Operand, decode_with};
+use crate::blocks::{BlockCache, Op};
+use crate::trace::{TraceRecord, Tracer};
+use crate::timeline::{Change, LastWrite, Step, Timeline};
+use crate::replay::{Recorder, Replay};
+use crate::script::{Script, ScriptLine};
 
 
 #[derive(Serialize, Deserialize)]
 pub struct Machine {
-    pub mem:Vec<u16>,
-    stack:Vec<u16>,
-    registers:[u16; NUM_REG],
-    pc:u16,
+    #[serde(serialize_with = "serialize_swapped", deserialize_with = "deserialize_swapped")]
+    pub(crate) mem:Vec<u16>,
+    pub(crate) stack:Vec<u16>,
+    #[serde(serialize_with = "serialize_swapped", deserialize_with = "deserialize_swapped_registers")]
+    pub(crate) registers:[u16; NUM_REG],
+    pub(crate) pc:u16,
     pub status:u16,
-    executed:u32,
-    pub recentMemAccess:Vec<(u16, u8)>,  // contains: (memory cell that was read or written to, type of access). To be consumed and pruned by a visualization
+    executed:u64,
+    #[serde(skip)]
+    instruction_pc:u16,     // address and opcode of the instruction being executed, for error reporting
+    #[serde(skip)]
+    instruction:u16,
+    #[serde(rename = "recentMemAccess")]
+    pub recent_mem_access:Vec<(u16, u8)>,  // contains: (memory cell that was read or written to, type of access). To be consumed and pruned by a visualization
     pub debug:bool,
+    #[serde(skip)]
+    pub entry_points:Vec<u16>,  // extra code addresses for the hypervisor's control-flow analysis, e.g. targets of `call r1`
+    #[serde(skip, default = "default_snapshot_dir")]
+    pub snapshot_dir:PathBuf,   // where the hypervisor keeps named snapshots
+    #[serde(skip, default = "default_input")]
+    input:Box<dyn InputSource>,
+    #[serde(skip, default = "default_output")]
+    output:Box<dyn OutputSink>,
+    #[serde(skip)]
+    tracer:Option<Tracer>,  // records executed instructions
+    #[serde(skip)]
+    timeline:Option<Timeline>,  // the undo log, for stepping backwards
+    #[serde(skip)]
+    recorder:Option<Recorder>,  // writes down the guest's input and output
+    #[serde(skip)]
+    replay:Option<Replay>,      // feeds the guest recorded input instead of `input`
+    #[serde(skip)]
+    script:Option<Script>,      // input lines and hypervisor commands, used before `input`
+    #[serde(skip)]
+    pending_input:VecDeque<u8>,     // fed to the guest before anything from `input`
+    #[serde(skip)]
+    recent_output:VecDeque<u8>,     // the last RECENT_OUTPUT_SIZE bytes written by the guest
+    #[serde(skip)]
+    pub(crate) debugger:Debugger,
+    #[serde(skip)]
+    pub(crate) session:hc::Session,  // the hypervisor's history and labels
+    #[serde(skip)]
+    mid_line:bool,      // the guest has read part of a line, so `.` isn't the hypervisor escape
+    #[serde(skip)]
+    interrupt:Arc<AtomicBool>,  // set from outside the step loop, e.g. by a signal handler
+    #[serde(skip)]
+    engine:Engine,
+    #[serde(skip)]
+    blocks:BlockCache,  // decoded code for `Engine::Blocks`
+}
+
+fn default_input() -> Box<dyn InputSource> {
+    Box::new(StdinInput)
+}
+
+fn default_output() -> Box<dyn OutputSink> {
+    Box::new(StdoutOutput)
+}
+
+fn default_snapshot_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_SNAPSHOT_DIR)
 }
 
 /*
-fn serialize_mem<S>(array: &[u16; TOM], serializer: S) -> Result<S::Ok, S::Error>
+ * Saved states hold memory and registers byte-swapped, the way the machine used to keep them,
+ * so older state files still load
+ */
+fn serialize_swapped<S>(words:&[u16], serializer:S) -> Result<S::Ok, S::Error>
     where S: Serializer {
-        array.serialize(serializer)
+    words.iter().map(|word| swap_endian(*word)).collect::<Vec<u16>>().serialize(serializer)
 }
 
-fn deserialize_mem<S>(array: &[u16; TOM], serializer: S) -> Result<S::Ok, S::Error>
-    where S: Serializer {
-    array.deserialize(serializer)
+fn deserialize_swapped<'de, D>(deserializer:D) -> Result<Vec<u16>, D::Error>
+    where D: Deserializer<'de> {
+    Ok(Vec::<u16>::deserialize(deserializer)?.into_iter().map(swap_endian).collect())
 }
-*/
 
-impl Index<u16> for Machine {
-    type Output = u16;
-    fn index(&self, addr: u16) -> &u16 {
-        if addr < (TOM as u16) {
-            &self.mem[addr as usize]
-        } else {
-            self.dump();
-            panic!(Error::MemoryInvalid);
-        }
+fn deserialize_swapped_registers<'de, D>(deserializer:D) -> Result<[u16; NUM_REG], D::Error>
+    where D: Deserializer<'de> {
+    let mut registers = [0; NUM_REG];
+    let words = deserialize_swapped(deserializer)?;
+    if words.len() != NUM_REG {
+        return Err(serde::de::Error::invalid_length(words.len(), &"8 registers"));
     }
+    registers.copy_from_slice(&words);
+    Ok(registers)
 }
 
-impl IndexMut<u16> for Machine {
-    fn index_mut(&mut self, addr: u16) -> &mut Self::Output {
-        if addr < (TOM as u16) {
-            &mut self.mem[addr as usize]
-        } else {
-            panic!(Error::MemoryInvalid);
-        }
-    }
+/**
+ * What happened as a result of asking the machine to execute
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction was executed and the machine can keep going
+    Running,
+    /// The machine is halted, either by `halt` or from the outside
+    Halted,
+    /// The guest executed `in` but there's no input available. `pc` is left on the `in`
+    /// instruction so execution can resume once more input is provided
+    WaitingForInput,
+    /// A breakpoint, watchpoint, `step`, `next` or `finish` stopped execution. For a
+    /// breakpoint the instruction at `pc` hasn't been executed yet
+    Stopped(StopReason),
+}
+
+/**
+ * How `Machine::run_fast` executes instructions. They behave the same, only the speed differs
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Decodes every instruction as it gets to it
+    #[default]
+    Interpreter,
+    /// Decodes straight runs of code once, up to the next jump, and keeps them until
+    /// something writes over them
+    Blocks,
 }
 
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
 
 impl Machine {
+    /**
+     * Creates a machine with zeroed memory whose guest I/O goes to STDIN/STDOUT
+     */
     pub fn new() -> Self {
+        Machine::with_io(default_input(), default_output())
+    }
+
+    /**
+     * Creates a machine with zeroed memory whose `in` and `out` instructions use
+     * `input` and `output` instead of the terminal
+     */
+    pub fn with_io(input:Box<dyn InputSource>, output:Box<dyn OutputSink>) -> Self {
         Machine {
             stack: Vec::new(),
             registers: [0; NUM_REG],
             pc: 0,
-            mem: Vec::with_capacity(TOM),
+            mem: vec![0; TOM],
             status: 0,
             executed: 0,
-            recentMemAccess: Vec::new(),
+            instruction_pc: 0,
+            instruction: 0,
+            recent_mem_access: Vec::new(),
             debug: false,
+            entry_points: Vec::new(),
+            snapshot_dir: default_snapshot_dir(),
+            input,
+            output,
+            tracer: None,
+            timeline: None,
+            recorder: None,
+            replay: None,
+            script: None,
+            pending_input: VecDeque::new(),
+            recent_output: VecDeque::new(),
+            debugger: Debugger::default(),
+            session: hc::Session::default(),
+            mid_line: false,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            engine: Engine::default(),
+            blocks: BlockCache::default(),
+        }
+    }
+
+    /**
+     * Creates a machine with an image from `path` loaded, whose guest I/O goes to STDIN/STDOUT.
+     * The format of the image is detected from its contents
+     */
+    pub fn from_file<P:AsRef<Path>>(path:P) -> Result<Self, LoadError> {
+        Machine::from_file_with(path, &LoadOptions::default())
+    }
+
+    pub fn from_file_with<P:AsRef<Path>>(path:P, options:&LoadOptions) -> Result<Self, LoadError> {
+        let mut m0 = Machine::new();
+        m0.load_image_with(&fs::read(path)?, options)?;
+        Ok(m0)
+    }
+
+    /**
+     * Resets the machine and loads an image into memory starting at address 0. The format
+     * of the image is detected from its contents
+     */
+    pub fn load_image(&mut self, bytes:&[u8]) -> Result<(), LoadError> {
+        self.load_image_with(bytes, &LoadOptions::default())
+    }
+
+    /**
+     * Like `load_image`, with the format and strictness given in `options`. The machine is
+     * left untouched if the image can't be loaded
+     */
+    pub fn load_image_with(&mut self, bytes:&[u8], options:&LoadOptions) -> Result<(), LoadError> {
+        let words = loader::parse_image(bytes, options)?;
+
+        self.mem = vec![0; TOM];
+        for (addr, word) in words.iter().enumerate() {
+            self.mem[addr] = *word;
+        }
+        self.blocks.clear();
+        self.stack.clear();
+        self.registers = [0; NUM_REG];
+        self.pc = 0;
+        self.status = 0;
+        self.executed = 0;
+        self.recent_mem_access.clear();
+        self.recent_output.clear();
+        Ok(())
+    }
+
+    /**
+     * Replaces the source of guest input, e.g. to feed a script after loading an image
+     */
+    pub fn set_input(&mut self, input:Box<dyn InputSource>) {
+        self.input = input;
+    }
+
+    /**
+     * Replaces the destination of guest output
+     */
+    pub fn set_output(&mut self, output:Box<dyn OutputSink>) {
+        self.output = output;
+    }
+
+    /**
+     * Returns the word at `addr`
+     */
+    pub fn read_word(&self, addr:u16) -> Result<u16, Error> {
+        match self.mem.get(addr as usize) {
+            Some(val) if addr < TOM as u16 => Ok(*val),
+            _ => Err(Error::AddressOutOfRange(addr)),
         }
     }
 
     /**
-     * Fetches the value at mem[pc], converts it to big-endian, increments the pc
-     * and returns the value.
+     * Sets the word at `addr` to `value`
+     */
+    pub fn write_word(&mut self, addr:u16, value:u16) -> Result<(), Error> {
+        match self.mem.get_mut(addr as usize) {
+            Some(cell) if addr < TOM as u16 => {
+                *cell = value;
+                self.blocks.invalidate(addr);
+                Ok(())
+            },
+            _ => Err(Error::AddressOutOfRange(addr)),
+        }
+    }
+
+    /**
+     * Returns register `reg` (0-7), or `None` if there's no such register
+     */
+    pub fn register(&self, reg:usize) -> Option<u16> {
+        self.registers.get(reg).copied()
+    }
+
+    /**
+     * Sets register `reg` (0-7) to `value`. Fails if there's no such register
+     */
+    pub fn set_register(&mut self, reg:usize, value:u16) -> Result<(), Error> {
+        match self.registers.get_mut(reg) {
+            Some(cell) => {
+                *cell = value;
+                Ok(())
+            },
+            None => Err(Error::AddressOutOfRange((TOM + reg) as u16)),
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc:u16) {
+        self.pc = pc;
+    }
+
+    /**
+     * The stack, bottom first
+     */
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    /**
+     * Builds a `MemoryInvalid` error for the instruction currently being executed
+     */
+    fn memory_invalid(&self, operand:u16) -> Error {
+        Error::MemoryInvalid { pc: self.instruction_pc, opcode: self.instruction, operand }
+    }
+
+    /**
+     * Decodes the instruction at mem[pc] and advances the pc past it.
      *
      * Sets and clears the MEMR flag in the status register
      */
-    fn peek_inc(&mut self) -> u16 {
+    fn fetch(&mut self) -> Result<Instruction, Error> {
         set_bit(&mut self.status, MEMR_BIT);
-        //println!("pc: {:#X}", self.pc);
-        let val:u16 = self.mem[self.pc as usize];
-
-        self.pc += 1;
+        let mem = &self.mem;
+        let (instruction, size) = decode_with(|addr| mem.get(addr as usize).copied(), self.pc)?;
 
-        if self.recentMemAccess.len() < MAX_RECENTMEMACCESS_SIZE as usize {
-            self.recentMemAccess.push((self.pc, RECENTMEMACCESS_READ_BIT));
+        for addr in self.pc..self.pc + size {
+            if self.recent_mem_access.len() < MAX_RECENTMEMACCESS_SIZE as usize {
+                self.recent_mem_access.push((addr, RECENTMEMACCESS_READ_BIT));
+            }
         }
-        return swap_endian(val);
+        self.pc += size;
+        Ok(instruction)
     }
 
     /**
-     * Gets the memory or register at `destination` (see below), converts it to big-endian, and returns it.
-     * If `dest_addr` (big-endian) is `<` `TOM`, then `destination` = `mem[dest_addr]`
+     * Gets the memory or register at `destination` (see below) and returns it.
+     * If `dest_addr` is `<` `TOM`, then `destination` = `mem[dest_addr]`
      * otherwise `dest_addr` refers to a register 0...7: `TOM, TOM+1, ... TOM+7 = registers[0...7]`
      *
      * Sets and clears the `MEMR` flag in the status register
      */
-    fn peek(&mut self, dest_addr:u16) -> u16 {
+    pub(crate) fn peek(&mut self, dest_addr:u16) -> Result<u16, Error> {
         set_bit(&mut self.status, MEMR_BIT);
-        let mut val:u16 = 0;
-        if dest_addr < TOM as u16 {
-            val = self.mem[dest_addr as usize];
+        let val:u16 = if dest_addr < TOM as u16 {
+            self.mem[dest_addr as usize]
         } else if dest_addr < (TOM+NUM_REG) as u16 {
-            val = self.registers[(dest_addr % (TOM as u16)) as usize];
+            self.registers[(dest_addr % (TOM as u16)) as usize]
         } else {
-            panic!(Error::MemoryInvalid);
-        }
+            return Err(self.memory_invalid(dest_addr));
+        };
 
-        if self.recentMemAccess.len() < MAX_RECENTMEMACCESS_SIZE as usize {
-            self.recentMemAccess.push((dest_addr, RECENTMEMACCESS_READ_BIT));
+        if self.recent_mem_access.len() < MAX_RECENTMEMACCESS_SIZE as usize {
+            self.recent_mem_access.push((dest_addr, RECENTMEMACCESS_READ_BIT));
         }
+        self.debugger.access(dest_addr, Access::Read, val, self.instruction_pc);
 
-        return swap_endian(val);
+        Ok(val)
     }
 
     /**
      * Sets the memory or register at `destination` to `value`
-     * `value` should be provided big-endian, and it will be converted to
-     * little-endian
      * If `dest_addr` is `<` `TOM`, then `destination` = `mem[dest_addr]`
      * otherwise `dest_addr` refers to a register 0...7: `TOM, TOM+1, ... TOM+7 = registers[0...7]`
      *
      * Sets and clears the `MEMW` flag in the status register
      */
-    fn poke(&mut self, dest_addr:u16, value:u16) {
+    fn poke(&mut self, dest_addr:u16, value:u16) -> Result<(), Error> {
         set_bit(&mut self.status, MEMW_BIT);
-        if dest_addr < TOM as u16 {
-            self.mem[dest_addr as usize] = swap_endian(value);
+        let old = if dest_addr < TOM as u16 {
+            self.blocks.invalidate(dest_addr);
+            std::mem::replace(&mut self.mem[dest_addr as usize], value)
         } else if dest_addr <= (TOM+7) as u16 {
-            self.registers[(dest_addr % (TOM as u16)) as usize] = swap_endian(value);
+            std::mem::replace(&mut self.registers[(dest_addr % (TOM as u16)) as usize], value)
         } else {
-            panic!(Error::MemoryInvalid);
-        }
+            return Err(self.memory_invalid(dest_addr));
+        };
+        self.record(Change::Write { addr: dest_addr, old });
 
-        if self.recentMemAccess.len() < MAX_RECENTMEMACCESS_SIZE as usize {
-            self.recentMemAccess.push((dest_addr, RECENTMEMACCESS_WRITE_BIT));
+        if self.recent_mem_access.len() < MAX_RECENTMEMACCESS_SIZE as usize {
+            self.recent_mem_access.push((dest_addr, RECENTMEMACCESS_WRITE_BIT));
         }
+        self.debugger.access(dest_addr, Access::Write, value, self.instruction_pc);
+        Ok(())
     }
 
     fn reset_status(&mut self) {
@@ -148,26 +396,707 @@ impl Machine {
     }
 
     /**
-     * Performs the M1 operation to fetch the opcode from mem[pc], swaps its endian-ness
-     * (from LE to BE) and then executes. The fetch increments `pc`
+     * Adds `change` to the undo log, if there is one
      */
-    pub fn fetch_and_execute(&mut self) {
-        if !self.is_halted() {
-            self.reset_status();
-            set_bit(&mut self.status, M1_BIT);
-            let instruction:u16 = self.peek_inc();
-            //clear_bit(&mut self.status, M1_BIT);
+    fn record(&mut self, change:Change) {
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.change(change);
+        }
+    }
 
-            self.execute(instruction);
+    /**
+     * Writes every instruction executed from now on to `trace`, in the same format as the
+     * debug output. `None` turns tracing off
+     */
+    pub fn set_trace(&mut self, trace:Option<Box<dyn Write>>) {
+        self.tracer = trace.map(Tracer::text);
+    }
+
+    /**
+     * Records the instructions executed from now on with `tracer`, replacing (and flushing)
+     * the one there was. `None` turns tracing off
+     */
+    pub fn set_tracer(&mut self, tracer:Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /**
+     * Number of instructions executed so far
+     */
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /**
+     * Queues `bytes` to be read by the guest before anything from the input source
+     */
+    pub fn queue_input(&mut self, bytes:&[u8]) {
+        self.pending_input.extend(bytes);
+    }
+
+    /**
+     * The last SNAPSHOT_OUTPUT_LINES lines written by the guest
+     */
+    pub fn recent_output(&self) -> Vec<u8> {
+        let output:Vec<u8> = self.recent_output.iter().copied().collect();
+        let body = output.strip_suffix(b"\n").unwrap_or(&output);
+        let start = body.iter().enumerate().rev()
+            .filter(|(_, byte)| **byte == b'\n')
+            .nth(SNAPSHOT_OUTPUT_LINES - 1)
+            .map_or(0, |(i, _)| i + 1);
+        output[start..].to_vec()
+    }
+
+    /**
+     * Captures the whole CPU: memory, registers, stack, pc, status, instruction count and
+     * queued input, along with the time and the last few lines of output
+     */
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            status: self.status,
+            registers: self.registers,
+            stack: self.stack.clone(),
+            mem: self.mem.clone(),
+            executed: self.executed,
+            pending_input: self.pending_input.iter().copied().collect(),
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+            recent_output: self.recent_output(),
+        }
+    }
+
+    /**
+     * Puts the CPU back the way it was when `snapshot` was taken. I/O and tracing are kept
+     * as they are
+     */
+    pub fn restore(&mut self, snapshot:&Snapshot) {
+        self.restore_state(snapshot);
+        self.mid_line = false;  // whatever was half read belongs to the old session
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.clear();   // none of it leads here any more
+        }
+    }
+
+    fn restore_state(&mut self, snapshot:&Snapshot) {
+        self.mem = snapshot.mem.clone();
+        self.mem.resize(TOM, 0);
+        self.blocks.clear();
+        self.stack = snapshot.stack.clone();
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.status = snapshot.status;
+        self.executed = snapshot.executed;
+        self.pending_input = snapshot.pending_input.iter().copied().collect();
+        self.recent_output = snapshot.recent_output.iter().copied().collect();
+        self.recent_mem_access.clear();
+    }
+
+    /**
+     * Saves a compressed snapshot of the machine to `path`
+     */
+    pub fn save_state<P:AsRef<Path>>(&self, path:P) -> io::Result<()> {
+        fs::write(path, self.snapshot().to_bytes(true))
+    }
+
+    /**
+     * Restores the machine from a snapshot saved in `path` by `save_state`, or from a JSON
+     * state file written by older versions
+     */
+    pub fn load_state<P:AsRef<Path>>(&mut self, path:P) -> Result<(), FormatError> {
+        let snapshot = Snapshot::from_bytes(&fs::read(path).map_err(|e| FileFormat::Snapshot.io(e))?)?;
+        self.restore(&snapshot);
+        Ok(())
+    }
+
+    /**
+     * Performs the M1 operation to fetch and decode the instruction at mem[pc]
+     * and then executes it. The fetch moves `pc` past the instruction
+     *
+     * A fault leaves the machine as it was at the moment of the fault, with `pc` either on
+     * or just past the faulting instruction; the returned error says which instruction it was
+     *
+     * Interrupts and breakpoints are checked before the fetch, watchpoints and stepping after
+     * the instruction has executed. Any of them returns `StepOutcome::Stopped`
+     */
+    pub fn fetch_and_execute(&mut self) -> Result<StepOutcome, Error> {
+        if self.is_halted() {
+            return Ok(StepOutcome::Halted);
+        }
+        if self.interrupt.swap(false, Ordering::Relaxed) {
+            // nothing has run at pc yet, so a breakpoint there still gets its turn
+            self.debugger.stop_when = StopWhen::Never;
+            return Ok(StepOutcome::Stopped(StopReason::Interrupted));
+        }
+        if let Some(reason) = self.check_breakpoints() {
+            return Ok(self.stop(reason));
+        }
+        self.debugger.take_hit();   // left over from an instruction that faulted
+        if self.script.is_some() {
+            self.feed_script();
+        }
+        if self.timeline.is_some() {
+            self.begin_step();
+        }
+
+        self.reset_status();
+        set_bit(&mut self.status, M1_BIT);
+        self.instruction_pc = self.pc;
+        self.instruction = 0;
+        let instruction:Instruction = match self.fetch() {
+            Ok(instruction) => instruction,
+            Err(e) => {
+                if let Some(timeline) = self.timeline.as_mut() {
+                    timeline.discard();
+                }
+                return Err(e);
+            },
+        };
+        //clear_bit(&mut self.status, M1_BIT);
+        self.instruction = instruction.opcode();
+
+        let traced = match &self.tracer {
+            Some(tracer) if tracer.wants(self.executed, self.instruction_pc, self.instruction) => Some(self.operand_values(&instruction)),
+            _ => None,
+        };
+        let outcome = self.execute(instruction);
+        if let Some(timeline) = self.timeline.as_mut() {
+            match outcome {
+                Ok(StepOutcome::WaitingForInput) | Ok(StepOutcome::Stopped(_)) => timeline.discard(),
+                _ => timeline.commit(),     // a fault can leave changes behind too
+            }
+        }
+        let outcome = outcome?;
+        if matches!(outcome, StepOutcome::Running | StepOutcome::Halted) {
+            if let Some(values) = traced {
+                self.trace(instruction, values);
+            }
+            self.executed += 1;
+        }
+        if outcome == StepOutcome::Running {
+            if let Some(reason) = self.check_stop() {
+                return Ok(self.stop(reason));
+            }
+        }
+        Ok(outcome)
+    }
+
+    /**
+     * What each operand of `instruction` is worth right now, without counting as a read
+     */
+    fn operand_values(&self, instruction:&Instruction) -> [u16; 3] {
+        let mut values = [0u16; 3];
+        for (value, operand) in values.iter_mut().zip(instruction.operands()) {
+            *value = match operand {
+                Operand::Literal(val) => val,
+                Operand::Register(reg) => self.registers[reg as usize],
+            };
+        }
+        values
+    }
+
+    /**
+     * Hands the tracer the instruction that just executed, with what it wrote
+     */
+    fn trace(&mut self, instruction:Instruction, values:[u16; 3]) {
+        use Instruction::*;
+
+        let write = match instruction {
+            Set(a, _) | Pop(a) | Eq(a, _, _) | Gt(a, _, _) | Add(a, _, _) | Mult(a, _, _) | Mod(a, _, _)
+            | And(a, _, _) | Or(a, _, _) | Not(a, _) | Rmem(a, _) | In(a) => {
+                let addr = a.to_word();
+                let value = match a {
+                    Operand::Literal(_) => self.mem.get(addr as usize).copied(),
+                    Operand::Register(reg) => self.registers.get(reg as usize).copied(),
+                };
+                value.map(|value| (addr, value))
+            },
+            Wmem(_, _) => Some((values[0], values[1])),
+            _ => None,
+        };
+        let record = TraceRecord { executed: self.executed, pc: self.instruction_pc, instruction, values, write };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(record);
         }
     }
 
     /**
-     * Starts CPU execution at `pc` and continues until `HLT` is set in the status register
+     * The first breakpoint at `pc` whose condition holds, unless execution is resuming from `pc`
+     */
+    fn check_breakpoints(&mut self) -> Option<StopReason> {
+        if self.debugger.resume_from.take() == Some(self.pc) {
+            return None;
+        }
+        self.debugger.breakpoints_at(self.pc).into_iter()
+            .find(|(_, breakpoint)| breakpoint.condition.is_none_or(|condition| condition.evaluate(self)))
+            .map(|(id, breakpoint)| StopReason::Breakpoint { id, addr: breakpoint.addr })
+    }
+
+    /**
+     * Whether the instruction that just executed hit a watchpoint, diverged from a replay
+     * or finished a step
+     */
+    fn check_stop(&mut self) -> Option<StopReason> {
+        if let Some(hit) = self.debugger.take_hit() {
+            return Some(hit);
+        }
+        if let Some(divergence) = self.replay.as_mut().and_then(|replay| replay.take_divergence()) {
+            return Some(StopReason::Diverged(divergence));
+        }
+        match self.debugger.stop_when {
+            StopWhen::Never => None,
+            StopWhen::Steps(steps) if steps <= 1 => Some(StopReason::Stepped),
+            StopWhen::Steps(steps) => {
+                self.debugger.stop_when = StopWhen::Steps(steps - 1);
+                None
+            },
+            StopWhen::ReachedWith { addr, depth } if self.pc == addr && self.stack.len() == depth => Some(StopReason::Stepped),
+            StopWhen::Returned { depth } if self.instruction == Instruction::Ret.opcode() && self.stack.len() < depth => {
+                Some(StopReason::Finished { addr: self.pc })
+            },
+            _ => None,
+        }
+    }
+
+    fn stop(&mut self, reason:StopReason) -> StepOutcome {
+        self.debugger.stop_when = StopWhen::Never;
+        self.debugger.resume_from = Some(self.pc);
+        StepOutcome::Stopped(reason)
+    }
+
+    /**
+     * Records an undo log from now on, so the machine can go backwards. `None` stops
+     * recording and forgets the log
+     */
+    pub fn set_timeline(&mut self, timeline:Option<Timeline>) {
+        self.timeline = timeline;
+    }
+
+    pub fn timeline(&self) -> Option<&Timeline> {
+        self.timeline.as_ref()
+    }
+
+    /**
+     * Writes every byte the guest reads and prints from now on to `recorder`, replacing
+     * (and flushing) the one there was. `None` stops recording
      */
-    pub fn run(&mut self) {
-        while !self.is_halted() {
-            self.fetch_and_execute();
+    pub fn set_recorder(&mut self, recorder:Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    /**
+     * Feeds the guest the input from `replay` instead of the input source, and stops it
+     * with `StopReason::Diverged` the first time it reads or prints something differently
+     */
+    pub fn set_replay(&mut self, replay:Option<Replay>) {
+        self.replay = replay;
+    }
+
+    pub fn replay(&self) -> Option<&Replay> {
+        self.replay.as_ref()
+    }
+
+    /**
+     * Feeds the guest the input lines of `script` before anything from the input source,
+     * running its hypervisor commands as it gets to them
+     */
+    pub fn set_script(&mut self, script:Option<Script>) {
+        self.script = script;
+    }
+
+    pub fn script(&self) -> Option<&Script> {
+        self.script.as_ref()
+    }
+
+    /**
+     * When the guest is about to read a new line and nothing is queued, runs the script's
+     * hypervisor commands up to its next input line and queues that line
+     */
+    fn feed_script(&mut self) {
+        while self.mem.get(self.pc as usize) == Some(&Instruction::In(Operand::Literal(0)).opcode())
+            && self.pending_input.is_empty() && !self.mid_line {
+            match self.script.as_mut().and_then(|script| script.next_line()) {
+                Some(ScriptLine::Directive { line, command }) => hc::run_directive(self, line, &command),
+                Some(ScriptLine::Input(text)) => {
+                    self.queue_input(text.as_bytes());
+                    self.queue_input(b"\n");
+                },
+                None => {
+                    self.script = None;
+                    break;
+                },
+            }
+        }
+    }
+
+    /**
+     * Starts the undo log entry for the instruction about to be fetched, and takes a
+     * checkpoint if one is due
+     */
+    fn begin_step(&mut self) {
+        let checkpoint = match &self.timeline {
+            Some(timeline) if timeline.wants_checkpoint(self.executed) => Some(self.snapshot()),
+            _ => None,
+        };
+        if let Some(timeline) = self.timeline.as_mut() {
+            if let Some(snapshot) = checkpoint {
+                timeline.checkpoint(snapshot);
+            }
+            timeline.begin(self.executed, self.pc, self.status, self.mid_line);
+        }
+    }
+
+    /**
+     * Puts back everything `step` changed, leaving the machine about to execute it again.
+     * Bytes it read are queued to be read again; what it printed stays printed
+     */
+    fn undo(&mut self, step:Step) {
+        for change in step.changes.iter().rev() {
+            match *change {
+                Change::Write { addr, old } if addr < TOM as u16 => {
+                    self.mem[addr as usize] = old;
+                    self.blocks.invalidate(addr);
+                },
+                Change::Write { addr, old } => self.registers[addr as usize - TOM] = old,
+                Change::Pushed => {
+                    self.stack.pop();
+                },
+                Change::Popped(value) => self.stack.push(value),
+                Change::Input(byte) => self.pending_input.push_front(byte),
+            }
+        }
+        self.pc = step.pc;
+        self.status = step.status;
+        self.mid_line = step.mid_line;
+        self.executed = step.executed;
+    }
+
+    /**
+     * Undoes the last `n` instructions, or as many as were recorded. Returns how many that
+     * was. A checkpoint saves undoing the instructions after it one by one
+     */
+    pub fn step_back(&mut self, n:usize) -> usize {
+        let mut undone = 0;
+        let checkpoint = self.timeline.as_ref()
+            .and_then(|timeline| timeline.checkpoint_within(n))
+            .filter(|(_, skipped)| *skipped > 1 && *skipped <= n)
+            .map(|(snapshot, skipped)| (snapshot.clone(), skipped));
+        if let Some((snapshot, skipped)) = checkpoint {
+            let mut input:VecDeque<u8> = VecDeque::new();
+            let mut mid_line = self.mid_line;
+            for _ in 0..skipped {
+                if let Some(step) = self.timeline.as_mut().and_then(|timeline| timeline.pop()) {
+                    for change in step.changes.iter().rev() {
+                        if let Change::Input(byte) = change {
+                            input.push_front(*byte);
+                        }
+                    }
+                    mid_line = step.mid_line;
+                }
+            }
+            input.extend(self.pending_input.drain(..));
+            self.restore_state(&snapshot);
+            self.pending_input = input;
+            self.mid_line = mid_line;
+            undone = skipped;
+        }
+        while undone < n {
+            match self.timeline.as_mut().and_then(|timeline| timeline.pop()) {
+                Some(step) => self.undo(step),
+                None => break,
+            }
+            undone += 1;
+        }
+        if undone > 0 {
+            self.stop(StopReason::Stepped);     // resuming shouldn't stop on a breakpoint right here
+        }
+        undone
+    }
+
+    /**
+     * Runs backwards until just before a write a watchpoint is watching, or onto a
+     * breakpoint. Returns `None` if the start of the undo log comes first
+     */
+    pub fn reverse_continue(&mut self) -> Option<StopReason> {
+        self.debugger.take_hit();
+        self.debugger.resume_from = None;
+        loop {
+            let step = self.timeline.as_mut()?.pop()?;
+            for change in step.changes.iter() {
+                if let Change::Write { addr, .. } = *change {
+                    let value = self.current_value(addr);
+                    self.debugger.access(addr, Access::Write, value, step.pc);
+                }
+            }
+            self.undo(step);
+            if let Some(reason) = self.debugger.take_hit().or_else(|| self.check_breakpoints()) {
+                self.stop(reason);
+                return Some(reason);
+            }
+        }
+    }
+
+    /**
+     * The latest recorded instruction that wrote `addr` (TOM..TOM+7 for a register)
+     */
+    pub fn last_write(&self, addr:u16) -> Option<LastWrite> {
+        self.timeline.as_ref()?.last_write(addr, self.current_value(addr))
+    }
+
+    fn current_value(&self, addr:u16) -> u16 {
+        match addr as usize {
+            addr if addr < TOM => self.mem[addr],
+            addr => self.registers.get(addr - TOM).copied().unwrap_or(0),
+        }
+    }
+
+    /**
+     * Asks the machine to stop with `StopReason::Interrupted` before its next instruction
+     */
+    pub fn interrupt(&self) {
+        self.interrupt.store(true, Ordering::Relaxed);
+    }
+
+    /**
+     * The flag behind `interrupt()`, for setting it from another thread or a signal handler
+     */
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /**
+     * For adding and removing breakpoints and watchpoints
+     */
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /**
+     * Sets how far execution goes from here before stopping, breakpoints and watchpoints
+     * aside. `Next` over anything but a `call` is the same as `Step(1)`, and `Finish`
+     * with nothing on the stack never stops
+     */
+    pub fn set_run_mode(&mut self, mode:RunMode) {
+        let mem = &self.mem;
+        self.debugger.stop_when = match mode {
+            RunMode::Continue => StopWhen::Never,
+            RunMode::Step(steps) => StopWhen::Steps(steps.max(1)),
+            RunMode::Next => match decode_with(|addr| mem.get(addr as usize).copied(), self.pc) {
+                Ok((Instruction::Call(_), size)) => StopWhen::ReachedWith { addr: self.pc + size, depth: self.stack.len() },
+                _ => StopWhen::Steps(1),
+            },
+            RunMode::Finish => StopWhen::Returned { depth: self.stack.len() },
+        };
+    }
+
+    /**
+     * Starts CPU execution at `pc` and continues until `HLT` is set in the status register,
+     * the guest runs out of input, the debugger stops it, or a fault occurs
+     */
+    pub fn run(&mut self) -> Result<StepOutcome, Error> {
+        loop {
+            match self.fetch_and_execute()? {
+                StepOutcome::Running => {},
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+
+    /**
+     * Runs up to `max_steps` instructions as fast as it can, for long stretches of guest code
+     * like the teleporter check: no status bits, access tracking or per-instruction flushing,
+     * using the engine picked with `set_engine`. `in` and `out` still go through
+     * `fetch_and_execute`.
+     *
+     * If anything is watching the machine (`debug`, breakpoints, watchpoints, stepping, a
+     * tracer, the undo log, a recording, replay or script) every instruction goes through
+     * `fetch_and_execute` instead, so nothing is missed. Returns `Running` if `max_steps` ran
+     * out, otherwise the same as `run`
+     */
+    pub fn run_fast(&mut self, max_steps:u64) -> Result<StepOutcome, Error> {
+        let observed = self.debug || !self.debugger.is_idle() || self.tracer.is_some() || self.timeline.is_some()
+            || self.recorder.is_some() || self.replay.is_some() || self.script.is_some();
+        let mut steps:u64 = 0;
+        while steps < max_steps {
+            if !observed {
+                if self.is_halted() {
+                    return Ok(StepOutcome::Halted);
+                }
+                if !self.interrupt.load(Ordering::Relaxed) {  // otherwise `fetch_and_execute` stops for it
+                    let done = match self.engine {
+                        Engine::Interpreter => self.run_op()?,
+                        Engine::Blocks => self.run_block(max_steps - steps)?,
+                    };
+                    if done > 0 {
+                        self.debugger.resume_from = None;   // it's been run past, like `check_breakpoints` would
+                        steps += done;
+                        continue;
+                    }
+                }
+            }
+            match self.fetch_and_execute()? {
+                StepOutcome::Running => steps += 1,
+                outcome => return Ok(outcome),
+            }
+        }
+        if self.is_halted() {
+            Ok(StepOutcome::Halted)
+        } else {
+            Ok(StepOutcome::Running)
+        }
+    }
+
+    /**
+     * Picks how `run_fast` executes instructions
+     */
+    pub fn set_engine(&mut self, engine:Engine) {
+        self.engine = engine;
+        if engine != Engine::Blocks {
+            self.blocks.clear();
+        }
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /**
+     * Decodes and executes the instruction at `pc` for `Engine::Interpreter`. Returns how
+     * many instructions ran: 0 if this one has to go through `fetch_and_execute`
+     */
+    fn run_op(&mut self) -> Result<u64, Error> {
+        match Op::decode(&self.mem, self.pc) {
+            Ok(op) if !op.is_io() => {
+                self.execute_op(&op)?;
+                Ok(1)
+            },
+            _ => Ok(0),     // I/O, or a fault that `fetch_and_execute` reports
+        }
+    }
+
+    /**
+     * Executes the block at `pc` for `Engine::Blocks`, or as much of it as `max_steps` allows.
+     * A write to the block itself ends it early, since what follows might not be what was
+     * decoded. Returns how many instructions ran
+     */
+    fn run_block(&mut self, max_steps:u64) -> Result<u64, Error> {
+        let block = match self.blocks.get(&self.mem, self.pc) {
+            Some(block) => block,
+            None => return Ok(0),
+        };
+        let generation = self.blocks.generation();
+        let mut done:u64 = 0;
+        for op in block.ops.iter() {
+            if done == max_steps {
+                break;
+            }
+            self.execute_op(op)?;
+            done += 1;
+            if self.blocks.generation() != generation {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /**
+     * Executes a decoded instruction for `run_fast`
+     */
+    fn execute_op(&mut self, op:&Op) -> Result<(), Error> {
+        let Op { pc, opcode, args: [a, b, c], next } = *op;
+        self.pc = next;
+
+        match opcode {
+            0 => self.halt(),
+            1 => self.fast_store(a, self.fast_value(b)),
+            2 => self.stack.push(self.fast_value(a)),
+            3 => match self.stack.pop() {
+                Some(value) => self.fast_store(a, value),
+                None => return Err(Error::EmptyStack { pc, opcode }),
+            },
+            4 => self.fast_store(a, (self.fast_value(b) == self.fast_value(c)) as u16),
+            5 => self.fast_store(a, (self.fast_value(b) > self.fast_value(c)) as u16),
+            6 => self.pc = self.fast_value(a),
+            7 if self.fast_value(a) != 0 => self.pc = self.fast_value(b),
+            8 if self.fast_value(a) == 0 => self.pc = self.fast_value(b),
+            9 => self.fast_store(a, self.fast_value(b).wrapping_add(self.fast_value(c)) % TOM as u16),
+            10 => self.fast_store(a, self.fast_value(b).wrapping_mul(self.fast_value(c)) % TOM as u16),
+            11 => match self.fast_value(c) {
+                0 => return Err(Error::DivideByZero { pc, opcode }),
+                divisor => self.fast_store(a, self.fast_value(b) % divisor % TOM as u16),
+            },
+            12 => self.fast_store(a, (self.fast_value(b) & self.fast_value(c)) % TOM as u16),
+            13 => self.fast_store(a, (self.fast_value(b) | self.fast_value(c)) % TOM as u16),
+            14 => self.fast_store(a, !self.fast_value(b) % TOM as u16),
+            15 => {
+                // an address that isn't a literal can only come from a register
+                let source = self.fast_value(b);
+                match self.fast_load(source) {
+                    Some(value) => self.fast_store(a, value),
+                    None => return Err(Error::MemoryInvalid { pc, opcode, operand: source }),
+                }
+            },
+            16 => {
+                let dest = self.fast_value(a);
+                if dest as usize >= TOM + NUM_REG {
+                    return Err(Error::MemoryInvalid { pc, opcode, operand: dest });
+                }
+                self.fast_store(dest, self.fast_value(b));
+            },
+            17 => {
+                self.stack.push(self.pc);
+                self.pc = self.fast_value(a);
+            },
+            18 => match self.stack.pop() {
+                Some(addr) => self.pc = addr,
+                None => self.halt(),
+            },
+            _ => {},    // noop, or a jump not taken
+        }
+        self.executed += 1;
+        Ok(())
+    }
+
+    /**
+     * `value` for `run_fast`, given an operand word that's already been checked
+     */
+    #[inline(always)]
+    fn fast_value(&self, word:u16) -> u16 {
+        if (word as usize) < TOM {
+            word
+        } else {
+            self.registers[word as usize - TOM]
+        }
+    }
+
+    /**
+     * `peek` for `run_fast`, `None` past the registers
+     */
+    #[inline(always)]
+    fn fast_load(&self, addr:u16) -> Option<u16> {
+        match addr as usize {
+            addr if addr < TOM => Some(self.mem[addr]),
+            addr => self.registers.get(addr - TOM).copied(),
+        }
+    }
+
+    /**
+     * `store` for `run_fast`, given an address below TOM + 8
+     */
+    #[inline(always)]
+    fn fast_store(&mut self, addr:u16, value:u16) {
+        if (addr as usize) < TOM {
+            self.mem[addr as usize] = value;
+            self.blocks.invalidate(addr);
+        } else {
+            self.registers[addr as usize - TOM] = value;
         }
     }
 
@@ -179,53 +1108,51 @@ impl Machine {
     }
 
     /**
-     * Given `instruction`, calls the appropriate function to execute the opcode.
-     * If `instruction` can't decode into a known instruction, an `ErrorUnknownOpcode`
-     * panic occurs.
+     * Given a decoded `instruction`, calls the appropriate function to execute it
      */
-    fn execute(&mut self, instruction:u16) {
-        // if self.debug { println!(" opcode: {:#X} pc: {:#X} (offset {:#X}) step: {} ", instruction, self.pc, self.pc * 2, self.executed); }
-        if self.debug { disassemble_range(self, self.pc-1, self.pc-1); }
-        io::stdout().flush().unwrap();
-        self.executed += 1;
+    fn execute(&mut self, instruction:Instruction) -> Result<StepOutcome, Error> {
+        use Instruction::*;
+
+        if self.debug { println!("{:#06X}:\t{}", self.instruction_pc, instruction); }
         match instruction {
-            0x0000 => self.halt(),      // `halt`
-            0x0001 => self.set(),
-            0x0002 => self.push(),
-            0x0003 => self.pop(),
-            0x0004 => self.eq(),  // a to 1 if b =0 c, 0 otherwise
-            0x0005 => self.gt(),
-            0x0006 => self.jmp(),
-            0x0007 => self.jt(), // jmp if a != 0
-            0x0008 => self.jf(), // jmp if a == 0
-            0x0009 => self.add(),
-            0x000A => self.mult(),
-            0x000B => self.modulo(),
-            0x000C => self.and(),
-            0x000D => self.or(),
-            0x000E => self.not(),
-            0x000F => self.rmem(),
-            0x0010 => self.wmem(),
-            0x0011 => self.call(),
-            0x0012 => self.ret(),
-            0x0013 => self.out(),
-            0x0014 => self.read_in(),
-            0x0015 => self.nop(),       // `noop` 0d2
-            _ => self.unknown(instruction),
-        }
-    }
-
-    fn unknown(&mut self, instruction:u16) {
-        self.dump();
-        println!("\n**** unknown opcode ****\n(big-endian)");
-        println!("unknown instruction: {:#X}", instruction);
-        panic!(Error::UnknownOpcode)
+            Halt => self.halt(),
+            Set(a, b) => self.set(a, b)?,
+            Push(a) => self.push(a)?,
+            Pop(a) => self.pop(a)?,
+            Eq(a, b, c) => self.eq(a, b, c)?,  // a to 1 if b == c, 0 otherwise
+            Gt(a, b, c) => self.gt(a, b, c)?,
+            Jmp(a) => self.jmp(a)?,
+            Jt(a, b) => self.jt(a, b)?, // jmp if a != 0
+            Jf(a, b) => self.jf(a, b)?, // jmp if a == 0
+            Add(a, b, c) => self.add(a, b, c)?,
+            Mult(a, b, c) => self.mult(a, b, c)?,
+            Mod(a, b, c) => self.modulo(a, b, c)?,
+            And(a, b, c) => self.and(a, b, c)?,
+            Or(a, b, c) => self.or(a, b, c)?,
+            Not(a, b) => self.not(a, b)?,
+            Rmem(a, b) => self.rmem(a, b)?,
+            Wmem(a, b) => self.wmem(a, b)?,
+            Call(a) => self.call(a)?,
+            Ret => self.ret()?,
+            Out(a) => self.out(a)?,
+            In(a) => return self.read_in(a),
+            Noop => self.nop(),
+        }
+
+        if self.is_halted() {
+            Ok(StepOutcome::Halted)
+        } else {
+            Ok(StepOutcome::Running)
+        }
     }
 
     pub fn dump(&self) {
-        println!("\n**** dump ****\n(big-endian)");
+        println!("\n**** dump ****");
         println!("status: {:#b}", self.status);
-        println!("pc: {:#X}\nmem[pc]: {:#X}\n", self.pc, swap_endian(self.mem[self.pc as usize]));
+        match self.read_word(self.pc) {
+            Ok(val) => println!("pc: {:#X}\nmem[pc]: {:#X}\n", self.pc, val),
+            Err(_) => println!("pc: {:#X}\nmem[pc]: out of range\n", self.pc),
+        }
         println!("(file offset {:#X})", self.pc * 2);
 
         let mut val:u8;
@@ -236,22 +1163,23 @@ impl Machine {
             }
             print!(" ");
 
+            // bytes in the order they're in the image: low, then high
             for y in x..x+8 {
-                val = (self.mem[y] >> 8) as u8;
-                if val >= 0x20 && val <= 0x7E {
+                val = (self.mem[y] & 0x00FF) as u8;
+                if (0x20..=0x7E).contains(&val) {
                     print!("{}", val as char);
                 } else {
                     print!(".");
                 }
 
-                val = (self.mem[y] | 0x00FF) as u8;
-                if val >= 0x20 && val <= 0x7E {
+                val = (self.mem[y] >> 8) as u8;
+                if (0x20..=0x7E).contains(&val) {
                     print!("{}", val as char);
                 } else {
                     print!(".");
                 }
             }
-            println!("");
+            println!();
         }
     }
 
@@ -263,328 +1191,249 @@ impl Machine {
     }
 
     /**
-     * Set register a to the immediate value of b
+     * Returns the value of `operand`: a literal is its own value, and a register is read
      */
-    fn set(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut val:u16 = self.peek_inc();
-
-        if val >= TOM as u16 {
-            val = self.peek(val);
+    fn value(&mut self, operand:Operand) -> Result<u16, Error> {
+        match operand {
+            Operand::Literal(val) => Ok(val),
+            Operand::Register(_) => self.peek(operand.to_word()),
         }
+    }
 
-        self.poke(dest, val);
+    /**
+     * Writes `value` into the register named by `operand`. A literal destination
+     * writes to that memory address instead
+     */
+    fn store(&mut self, operand:Operand, value:u16) -> Result<(), Error> {
+        self.poke(operand.to_word(), value)
+    }
+
+    /**
+     * Set register a to the immediate value of b
+     */
+    fn set(&mut self, a:Operand, b:Operand) -> Result<(), Error> {
+        let val:u16 = self.value(b)?;
+        self.store(a, val)
     }
 
     /**
      * Pushes immediate value a onto the stack
      */
-    fn push(&mut self) {
-        let mut val:u16 = self.peek_inc();
-        if val >= TOM as u16 {
-            val = self.peek(val);
-        }
+    fn push(&mut self, a:Operand) -> Result<(), Error> {
+        let val:u16 = self.value(a)?;
         self.stack.push(val);
+        self.record(Change::Pushed);
+        Ok(())
     }
 
 
     /**
      * Remove the top element from the stack and write it into a
-     * An empty stack panics
+     * An empty stack is an error
      */
-    fn pop(&mut self) {
+    fn pop(&mut self, a:Operand) -> Result<(), Error> {
         let value:u16 = match self.stack.pop() {
             Some(p) => p,
-            None => panic!(Error::EmptyStack)
+            None => return Err(Error::EmptyStack { pc: self.instruction_pc, opcode: self.instruction }),
         };
-        let dest:u16 = self.peek_inc();
-        self.poke(dest, value);
+        self.record(Change::Popped(value));
+        self.store(a, value)
     }
 
     /**
      * set a to 1 if b is equal than c; set it to 0 otherwise
      */
-    fn eq(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        let mut c:u16 = self.peek_inc();
-
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-        if c >= TOM as u16 {
-            c = self.peek(c);
-        }
+    fn eq(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
         if b == c {
-            self.poke(dest, 1);
+            self.store(a, 1)
         } else {
-            self.poke(dest, 0);
+            self.store(a, 0)
         }
     }
 
     /**
      * set a to 1 if b is greater than c; set it to 0 otherwise
      */
-    fn gt(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        let mut c:u16 = self.peek_inc();
-
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-        if c >= TOM as u16 {
-            c = self.peek(c);
-        }
+    fn gt(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
         if b > c {
-            self.poke(dest, 1);
+            self.store(a, 1)
         } else {
-            self.poke(dest, 0);
+            self.store(a, 0)
         }
     }
 
     /**
      * jump to a
      */
-    fn jmp(&mut self) {
-        self.pc = self.peek_inc();
-        // if self.debug { print!(" (jmp {:#X} (byte offset in file: {:#X})) ", self.pc, self.pc * 2); }
+    fn jmp(&mut self, a:Operand) -> Result<(), Error> {
+        self.pc = self.value(a)?;
+        Ok(())
     }
 
     /**
      * if a is nonzero jump to b
      */
-    fn jt(&mut self) {
-        let mut val = self.peek_inc();
-
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if val >= TOM as u16 {
-            val = self.peek(val);
-        }
-
-        let dest = self.peek_inc();
+    fn jt(&mut self, a:Operand, b:Operand) -> Result<(), Error> {
+        let val:u16 = self.value(a)?;
+        let dest:u16 = self.value(b)?;
         if val != 0 {
             self.pc = dest;
-            // if self.debug { print!(" (jt {:#X} (byte offset in file: {:#X})) ", self.pc, self.pc * 2); }
         }
+        Ok(())
     }
 
     /**
      * if a is 0 jump to b
      */
-    fn jf(&mut self) {
-        let mut val = self.peek_inc();
-        let dest = self.peek_inc();
-
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if val >= TOM as u16 {
-            val = self.peek(val);
-        }
-
+    fn jf(&mut self, a:Operand, b:Operand) -> Result<(), Error> {
+        let val:u16 = self.value(a)?;
+        let dest:u16 = self.value(b)?;
         if val == 0 {
             self.pc = dest;
-            // if self.debug { print!(" (jf {:#X} (byte offset in file: {:#X})) ", self.pc, self.pc * 2); }
         }
+        Ok(())
     }
 
     /**
      * Assign into a the sum of immediate values b and c (modulo 0x8000)
      */
-    fn add(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut a:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if a >= TOM as u16 {
-            a = self.peek(a);
-        }
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-
-        //let sum:u16 = a.wrapping_add(b) % TOM as u16;
-        let sum:u16 = a.wrapping_add(b) % TOM as u16;
+    fn add(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
-        self.poke(dest, sum);
+        let sum:u16 = b.wrapping_add(c) % TOM as u16;
+        self.store(a, sum)
     }
 
     /**
      * store into a the product of b and c (modulo 32768)
      */
-    fn mult(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        let mut c:u16 = self.peek_inc();
+    fn mult(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-        if c >= TOM as u16 {
-            c = self.peek(c);
-        }
-
-        // if self.debug { println!("b: {:#X} c: {:#X}", b, c); }
-        b = b.wrapping_mul(c) % TOM as u16;
-
-        self.poke(dest, b)
+        let product:u16 = b.wrapping_mul(c) % TOM as u16;
+        self.store(a, product)
     }
 
     /**
      * Writes the character represented by immediate ASCII code a to the terminal
      */
-    fn out(&mut self) {
-        let mut val:u16 = self.peek_inc();
-
-        if val >= TOM as u16 {
-            val = self.peek(val);
-        }
+    fn out(&mut self, a:Operand) -> Result<(), Error> {
+        let val:u16 = self.value(a)?;
 
         // ASCII output
         set_bit(&mut self.status, OUT_BIT);
-        print!("{}", (val as u8) as char);
-        io::stdout().flush().unwrap();
+        self.output.write_byte(val as u8);
+        self.output.flush();
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.output(self.executed, val as u8);
+        }
+        if let Some(replay) = self.replay.as_mut() {
+            replay.output(self.executed, val as u8);
+        }
+        if self.recent_output.len() == RECENT_OUTPUT_SIZE {
+            self.recent_output.pop_front();
+        }
+        self.recent_output.push_back(val as u8);
         //clear_bit(&mut self.status, OUT_BIT);
+        Ok(())
     }
 
     /**
      * store into a the remainder of b/c
      */
-    fn modulo(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        let mut c:u16 = self.peek_inc();
-
-        // if self.debug { println!("dest: {}, b: {}, c: {}", dest, b, c); }
+    fn modulo(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
-        // a value between TOM and TOM + NUM_REG inclusive refers to a register location instead
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-        if c >= TOM as u16 {
-            c = self.peek(c);
+        if c == 0 {
+            return Err(Error::DivideByZero { pc: self.instruction_pc, opcode: self.instruction });
         }
-
-        // if self.debug { println!("dest: {}, b: {}, c: {}", dest, b, c); }
-
-        self.poke(dest, b%c);
+        self.store(a, (b%c) % TOM as u16)
     }
 
     /**
      * store into a the bitwise and of b and c
      */
-    fn and(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        let mut c:u16 = self.peek_inc();
-
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-        if c >= TOM as u16 {
-            c = self.peek(c);
-        }
+    fn and(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
         let value:u16 = (b&c) % TOM as u16;
-        self.poke(dest, value);
+        self.store(a, value)
     }
 
     /**
      * store into a the bitwise or of b and c
      */
-    fn or(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        let mut c:u16 = self.peek_inc();
-
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
-        if c >= TOM as u16 {
-            c = self.peek(c);
-        }
+    fn or(&mut self, a:Operand, b:Operand, c:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
+        let c:u16 = self.value(c)?;
 
         let value:u16 = (b|c) % TOM as u16;
-        self.poke(dest, value);
+        self.store(a, value)
     }
 
     /**
      * store into a the bitwise inverse of b
      */
-    fn not(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut b:u16 = self.peek_inc();
-        if b >= TOM as u16 {
-            b = self.peek(b);
-        }
+    fn not(&mut self, a:Operand, b:Operand) -> Result<(), Error> {
+        let b:u16 = self.value(b)?;
         let value:u16 = (!b) % TOM as u16;
-        self.poke(dest, value);
+        self.store(a, value)
     }
 
     /**
      * read memory at address <b> and write it to address in <a>
      */
-    fn rmem(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut source:u16 = self.peek_inc();
-
-        if source >= TOM as u16 {
-            source = self.peek(source);
-        }
-        let mut value:u16 = self.peek(source);
-
-        if value >= TOM as u16 {
-            value = self.peek(value);
-        }
-        // if self.debug { println!("storing into {:#X} the value contained in {:#X}, which is {:#X}", dest, source, value); }
-
-        self.poke(dest, value);
+    fn rmem(&mut self, a:Operand, b:Operand) -> Result<(), Error> {
+        let source:u16 = self.value(b)?;
+        let value:u16 = self.peek(source)?;
+        self.store(a, value)
     }
 
     /**
      * write value contained in <b> into memory at address <a>
      */
-    fn wmem(&mut self) {
-        let mut dest:u16 = self.peek_inc();
-        let mut value:u16 = self.peek_inc();
+    fn wmem(&mut self, a:Operand, b:Operand) -> Result<(), Error> {
+        let dest:u16 = self.value(a)?;
+        let value:u16 = self.value(b)?;
 
-        if dest >= TOM as u16 {
-            dest = self.peek(dest);
-        }
-        if value >= TOM as u16 {
-            value = self.peek(value);
-        }
-        // if self.debug { println!("writing mem location {:#X} with the value {:#X}", dest, value); }
-
-        self.poke(dest, value);
+        self.poke(dest, value)
     }
 
     /**
      * write the address of the next instruction to the stack and jump to a
      */
-    fn call(&mut self) {
-        let mut dest:u16 = self.peek_inc();
+    fn call(&mut self, a:Operand) -> Result<(), Error> {
+        let dest:u16 = self.value(a)?;
         self.stack.push(self.pc);
-        if dest >= TOM as u16 {
-            dest = self.peek(dest);
-        }
+        self.record(Change::Pushed);
         self.pc = dest;
+        Ok(())
     }
 
     /**
      * remove the top element from the stack and jump to it; empty stack = halt
      */
-    fn ret(&mut self) {
+    fn ret(&mut self) -> Result<(), Error> {
         let value:u16 = match self.stack.pop() {
             Some(p) => p,
-            None => panic!(Error::EmptyStack)
+            None => {
+                self.halt();
+                return Ok(());
+            },
         };
+        self.record(Change::Popped(value));
         self.pc = value;
+        Ok(())
     }
 
     /**
@@ -593,58 +1442,73 @@ impl Machine {
      * until a newline is encountered.
      * This means that you can safely read whole lines from the keyboard
      * and trust that they will be fully read
+     *
+     * Queued input comes first, then a replay's input if there is one, then the input source.
+     * If that has nothing left, `pc` is rewound to this instruction and `WaitingForInput` is
+     * returned
+     *
+     * A `.` typed at the start of a line is the hypervisor escape: the rest of that line is
+     * thrown away, `pc` is rewound to this instruction and `Stopped(Interrupted)` is returned,
+     * so the guest never sees any of it
      */
-    fn read_in(&mut self) {
-        let dest:u16 = self.peek_inc();
-        let mut input = String::new();
+    fn read_in(&mut self, a:Operand) -> Result<StepOutcome, Error> {
         set_bit(&mut self.status, IN_BIT);
 
-        let in_char:u8 = std::io::stdin().bytes().nth(0).expect("no byte read").unwrap();
-        if in_char == '.' as u8  {
-            // TODO: entering and exiting hypervisor control still passes some input to the guest. Prevent this
-            self.hypervisor_input_handler();
+        let (in_char, queued):(u8, bool) = match self.pending_input.pop_front() {
+            Some(c) => (c, true),
+            None => {
+                let read = match self.replay.as_mut() {
+                    Some(replay) => replay.input(self.executed),
+                    None => self.input.read_byte(),
+                };
+                match read {
+                    Some(c) => (c, self.replay.is_some()),
+                    None => {
+                        self.pc = self.instruction_pc;
+                        return Ok(StepOutcome::WaitingForInput);
+                    },
+                }
+            },
+        };
+        if in_char == b'.' && !queued && !self.mid_line && self.input.is_interactive() {
+            while !matches!(self.input.read_byte(), Some(b'\n') | None) {}
+            self.pc = self.instruction_pc;
+            return Ok(self.stop(StopReason::Interrupted));
+        }
+        self.record(Change::Input(in_char));
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.input(self.executed, in_char);
+        }
+        self.mid_line = in_char != b'\n';
+        self.store(a, in_char as u16)?;
+
+        if self.is_halted() {
+            Ok(StepOutcome::Halted)
+        } else {
+            Ok(StepOutcome::Running)
         }
-        self.poke(dest, in_char as u16);
     }
 
     fn nop(&self) { }
 
-    fn hypervisor_input_handler(&mut self) {
+    /**
+     * Reports why execution stopped and hands the terminal over to the hypervisor
+     */
+    pub fn debug_break(&mut self, reason:StopReason) {
+        hc::report_stop(self, reason);
+        self.hypervisor_loop();
+        self.interrupt.store(false, Ordering::Relaxed);     // a Ctrl-C typed at the hypervisor prompt
+    }
+
+    /**
+     * Hands the terminal over to the hypervisor until the user returns to the guest
+     */
+    pub fn hypervisor_input_handler(&mut self) {
         println!("\n\n***\nSynacor hypervisor control program\nh - help\n");
-        loop {
-            io::stdout().flush().unwrap();
-
-            // TODO: stop reading after getting a command. E.g., 'sl' will both save and load. It should save only, or be invalid
-            match std::io::stdin().bytes().nth(0).expect("no byte read").unwrap() as char {
-                'd' => hc::disassemble(self),
-                'D' => hc::toggle_debug(self),
-                's' => hc::save_state(self),
-                'l' => hc::load_state(self),
-                'p' => hc::print_regs(self),
-                'g' => hc::goto_and_run(self),
-                'x' => hc::examine_memory(self),
-                'w' => hc::write_memory(self),
-                'r' => { println!("returning execution to guest...\n***\n\n"); break; },
-                '\n' => {},
-                _ => println!("{}", {
-                    "h - Help\n\
-                     d - Disassemble: d SSSS EEEE\n\
-                     D - toggle debug output\n\
-                     s - Save state\n\
-                     l - Load state\n\
-                     p - Print registers\n\
-                     g - Goto and run: g NNNN\n\
-                     x - eXamine memory: x SSSS EEEE\n\
-                     w - Write memory: w NNNN v\n\
-                     r - Return to guest\n\
-                     \n\
-                     NNNN memory location in hex\n\
-                     SSSS start memory location in hex\n\
-                     EEEE end memory location in hex\n\
-                     v value in hex\n\
-                     "
-                }),
-            }
-        }
+        self.hypervisor_loop();
+    }
+
+    fn hypervisor_loop(&mut self) {
+        hc::command_loop(self);
     }
 }
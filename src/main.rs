@@ -1,42 +1,539 @@
-mod tests;
-mod errors;
+#[cfg(feature = "frontpanel")]
 mod display;
 
-use std::ops::{Index, IndexMut};
-use std::io::{self, Write, Read};
-use std::char;
-use crate::errors::Error::{MemoryInvalid, UnknownOpcode, EmptyStack, FailedToReadLine};
-use std::fs::File;
-use std::num::Wrapping;
-use crate::display::frontpanelRun;
-use synacor_cpu::Machine;
-use synacor_cpu::constants::TOM;
+use std::error::Error;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::fs::{self, File};
+use std::{env, process};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+#[cfg(feature = "frontpanel")]
+use crate::display::frontpanel_run;
+use synacor_cpu::{analysis, assembler, disassembler, loader, Engine, Machine, StepOutcome};
+use synacor_cpu::loader::LoadOptions;
+use synacor_cpu::console::{MemoryInput, MemoryOutput, StdinInput};
+use synacor_cpu::constants::{CHECKPOINT_INTERVAL, DEFAULT_TIMELINE_SIZE, TOM};
+use synacor_cpu::timeline::Timeline;
+use synacor_cpu::replay::{Recorder, Replay};
+use synacor_cpu::script::Script;
+use synacor_cpu::debugger::StopReason;
+use synacor_cpu::trace::{TraceFilter, TraceReader, Tracer};
 
+const USAGE:&str = "\
+Usage:
+    synacor_cpu run <image> [options]      run an image (challenge.bin with the frontpanel if no arguments are given)
+    synacor_cpu debug <image> [options]    run headless, starting in the hypervisor
+    synacor_cpu disasm <image> [SSSS EEEE] [options]
+    synacor_cpu asm <src> -o <bin>
+    synacor_cpu trace dump <log> [options] print a binary trace log as text
+    synacor_cpu bench [image] [--instructions N]
+                                           instructions per second without the fast path and
+                                           with each engine, on a built-in program if no image
+                                           is given
 
-// see tests.rs
-fn main() -> io::Result<()> {
-    let mut f = File::open("challenge.bin")?;
+Options for run and debug:
+    --headless              don't open the frontpanel window (always the case without the
+                            frontpanel feature)
+    --input FILE            feed FILE to the guest before reading from STDIN
+    --script FILE           like --input, but lines starting with ! are hypervisor commands,
+                            run when the guest starts reading the next line (!set r7 25734)
+    --state FILE            load a state saved by the hypervisor before starting
+    --max-instructions N    stop after executing N instructions
+    --engine interp|blocks  how to run while nothing's watching: one instruction at a time
+                            (the default) or from a cache of decoded basic blocks
+    --trace FILE            write every executed instruction to FILE
+    --trace-log FILE        record executed instructions, the values of their operands and
+                            what they wrote to FILE as a compact binary log
+    --record-input FILE     write every byte the guest reads, and what it prints, to FILE
+    --replay FILE           feed the guest the input recorded in FILE instead of STDIN and
+                            check it prints the same. Exits with 1 if it doesn't
+    --record                keep an undo log so the hypervisor can step back (record on)
+    --snapshot-dir DIR      where the hypervisor's save, load, list and delete keep named
+                            snapshots (default: snapshots)
 
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer)?;
+Options for loading images (run, debug and disasm):
+    --format bin|dec|hex    little-endian binary, comma-separated decimal words or Intel HEX.
+                            Detected from the contents by default
+    --strict                reject images with words in the invalid range 32776..65535
 
-    let mut m0 = Machine::new();
-    let mut val:u16 = 0;
-    for n in (0..TOM*2).step_by(2) {
-    // for n in (0..buffer.len()).step_by(2) {
-        if n >= buffer.len() {
-            m0.mem.push(0 as u16);
-        } else {
-            val = (buffer[n] as u16) << 8;
-            val |= buffer[n+1] as u16;
-            m0.mem.push(val);
+Trace filters (run, debug and trace dump), each can be repeated:
+    --trace-addr SSSS[-EEEE]    only instructions in this range of addresses
+    --trace-op NAME             only this instruction, e.g. call
+    --trace-window N-[M]        only instructions N (counting from 0) up to M
+
+Options for disasm:
+    --entry NNNN            follow control flow from 0 and NNNN (can be repeated) instead of
+                            sweeping linearly. Words that aren't reached are shown as data
+    --dot FILE              write the control-flow graph to FILE as Graphviz source
+
+SSSS, EEEE and NNNN are addresses in hex
+
+Ctrl-C (or H in the frontpanel) stops the guest before its next instruction and opens the
+hypervisor. Pressing Ctrl-C again before that happens quits";
+
+/**
+ * Prints `message` and the usage text, and exits with the status for bad arguments
+ */
+fn usage_error(message:&str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn parse_addr(token:&str) -> u16 {
+    match u16::from_str_radix(token, 16) {
+        Ok(addr) if (addr as usize) < TOM => addr,
+        _ => usage_error(&format!("`{}` isn't an address in hex", token)),
+    }
+}
+
+/**
+ * Returns the value following a flag, e.g. the FILE in `--trace FILE`
+ */
+fn flag_value<'a>(flag:&str, args:&mut impl Iterator<Item = &'a String>) -> &'a String {
+    args.next().unwrap_or_else(|| usage_error(&format!("{} needs a value", flag)))
+}
+
+/**
+ * Handles the trace filters shared by run, debug and trace dump. Returns `false` if `arg`
+ * isn't one of them
+ */
+fn trace_option<'a>(arg:&str, args:&mut impl Iterator<Item = &'a String>, filter:&mut TraceFilter) -> bool {
+    let kind = match arg {
+        "--trace-addr" => "addr",
+        "--trace-op" => "op",
+        "--trace-window" => "window",
+        _ => return false,
+    };
+    if let Err(e) = filter.add(kind, flag_value(arg, args)) {
+        usage_error(&e);
+    }
+    true
+}
+
+/**
+ * Handles the image options shared by run, debug and disasm. Returns `false` if `arg`
+ * isn't one of them
+ */
+fn load_option<'a>(arg:&str, args:&mut impl Iterator<Item = &'a String>, options:&mut LoadOptions) -> bool {
+    match arg {
+        "--strict" => options.strict = true,
+        "--format" => {
+            let value = flag_value(arg, args);
+            options.format = Some(value.parse().unwrap_or_else(|e:String| usage_error(&e)));
+        },
+        _ => return false,
+    }
+    true
+}
+
+struct RunOptions {
+    image:String,
+    load:LoadOptions,
+    debug:bool,
+    headless:bool,
+    input:Option<String>,
+    script:Option<String>,
+    state:Option<String>,
+    max_instructions:Option<u64>,
+    engine:Engine,
+    trace:Option<String>,
+    trace_log:Option<String>,
+    record:bool,
+    record_input:Option<String>,
+    replay:Option<String>,
+    trace_filter:TraceFilter,
+    snapshot_dir:Option<String>,
+}
+
+impl RunOptions {
+    fn parse(args:&[String], debug:bool) -> RunOptions {
+        let mut options = RunOptions {
+            image: String::new(),
+            load: LoadOptions::default(),
+            debug,
+            headless: debug,    // the hypervisor wants the terminal, not a window
+            input: None,
+            script: None,
+            state: None,
+            max_instructions: None,
+            engine: Engine::default(),
+            trace: None,
+            trace_log: None,
+            record: false,
+            record_input: None,
+            replay: None,
+            trace_filter: TraceFilter::default(),
+            snapshot_dir: None,
+        };
+
+        let mut image:Option<String> = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if load_option(arg, &mut args, &mut options.load) || trace_option(arg, &mut args, &mut options.trace_filter) {
+                continue;
+            }
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--record" => options.record = true,
+                "--input" => options.input = Some(flag_value(arg, &mut args).clone()),
+                "--script" => options.script = Some(flag_value(arg, &mut args).clone()),
+                "--state" => options.state = Some(flag_value(arg, &mut args).clone()),
+                "--trace" => options.trace = Some(flag_value(arg, &mut args).clone()),
+                "--trace-log" => options.trace_log = Some(flag_value(arg, &mut args).clone()),
+                "--record-input" => options.record_input = Some(flag_value(arg, &mut args).clone()),
+                "--replay" => options.replay = Some(flag_value(arg, &mut args).clone()),
+                "--snapshot-dir" => options.snapshot_dir = Some(flag_value(arg, &mut args).clone()),
+                "--max-instructions" => {
+                    let value = flag_value(arg, &mut args);
+                    options.max_instructions = Some(value.parse()
+                        .unwrap_or_else(|_| usage_error(&format!("`{}` isn't a number of instructions", value))));
+                },
+                "--engine" => options.engine = match flag_value(arg, &mut args).as_str() {
+                    "interp" => Engine::Interpreter,
+                    "blocks" => Engine::Blocks,
+                    engine => usage_error(&format!("unknown engine `{}`, expected interp or blocks", engine)),
+                },
+                flag if flag.starts_with("--") => usage_error(&format!("unknown option {}", flag)),
+                _ if image.is_none() => image = Some(arg.clone()),
+                _ => usage_error(&format!("unexpected argument `{}`", arg)),
+            }
         }
+        options.image = image.unwrap_or_else(|| usage_error("missing image"));
+        if options.trace.is_some() && options.trace_log.is_some() {
+            usage_error("--trace and --trace-log can't be used together");
+        }
+        if options.replay.is_some() && (options.input.is_some() || options.script.is_some()) {
+            usage_error("--replay can't be used with --input or --script");
+        }
+        options
+    }
+}
+
+/**
+ * `run <image>` and `debug <image>`. Returns the exit status: 0 when the guest halts, runs
+ * out of input or hits the instruction limit, 1 on a fault or if a replay doesn't match
+ */
+fn run(options:&RunOptions) -> Result<i32, Box<dyn Error>> {
+    let mut m0 = Machine::from_file_with(&options.image, &options.load)?;
+    m0.set_engine(options.engine);
+    if let Some(state) = &options.state {
+        m0.load_state(state)?;
+    }
+    let mut scripted = false;
+    if let Some(input) = &options.input {
+        m0.set_input(Box::new(MemoryInput::new(&fs::read(input)?)));
+        scripted = true;
+    }
+    if let Some(script) = &options.script {
+        m0.set_script(Some(Script::parse(&fs::read_to_string(script)?)));
+    }
+    if let Some(dir) = &options.snapshot_dir {
+        m0.snapshot_dir = dir.into();
+    }
+    if let Some(trace) = &options.trace {
+        m0.set_tracer(Some(Tracer::text(Box::new(BufWriter::new(File::create(trace)?))).with_filter(options.trace_filter.clone())));
+    }
+    if let Some(log) = &options.trace_log {
+        m0.set_tracer(Some(Tracer::binary(Box::new(BufWriter::new(File::create(log)?)))?.with_filter(options.trace_filter.clone())));
     }
 
-    frontpanelRun(&mut m0);
+    if let Some(recording) = &options.record_input {
+        m0.set_recorder(Some(Recorder::new(Box::new(BufWriter::new(File::create(recording)?)))?));
+    }
+    if let Some(recording) = &options.replay {
+        m0.set_replay(Some(Replay::from_bytes(&fs::read(recording)?)?));
+    }
+    if options.record {
+        m0.set_timeline(Some(Timeline::new(DEFAULT_TIMELINE_SIZE, CHECKPOINT_INTERVAL)));
+    }
+
+    let interrupt = m0.interrupt_handle();
+    ctrlc::set_handler(move || {
+        // a guest blocked reading STDIN never gets to the next instruction, so a second
+        // Ctrl-C before the first one is picked up quits
+        if interrupt.swap(true, Ordering::Relaxed) {
+            process::exit(130);
+        }
+    })?;
 
-    m0.run();
+    if options.debug {
+        m0.hypervisor_input_handler();
+    }
+    #[cfg(feature = "frontpanel")]
+    if !options.headless {
+        if let Err(e) = frontpanel_run(&mut m0) {
+            eprintln!("frontpanel: {}", e);
+        }
+    }
+    #[cfg(not(feature = "frontpanel"))]
+    if !options.headless {
+        eprintln!("built without the frontpanel feature, running headless");
+    }
 
-    Ok(())
+    let start = m0.executed();
+    let status = loop {
+        let count = m0.executed() - start;
+        if options.max_instructions.is_some_and(|max| count >= max) {
+            eprintln!("\n**** stopped after {} instructions ****", count);
+            break 0;
+        }
+        match m0.run_fast(options.max_instructions.map_or(u64::MAX, |max| max - count)) {
+            Ok(StepOutcome::Running) => {},
+            Ok(StepOutcome::Stopped(StopReason::Diverged(_))) if !options.debug => break replay_status(&m0),
+            Ok(StepOutcome::Stopped(reason)) => m0.debug_break(reason),
+            Ok(StepOutcome::Halted | StepOutcome::WaitingForInput) if m0.replay().is_some() => break replay_status(&m0),
+            Ok(StepOutcome::Halted) => break 0,
+            Ok(StepOutcome::WaitingForInput) if scripted => {
+                // the script is used up, let a person take over
+                m0.set_input(Box::new(StdinInput));
+                scripted = false;
+            },
+            Ok(StepOutcome::WaitingForInput) => break 0,
+            Err(e) => {
+                m0.dump();
+                eprintln!("\n**** {} ****", e);
+                break 1;
+            },
+        }
+    };
+
+    m0.set_tracer(None);    // flushes the trace file
+    m0.set_recorder(None);
+    Ok(status)
+}
+
+/**
+ * Says how a replay went. Returns the exit status: 0 if it matched the recording
+ */
+fn replay_status(m0:&Machine) -> i32 {
+    match m0.replay() {
+        Some(replay) => {
+            eprintln!("\n**** {} ****", replay.summary());
+            if replay.matched() { 0 } else { 1 }
+        },
+        None => 0,
+    }
+}
+
+/**
+ * `asm <src> -o <bin>`: assembles `src` into a binary image that can be loaded in place
+ * of challenge.bin
+ */
+fn assemble(args:&[String]) -> Result<i32, Box<dyn Error>> {
+    let (src, bin) = match args {
+        [src, flag, bin] if flag == "-o" => (src, bin),
+        _ => usage_error("asm takes a source file and -o <bin>"),
+    };
+
+    let source = fs::read_to_string(src)?;
+    match assembler::assemble_to_bytes(&source) {
+        Ok(bytes) => {
+            fs::write(bin, bytes)?;
+            Ok(0)
+        },
+        Err(e) => {
+            eprintln!("{}:{}", src, e);
+            Ok(1)
+        },
+    }
+}
+
+/**
+ * `disasm <image> [SSSS EEEE]`: prints the disassembly of an image, or of the range
+ * SSSS..=EEEE of it
+ */
+fn disassemble(args:&[String]) -> Result<i32, Box<dyn Error>> {
+    let mut load = LoadOptions::default();
+    let mut positional:Vec<&String> = Vec::new();
+    let mut entries:Vec<u16> = Vec::new();
+    let mut dot:Option<&String> = None;
+    let mut analyze = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if load_option(arg, &mut args, &mut load) {
+            continue;
+        }
+        match arg.as_str() {
+            "--entry" => {
+                entries.push(parse_addr(flag_value(arg, &mut args)));
+                analyze = true;
+            },
+            "--dot" => {
+                dot = Some(flag_value(arg, &mut args));
+                analyze = true;
+            },
+            flag if flag.starts_with("--") => usage_error(&format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let (image, range) = match positional.as_slice() {
+        [image] => (image, None),
+        [image, start, end] => (image, Some((parse_addr(start), parse_addr(end)))),
+        _ => usage_error("disasm takes an image and an optional SSSS EEEE range"),
+    };
+
+    let words = loader::parse_image(&fs::read(image)?, &load)?;
+    let (start, end) = range.unwrap_or((0, words.len().saturating_sub(1) as u16));
+    if analyze {
+        let analysis = analysis::analyze(&words, &entries);
+        if let Some(dot) = dot {
+            fs::write(dot, analysis.cfg_dot())?;
+        }
+        print!("{}", disassembler::disassemble_with(&words, start, end, &analysis));
+    } else {
+        print!("{}", disassembler::disassemble(&words, start, end));
+    }
+    Ok(0)
 }
 
+/**
+ * `trace dump <log>`: prints the records in a binary trace log that get through the filters
+ */
+fn dump_trace(args:&[String]) -> Result<i32, Box<dyn Error>> {
+    let mut filter = TraceFilter::default();
+    let mut log:Option<&String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if trace_option(arg, &mut args, &mut filter) {
+            continue;
+        }
+        match arg.as_str() {
+            flag if flag.starts_with("--") => usage_error(&format!("unknown option {}", flag)),
+            _ if log.is_none() => log = Some(arg),
+            _ => usage_error(&format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    let log = log.unwrap_or_else(|| usage_error("trace dump takes a log file"));
+    let mut out = BufWriter::new(io::stdout().lock());
+    for record in TraceReader::new(BufReader::new(File::open(log)?))? {
+        let record = record?;
+        if filter.matches(record.executed, record.pc, record.instruction.opcode()) {
+            match writeln!(out, "{}", record) {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,    // piped into head
+                result => result?,
+            }
+        }
+    }
+    Ok(0)
+}
+
+/**
+ * The teleporter check from challenge.bin with smaller arguments, so it takes a few billion
+ * instructions instead of forever: lots of calls, returns, pushes and arithmetic
+ */
+const BENCH_PROGRAM:&str = "
+        set r0 4
+        set r1 1
+        set r7 1
+        call check
+        halt
+check:  jt r0 outer
+        add r0 r1 1
+        ret
+outer:  jt r1 inner
+        add r0 r0 32767
+        set r1 r7
+        call check
+        ret
+inner:  push r0
+        add r1 r1 32767
+        call check
+        set r1 r0
+        pop r0
+        add r0 r0 32767
+        call check
+        ret
+";
+
+/**
+ * `bench [image] [--instructions N]`: runs an image (or `BENCH_PROGRAM`) for N instructions
+ * one `fetch_and_execute` at a time, then with `run_fast` on each engine, and prints how many
+ * instructions a second each managed
+ */
+fn bench(args:&[String]) -> Result<i32, Box<dyn Error>> {
+    let mut instructions:u64 = 50_000_000;
+    let mut image:Option<&String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--instructions" => {
+                let value = flag_value(arg, &mut args);
+                instructions = value.parse().unwrap_or_else(|_| usage_error(&format!("`{}` isn't a number", value)));
+            },
+            flag if flag.starts_with("--") => usage_error(&format!("unknown option {}", flag)),
+            _ if image.is_none() => image = Some(arg),
+            _ => usage_error(&format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let bytes = match image {
+        Some(image) => fs::read(image)?,
+        None => assembler::assemble_to_bytes(BENCH_PROGRAM).map_err(|e| e.to_string())?,
+    };
+
+    let mut rates:Vec<f64> = Vec::new();
+    let modes = [("fetch_and_execute", None), ("run_fast interp", Some(Engine::Interpreter)), ("run_fast blocks", Some(Engine::Blocks))];
+    for (name, engine) in modes.iter() {
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        m0.load_image(&bytes)?;
+        let start = Instant::now();
+        let outcome = match engine {
+            Some(engine) => {
+                m0.set_engine(*engine);
+                m0.run_fast(instructions)
+            },
+            None => {
+                let mut outcome = Ok(StepOutcome::Running);
+                while outcome == Ok(StepOutcome::Running) && m0.executed() < instructions {
+                    outcome = m0.fetch_and_execute();
+                }
+                outcome
+            },
+        };
+        let elapsed = start.elapsed().as_secs_f64();
+        let rate = m0.executed() as f64 / elapsed;
+        let ended = match outcome {
+            Ok(StepOutcome::Running) => String::new(),
+            Ok(outcome) => format!(" ({:?})", outcome),
+            Err(e) => format!(" ({})", e),
+        };
+        println!("{:<18} {:>12} instructions in {:>7.3}s: {:>6.1}M/s{}",
+            name, m0.executed(), elapsed, rate / 1e6, ended);
+        rates.push(rate);
+    }
+    println!("run_fast is {:.1}x as fast with interp, {:.1}x with blocks", rates[1] / rates[0], rates[2] / rates[0]);
+    Ok(0)
+}
+
+fn main() {
+    let args:Vec<String> = env::args().collect();
+    let result = match args.get(1).map(|arg| arg.as_str()) {
+        None => run(&RunOptions::parse(&["challenge.bin".to_string()], false)),
+        Some("run") => run(&RunOptions::parse(&args[2..], false)),
+        Some("debug") => run(&RunOptions::parse(&args[2..], true)),
+        Some("disasm") => disassemble(&args[2..]),
+        Some("asm") => assemble(&args[2..]),
+        Some("bench") => bench(&args[2..]),
+        Some("trace") => match args.get(2).map(|arg| arg.as_str()) {
+            Some("dump") => dump_trace(&args[3..]),
+            _ => usage_error("the only trace command is dump"),
+        },
+        Some("-h") | Some("--help") | Some("help") => {
+            println!("{}", USAGE);
+            Ok(0)
+        },
+        Some(command) => usage_error(&format!("unknown command `{}`", command)),
+    };
+
+    match result {
+        Ok(status) => process::exit(status),
+        Err(e) => {
+            eprintln!("synacor_cpu: {}", e);
+            process::exit(1);
+        },
+    }
+}
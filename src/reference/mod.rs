@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::fmt;
+use crate::console::{MemoryInput, MemoryOutput};
+use crate::constants::{NUM_REG, TOM};
+use crate::errors::Error;
+use crate::machine::{Engine, Machine, StepOutcome};
+use crate::utils::Rng;
+
+/**
+ * A second interpreter, written straight from `arch-spec` and kept as plain as possible: no
+ * status bits, access tracking, debugger or undo log. It's there to check `Machine` against,
+ * see `differential`.
+ *
+ * What it takes from `Machine` rather than the spec: a literal destination writes to that
+ * memory address, `rmem` and `wmem` on 32768..32775 read and write the registers, every
+ * result is kept to 15 bits (a register can pick up a word that isn't, from memory), and
+ * faults are reported with the same `Error`s
+ */
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub mem:Vec<u16>,
+    pub registers:[u16; NUM_REG],
+    pub stack:Vec<u16>,
+    pub pc:u16,
+    pub halted:bool,
+    pub input:VecDeque<u8>,
+    pub output:Vec<u8>,
+}
+
+impl Reference {
+    /**
+     * Loads `program` at address 0, with `input` for the guest to read
+     */
+    pub fn new(program:&[u16], input:&[u8]) -> Self {
+        let mut mem = vec![0; TOM];
+        mem[..program.len()].copy_from_slice(program);
+        Reference {
+            mem,
+            registers: [0; NUM_REG],
+            stack: Vec::new(),
+            pc: 0,
+            halted: false,
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /**
+     * Executes one instruction. Running out of input leaves `pc` on the `in`
+     */
+    pub fn step(&mut self) -> Result<StepOutcome, Error> {
+        if self.halted {
+            return Ok(StepOutcome::Halted);
+        }
+        let pc = self.pc;
+        let opcode = self.word_at(pc).ok_or(Error::MemoryInvalid { pc, opcode: 0, operand: pc })?;
+        let count:u16 = match opcode {
+            0 | 18 | 21 => 0,
+            2 | 3 | 6 | 17 | 19 | 20 => 1,
+            1 | 7 | 8 | 14 | 15 | 16 => 2,
+            4 | 5 | 9..=13 => 3,
+            _ => return Err(Error::UnknownOpcode { pc, opcode }),
+        };
+        let mut args = [0u16; 3];
+        for n in 0..count {
+            let addr = pc.wrapping_add(1 + n);
+            let word = self.word_at(addr).ok_or(Error::MemoryInvalid { pc, opcode, operand: addr })?;
+            if word as usize >= TOM + NUM_REG {
+                return Err(Error::MemoryInvalid { pc, opcode, operand: word });
+            }
+            args[n as usize] = word;
+        }
+        let [a, b, c] = args;
+        self.pc = pc + 1 + count;
+
+        match opcode {
+            0 => self.halted = true,
+            1 => self.set(a, self.val(b)),
+            2 => self.stack.push(self.val(a)),
+            3 => match self.stack.pop() {
+                Some(value) => self.set(a, value),
+                None => return Err(Error::EmptyStack { pc, opcode }),
+            },
+            4 => self.set(a, (self.val(b) == self.val(c)) as u16),
+            5 => self.set(a, (self.val(b) > self.val(c)) as u16),
+            6 => self.pc = self.val(a),
+            7 if self.val(a) != 0 => self.pc = self.val(b),
+            8 if self.val(a) == 0 => self.pc = self.val(b),
+            9 => self.set(a, ((self.val(b) as u32 + self.val(c) as u32) % 32768) as u16),
+            10 => self.set(a, ((self.val(b) as u32 * self.val(c) as u32) % 32768) as u16),
+            11 => match self.val(c) {
+                0 => return Err(Error::DivideByZero { pc, opcode }),
+                divisor => self.set(a, self.val(b) % divisor % 32768),
+            },
+            12 => self.set(a, (self.val(b) & self.val(c)) % 32768),
+            13 => self.set(a, (self.val(b) | self.val(c)) % 32768),
+            14 => self.set(a, !self.val(b) & 0x7FFF),
+            15 => match self.load(self.val(b)) {
+                Some(value) => self.set(a, value),
+                None => return Err(Error::MemoryInvalid { pc, opcode, operand: self.val(b) }),
+            },
+            16 if self.val(a) as usize >= TOM + NUM_REG => return Err(Error::MemoryInvalid { pc, opcode, operand: self.val(a) }),
+            16 => self.set(self.val(a), self.val(b)),
+            17 => {
+                self.stack.push(self.pc);
+                self.pc = self.val(a);
+            },
+            18 => match self.stack.pop() {
+                Some(addr) => self.pc = addr,
+                None => self.halted = true,
+            },
+            19 => self.output.push(self.val(a) as u8),
+            20 => match self.input.pop_front() {
+                Some(byte) => self.set(a, byte as u16),
+                None => {
+                    self.pc = pc;
+                    return Ok(StepOutcome::WaitingForInput);
+                },
+            },
+            _ => {},    // noop, or a jump not taken
+        }
+
+        if self.halted {
+            Ok(StepOutcome::Halted)
+        } else {
+            Ok(StepOutcome::Running)
+        }
+    }
+
+    fn word_at(&self, addr:u16) -> Option<u16> {
+        self.mem.get(addr as usize).copied()
+    }
+
+    /**
+     * A literal is its own value, 32768..32775 is a register
+     */
+    fn val(&self, operand:u16) -> u16 {
+        if operand as usize >= TOM {
+            self.registers[operand as usize - TOM]
+        } else {
+            operand
+        }
+    }
+
+    /**
+     * What `rmem` reads at `addr`: memory, or a register for 32768..32775
+     */
+    fn load(&self, addr:u16) -> Option<u16> {
+        if addr as usize >= TOM {
+            self.registers.get(addr as usize - TOM).copied()
+        } else {
+            self.word_at(addr)
+        }
+    }
+
+    fn set(&mut self, operand:u16, value:u16) {
+        if operand as usize >= TOM {
+            self.registers[operand as usize - TOM] = value;
+        } else {
+            self.mem[operand as usize] = value;
+        }
+    }
+}
+
+/**
+ * Where `Machine` and the reference first disagreed. `step` counts from 0, `pc` is where the
+ * instruction started
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub step:u64,
+    pub pc:u16,
+    pub what:String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "step {} at {:#06X}: {}", self.step, self.pc, self.what)
+    }
+}
+
+/**
+ * Runs `program` on a `Machine` and on the reference side by side, for up to `max_steps`
+ * instructions, comparing the outcome, pc, registers, stack, memory and output after every
+ * one. Stops early, without a mismatch, once both halt, fault the same way or run out of
+ * input. Returns the number of instructions the machine executed
+ */
+pub fn differential(program:&[u16], input:&[u8], max_steps:u64) -> Result<u64, Mismatch> {
+    compare(program, input, max_steps, None)
+}
+
+/**
+ * Same as `differential`, for `Machine::run_fast` with `engine`, comparing after every
+ * `stride` instructions
+ */
+pub fn differential_fast(program:&[u16], input:&[u8], max_steps:u64, engine:Engine, stride:u64) -> Result<u64, Mismatch> {
+    compare(program, input, max_steps, Some((engine, stride.max(1))))
+}
+
+/**
+ * Steps the machine with `run_fast` if there's an engine and a stride, `fetch_and_execute`
+ * if not
+ */
+fn compare(program:&[u16], input:&[u8], max_steps:u64, fast:Option<(Engine, u64)>) -> Result<u64, Mismatch> {
+    let output = MemoryOutput::new();
+    let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(output.clone()));
+    let (engine, stride) = fast.unwrap_or((Engine::Interpreter, 1));
+    m0.set_engine(engine);
+    m0.mem[..program.len()].copy_from_slice(program);
+    m0.queue_input(input);      // queued input is never taken for the hypervisor escape
+    let mut reference = Reference::new(program, input);
+
+    for step in (0..max_steps).step_by(stride as usize) {
+        let pc = reference.pc;
+        let mismatch = |what:String| Mismatch { step, pc, what };
+        let mut expected = reference.step();
+        for _ in 1..stride {
+            if expected != Ok(StepOutcome::Running) {
+                break;
+            }
+            expected = reference.step();
+        }
+        let found = match fast {
+            Some(_) => m0.run_fast(stride),
+            None => m0.fetch_and_execute(),
+        };
+        if found != expected {
+            return Err(mismatch(format!("expected {:?}, got {:?}", expected, found)));
+        }
+        let running = expected == Ok(StepOutcome::Running);
+        if !running && expected != Ok(StepOutcome::Halted) {
+            return Ok(m0.executed());   // the state after a fault isn't specified
+        }
+
+        if m0.pc != reference.pc {
+            return Err(mismatch(format!("pc is {:#06X}, expected {:#06X}", m0.pc, reference.pc)));
+        }
+        if m0.registers != reference.registers {
+            return Err(mismatch(format!("registers are {:?}, expected {:?}", m0.registers, reference.registers)));
+        }
+        if m0.stack != reference.stack {
+            return Err(mismatch(format!("stack is {:?}, expected {:?}", m0.stack, reference.stack)));
+        }
+        if m0.mem != reference.mem {
+            let addr = m0.mem.iter().zip(reference.mem.iter()).position(|(found, expected)| found != expected).unwrap_or(0);
+            return Err(mismatch(format!("mem[{:#06X}] is {:#06X}, expected {:#06X}", addr, m0.mem[addr], reference.mem[addr])));
+        }
+        if output.bytes() != reference.output {
+            return Err(mismatch(format!("printed {:?}, expected {:?}", output.contents(), String::from_utf8_lossy(&reference.output))));
+        }
+        if !running {
+            return Ok(m0.executed());
+        }
+    }
+    Ok(m0.executed())
+}
+
+/**
+ * Makes up a program of `instructions` valid instructions followed by some data, for
+ * `differential`. Jumps and calls land on instructions, `wmem` writes into the data and
+ * sometimes the code, and values cluster around the edges where modulo arithmetic goes wrong
+ */
+pub fn random_program(rng:&mut Rng, instructions:usize) -> Vec<u16> {
+    const DATA_SIZE:usize = 16;
+    const OPCODES:[u16; 21] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21];
+
+    // lay the code out first so jump targets are known, then fill in the operands
+    let mut opcodes:Vec<u16> = vec![1; NUM_REG];   // start with something in every register
+    let mut starts:Vec<u16> = Vec::with_capacity(instructions + NUM_REG);
+    let mut addr:u16 = 0;
+    let size = |opcode:u16| -> u16 {
+        match opcode {
+            0 | 18 | 21 => 1,
+            2 | 3 | 6 | 17 | 19 | 20 => 2,
+            1 | 7 | 8 | 14 | 15 | 16 => 3,
+            _ => 4,
+        }
+    };
+    for _ in 0..instructions {
+        opcodes.push(if rng.chance(2) { 0 } else { OPCODES[rng.below(OPCODES.len() as u64) as usize] });
+    }
+    for opcode in opcodes.iter() {
+        starts.push(addr);
+        addr += size(*opcode);
+    }
+    let data_start = addr;
+    let end = data_start + DATA_SIZE as u16;
+
+    let register = |rng:&mut Rng| TOM as u16 + rng.below(NUM_REG as u64) as u16;
+    let value = |rng:&mut Rng| -> u16 {
+        match rng.below(6) {
+            0 | 1 => TOM as u16 + rng.below(NUM_REG as u64) as u16,
+            2 => rng.below(4) as u16,
+            3 => 32767 - rng.below(4) as u16,
+            4 => 32 + rng.below(95) as u16,    // printable, for `out`
+            _ => rng.below(TOM as u64) as u16,
+        }
+    };
+    let target = |rng:&mut Rng| -> u16 {
+        if rng.chance(10) { register(rng) } else { starts[rng.below(starts.len() as u64) as usize] }
+    };
+    let address = |rng:&mut Rng| -> u16 {
+        match rng.below(10) {
+            0 => register(rng),
+            1 => rng.below(end as u64) as u16,   // code too
+            _ => data_start + rng.below(DATA_SIZE as u64) as u16,
+        }
+    };
+
+    let mut program:Vec<u16> = Vec::with_capacity(end as usize);
+    for (n, opcode) in opcodes.iter().enumerate() {
+        program.push(*opcode);
+        match opcode {
+            1 if n < NUM_REG => program.extend([TOM as u16 + n as u16, value(rng)]),
+            1 | 14 | 15 => program.extend([register(rng), if *opcode == 15 { address(rng) } else { value(rng) }]),
+            2 | 19 => program.push(value(rng)),
+            3 | 20 => program.push(register(rng)),
+            4 | 5 | 9..=13 => program.extend([register(rng), value(rng), value(rng)]),
+            6 | 17 => program.push(target(rng)),
+            7 | 8 => program.extend([value(rng), target(rng)]),
+            16 => program.extend([address(rng), value(rng)]),
+            _ => {},
+        }
+    }
+    for _ in 0..DATA_SIZE {
+        program.push(value(rng));
+    }
+    program
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use crate::errors::{FileFormat, FormatError, FormatErrorKind};
+
+/**
+ * Every input recording starts with these bytes
+ */
+pub const MAGIC:&[u8; 8] = b"SYNPLAY\0";
+pub const VERSION:u16 = 1;
+const FORMAT:FileFormat = FileFormat::Recording;
+
+const INPUT_TAG:u8 = b'i';
+const OUTPUT_TAG:u8 = b'o';
+const EVENT_SIZE:usize = 10;
+
+/**
+ * Writes down every byte the guest reads, and every byte it prints to check a replay
+ * against. After the magic and a u16 version, each event is (little-endian):
+ *
+ * ```text
+ * tag         u8, `i` for a byte read by `in`, `o` for a byte written by `out`
+ * executed    u64, instructions executed before the `in` or `out`
+ * byte        u8
+ * ```
+ */
+pub struct Recorder {
+    out:Box<dyn Write>,
+}
+
+impl Recorder {
+    pub fn new(mut out:Box<dyn Write>) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Recorder { out })
+    }
+
+    pub(crate) fn input(&mut self, executed:u64, byte:u8) {
+        self.event(INPUT_TAG, executed, byte);
+        if byte == b'\n' {
+            let _ = self.out.flush();   // a line at a time, so little is lost if the process is killed
+        }
+    }
+
+    pub(crate) fn output(&mut self, executed:u64, byte:u8) {
+        self.event(OUTPUT_TAG, executed, byte);
+    }
+
+    fn event(&mut self, tag:u8, executed:u64, byte:u8) {
+        let mut event = [0u8; EVENT_SIZE];
+        event[0] = tag;
+        event[1..9].copy_from_slice(&executed.to_le_bytes());
+        event[9] = byte;
+        // a recording that can't be written to shouldn't stop the guest
+        let _ = self.out.write_all(&event);
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/**
+ * The first place a replay went differently from the recording. `offset` counts bytes of
+ * input or output from 0
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Divergence {
+    Output { executed:u64, offset:u64, expected:Option<u8>, found:u8 },   // `None` if the recording had ended
+    Input { executed:u64, offset:u64, expected:u64 },    // read at a different instruction count
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::Output { executed, offset, expected: Some(expected), found } => {
+                write!(f, "output byte {} differs at #{}: expected {:?}, got {:?}", offset, executed, *expected as char, *found as char)
+            },
+            Divergence::Output { executed, offset, expected: None, found } => {
+                write!(f, "output byte {} ({:?}) at #{} is past the end of the recording", offset, *found as char, executed)
+            },
+            Divergence::Input { executed, offset, expected } => {
+                write!(f, "input byte {} was read at #{}, but at #{} in the recording", offset, executed, expected)
+            },
+        }
+    }
+}
+
+/**
+ * Feeds the guest the input from a recording and checks that it prints the same output
+ */
+#[derive(Debug, Clone)]
+pub struct Replay {
+    inputs:VecDeque<(u64, u8)>,
+    outputs:VecDeque<(u64, u8)>,
+    read:u64,       // input bytes fed so far
+    written:u64,    // output bytes checked so far
+    divergence:Option<Divergence>,
+    reported:bool,  // the divergence has stopped the guest, so it doesn't again
+}
+
+impl Replay {
+    pub fn from_bytes(bytes:&[u8]) -> Result<Replay, FormatError> {
+        if bytes.len() < MAGIC.len() + 2 {
+            return Err(FORMAT.error(if MAGIC.starts_with(bytes) { FormatErrorKind::Truncated } else { FormatErrorKind::BadMagic }));
+        }
+        if &bytes[..MAGIC.len()] != MAGIC {
+            return Err(FORMAT.error(FormatErrorKind::BadMagic));
+        }
+        let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+        if version == 0 || version > VERSION {
+            return Err(FORMAT.error(FormatErrorKind::UnsupportedVersion(version)));
+        }
+
+        let events = &bytes[MAGIC.len() + 2..];
+        if !events.len().is_multiple_of(EVENT_SIZE) {
+            return Err(FORMAT.error(FormatErrorKind::Truncated));
+        }
+        let mut replay = Replay {
+            inputs: VecDeque::new(),
+            outputs: VecDeque::new(),
+            read: 0,
+            written: 0,
+            divergence: None,
+            reported: false,
+        };
+        for event in events.chunks(EVENT_SIZE) {
+            let mut executed = [0u8; 8];
+            executed.copy_from_slice(&event[1..9]);
+            let executed = u64::from_le_bytes(executed);
+            match event[0] {
+                INPUT_TAG => replay.inputs.push_back((executed, event[9])),
+                OUTPUT_TAG => replay.outputs.push_back((executed, event[9])),
+                tag => return Err(FORMAT.error(FormatErrorKind::Corrupt(format!("unknown event {:#04X}", tag)))),
+            }
+        }
+        Ok(replay)
+    }
+
+    /**
+     * Input bytes still to be fed to the guest
+     */
+    pub fn remaining_input(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /**
+     * Output bytes the guest hasn't printed yet
+     */
+    pub fn remaining_output(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence
+    }
+
+    /**
+     * Whether everything was fed and printed the same as in the recording
+     */
+    pub fn matched(&self) -> bool {
+        self.divergence.is_none() && self.inputs.is_empty() && self.outputs.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        match self.divergence {
+            Some(divergence) => format!("replay diverged: {}", divergence),
+            None if self.outputs.is_empty() => format!("replay matched: {} bytes of input, {} bytes of output", self.read, self.written),
+            None => format!("replay ended early: the guest didn't print the last {} bytes of the recording", self.outputs.len()),
+        }
+    }
+
+    pub(crate) fn input(&mut self, executed:u64) -> Option<u8> {
+        let (expected, byte) = self.inputs.pop_front()?;
+        if expected != executed {
+            self.diverge(Divergence::Input { executed, offset: self.read, expected });
+        }
+        self.read += 1;
+        Some(byte)
+    }
+
+    pub(crate) fn output(&mut self, executed:u64, byte:u8) {
+        let expected = self.outputs.pop_front().map(|(_, expected)| expected);
+        if expected != Some(byte) {
+            self.diverge(Divergence::Output { executed, offset: self.written, expected, found: byte });
+        }
+        self.written += 1;
+    }
+
+    fn diverge(&mut self, divergence:Divergence) {
+        if self.divergence.is_none() {
+            self.divergence = Some(divergence);
+        }
+    }
+
+    /**
+     * The divergence, the first time it's asked for
+     */
+    pub(crate) fn take_divergence(&mut self) -> Option<Divergence> {
+        match self.divergence {
+            Some(divergence) if !self.reported => {
+                self.reported = true;
+                Some(divergence)
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+
+/**
+ * A line of an input script
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptLine {
+    Input(String),  // for the guest, without the newline
+    Directive { line:usize, command:String },   // a hypervisor command, `line` counts from 1
+}
+
+/**
+ * Input for the guest mixed with hypervisor commands. A line starting with `!` is a command,
+ * run when the guest starts reading the next line of input; every other line is fed to the
+ * guest. `!!` at the start of a line stands for a single `!` in guest input
+ *
+ * ```text
+ * take tablet
+ * !set r7 25734
+ * !save before-teleport
+ * use teleporter
+ * ```
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    lines:VecDeque<ScriptLine>,
+}
+
+impl Script {
+    pub fn parse(text:&str) -> Script {
+        let lines = text.lines().enumerate().map(|(i, line)| {
+            match line.strip_prefix('!') {
+                Some(rest) if rest.starts_with('!') => ScriptLine::Input(rest.to_string()),
+                Some(command) => ScriptLine::Directive { line: i + 1, command: command.trim().to_string() },
+                None => ScriptLine::Input(line.to_string()),
+            }
+        }).collect();
+        Script { lines }
+    }
+
+    /**
+     * Lines that haven't been used yet
+     */
+    pub fn remaining(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub(crate) fn next_line(&mut self) -> Option<ScriptLine> {
+        self.lines.pop_front()
+    }
+}
@@ -0,0 +1,337 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::constants::{TOM, NUM_REG};
+use crate::errors::{FileFormat, FormatError, FormatErrorKind};
+use crate::machine::Machine;
+
+/**
+ * Every snapshot starts with these bytes
+ */
+pub const MAGIC:&[u8; 8] = b"SYNSNAP\0";
+pub const VERSION:u16 = 2;
+const FORMAT:FileFormat = FileFormat::Snapshot;
+
+const SLOT_EXTENSION:&str = "snap";
+const FLAG_COMPRESSED:u16 = 1;
+const RUN_BIT:u16 = 0x8000;     // in a compressed memory token, set for a run of one repeated word
+const MAX_TOKEN_LENGTH:usize = 0x7FFF;
+const MIN_RUN_LENGTH:usize = 3;
+
+/**
+ * Everything needed to resume a machine exactly where it was, apart from its I/O.
+ *
+ * Version 2 of the format, all numbers little-endian:
+ *
+ * ```text
+ * magic           8 bytes, "SYNSNAP\0"
+ * version         u16
+ * flags           u16, bit 0 set if memory is compressed
+ * pc, status      u16 each
+ * registers       8 x u16
+ * executed        u64
+ * stack           u32 length, then that many u16, bottom first
+ * pending input   u32 length, then that many bytes
+ * memory          u32 length in bytes, then 32768 x u16 or the compressed form
+ * saved at        u64, seconds since the Unix epoch
+ * recent output   u32 length, then that many bytes
+ * ```
+ *
+ * Version 1 is the same without the last two fields
+ *
+ * Compressed memory is a list of tokens. A token with the top bit set is a run: the low 15 bits
+ * are a count and the next word is repeated that many times. Otherwise the token is a count of
+ * words that follow as they are
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub pc:u16,
+    pub status:u16,
+    pub registers:[u16; NUM_REG],
+    pub stack:Vec<u16>,
+    pub mem:Vec<u16>,
+    pub executed:u64,
+    pub pending_input:Vec<u8>,   // bytes queued for the guest that it hasn't read yet
+    pub saved_at:u64,           // seconds since the Unix epoch, 0 if unknown
+    pub recent_output:Vec<u8>,  // the last few lines the guest printed, to tell snapshots apart
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self, compress:bool) -> Vec<u8> {
+        let mut bytes:Vec<u8> = Vec::with_capacity(TOM * 2);
+        bytes.extend_from_slice(MAGIC);
+        put_u16(&mut bytes, VERSION);
+        put_u16(&mut bytes, if compress { FLAG_COMPRESSED } else { 0 });
+        put_u16(&mut bytes, self.pc);
+        put_u16(&mut bytes, self.status);
+        for reg in self.registers.iter() {
+            put_u16(&mut bytes, *reg);
+        }
+        bytes.extend_from_slice(&self.executed.to_le_bytes());
+
+        put_u32(&mut bytes, self.stack.len() as u32);
+        for word in self.stack.iter() {
+            put_u16(&mut bytes, *word);
+        }
+        put_u32(&mut bytes, self.pending_input.len() as u32);
+        bytes.extend_from_slice(&self.pending_input);
+
+        let mut mem:Vec<u8> = Vec::new();
+        let words = if compress { compress_words(&self.mem) } else { self.mem.clone() };
+        for word in words {
+            put_u16(&mut mem, word);
+        }
+        put_u32(&mut bytes, mem.len() as u32);
+        bytes.extend_from_slice(&mem);
+
+        bytes.extend_from_slice(&self.saved_at.to_le_bytes());
+        put_u32(&mut bytes, self.recent_output.len() as u32);
+        bytes.extend_from_slice(&self.recent_output);
+        bytes
+    }
+
+    /**
+     * Reads a snapshot written by `to_bytes`, or a JSON state file from before snapshots
+     * existed
+     */
+    pub fn from_bytes(bytes:&[u8]) -> Result<Snapshot, FormatError> {
+        if bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+            return from_json(bytes);
+        }
+
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(FORMAT.error(FormatErrorKind::BadMagic));
+        }
+        let version = reader.u16()?;
+        if version == 0 || version > VERSION {
+            return Err(FORMAT.error(FormatErrorKind::UnsupportedVersion(version)));
+        }
+        let flags = reader.u16()?;
+        let pc = reader.u16()?;
+        let status = reader.u16()?;
+        let mut registers = [0u16; NUM_REG];
+        for reg in registers.iter_mut() {
+            *reg = reader.u16()?;
+        }
+        let executed = reader.u64()?;
+
+        let stack_len = reader.u32()? as usize;
+        let stack = reader.take(stack_len.checked_mul(2).ok_or(FORMAT.error(FormatErrorKind::Truncated))?)?
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        let input_len = reader.u32()? as usize;
+        let pending_input = reader.take(input_len)?.to_vec();
+
+        let mem_len = reader.u32()? as usize;
+        if !mem_len.is_multiple_of(2) {
+            return Err(FORMAT.error(FormatErrorKind::Corrupt(format!("memory is {} bytes, which isn't a whole number of words", mem_len))));
+        }
+        let words:Vec<u16> = reader.take(mem_len)?
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        let mem = if flags & FLAG_COMPRESSED != 0 { decompress_words(&words)? } else { words };
+
+        let (saved_at, recent_output) = if version >= 2 {
+            let saved_at = reader.u64()?;
+            let output_len = reader.u32()? as usize;
+            (saved_at, reader.take(output_len)?.to_vec())
+        } else {
+            (0, vec![])
+        };
+
+        let snapshot = Snapshot { pc, status, registers, stack, mem, executed, pending_input, saved_at, recent_output };
+        snapshot.validate()?;
+        Ok(snapshot)
+    }
+
+    /**
+     * Only memory has a size to check. Registers and pc can hold any word a running machine
+     * can end up with, e.g. 0xFFFF from `rmem` of a data word
+     */
+    fn validate(&self) -> Result<(), FormatError> {
+        if self.mem.len() != TOM {
+            return Err(FORMAT.error(FormatErrorKind::Corrupt(format!("memory holds {} words instead of {}", self.mem.len(), TOM))));
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Migrates the JSON `state0.bin` written by older versions of the hypervisor
+ */
+fn from_json(bytes:&[u8]) -> Result<Snapshot, FormatError> {
+    let machine:Machine = serde_json::from_slice(bytes).map_err(|e| FORMAT.error(FormatErrorKind::Corrupt(e.to_string())))?;
+    let snapshot = Snapshot { saved_at: 0, ..machine.snapshot() };
+    snapshot.validate()?;
+    Ok(snapshot)
+}
+
+fn put_u16(bytes:&mut Vec<u8>, value:u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes:&mut Vec<u8>, value:u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+struct Reader<'a> {
+    bytes:&'a [u8],
+    position:usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len:usize) -> Result<&'a [u8], FormatError> {
+        let end = self.position.checked_add(len).ok_or(FORMAT.error(FormatErrorKind::Truncated))?;
+        let taken = self.bytes.get(self.position..end).ok_or(FORMAT.error(FormatErrorKind::Truncated))?;
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+fn compress_words(words:&[u16]) -> Vec<u16> {
+    let mut tokens:Vec<u16> = Vec::new();
+    let mut literals:Vec<u16> = Vec::new();
+    let mut n = 0;
+
+    while n < words.len() {
+        let run = words[n..].iter().take(MAX_TOKEN_LENGTH).take_while(|word| **word == words[n]).count();
+        if (run >= MIN_RUN_LENGTH || literals.len() == MAX_TOKEN_LENGTH) && !literals.is_empty() {
+            tokens.push(literals.len() as u16);
+            tokens.append(&mut literals);
+        }
+        if run >= MIN_RUN_LENGTH {
+            tokens.push(RUN_BIT | run as u16);
+            tokens.push(words[n]);
+            n += run;
+        } else {
+            literals.push(words[n]);
+            n += 1;
+        }
+    }
+    if !literals.is_empty() {
+        tokens.push(literals.len() as u16);
+        tokens.append(&mut literals);
+    }
+    tokens
+}
+
+fn decompress_words(tokens:&[u16]) -> Result<Vec<u16>, FormatError> {
+    let mut words:Vec<u16> = Vec::with_capacity(TOM);
+    let mut n = 0;
+
+    while n < tokens.len() {
+        let token = tokens[n];
+        let count = (token & !RUN_BIT) as usize;
+        if token & RUN_BIT != 0 {
+            let word = *tokens.get(n + 1).ok_or(FORMAT.error(FormatErrorKind::Truncated))?;
+            words.extend(std::iter::repeat_n(word, count));
+            n += 2;
+        } else {
+            let literals = tokens.get(n + 1..n + 1 + count).ok_or(FORMAT.error(FormatErrorKind::Truncated))?;
+            words.extend_from_slice(literals);
+            n += 1 + count;
+        }
+        if words.len() > TOM {
+            return Err(FORMAT.error(FormatErrorKind::Corrupt("memory is larger than 32768 words".to_string())));
+        }
+    }
+    Ok(words)
+}
+
+/**
+ * A directory of named snapshots, kept as `<name>.snap` files
+ */
+pub struct SlotStore {
+    dir:PathBuf,
+}
+
+impl SlotStore {
+    pub fn new<P:Into<PathBuf>>(dir:P) -> Self {
+        SlotStore { dir: dir.into() }
+    }
+
+    /**
+     * Saves `snapshot` as `name`, replacing any snapshot that already has that name
+     */
+    pub fn save(&self, name:&str, snapshot:&Snapshot) -> Result<(), FormatError> {
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.dir).map_err(|e| FORMAT.io(e))?;
+        fs::write(path, snapshot.to_bytes(true)).map_err(|e| FORMAT.io(e))?;
+        Ok(())
+    }
+
+    pub fn load(&self, name:&str) -> Result<Snapshot, FormatError> {
+        match fs::read(self.path(name)?) {
+            Ok(bytes) => Snapshot::from_bytes(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(FORMAT.error(FormatErrorKind::NotFound(name.to_string()))),
+            Err(e) => Err(FORMAT.io(e)),
+        }
+    }
+
+    pub fn delete(&self, name:&str) -> Result<(), FormatError> {
+        match fs::remove_file(self.path(name)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(FORMAT.error(FormatErrorKind::NotFound(name.to_string()))),
+            Err(e) => Err(FORMAT.io(e)),
+        }
+    }
+
+    /**
+     * Every snapshot in the directory with its name, oldest first. Files that can't be read
+     * as snapshots are left out
+     */
+    pub fn list(&self) -> Result<Vec<(String, Snapshot)>, FormatError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(FORMAT.io(e)),
+        };
+
+        let mut slots:Vec<(String, Snapshot)> = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| FORMAT.io(e))?.path();
+            let name = match (path.file_stem().and_then(|stem| stem.to_str()), path.extension()) {
+                (Some(name), Some(extension)) if extension == SLOT_EXTENSION => name.to_string(),
+                _ => continue,
+            };
+            if let Ok(snapshot) = fs::read(&path).map_err(|e| FORMAT.io(e)).and_then(|bytes| Snapshot::from_bytes(&bytes)) {
+                slots.push((name, snapshot));
+            }
+        }
+        slots.sort_by(|a, b| (a.1.saved_at, &a.0).cmp(&(b.1.saved_at, &b.0)));
+        Ok(slots)
+    }
+
+    /**
+     * Names can use letters, digits, `-`, `_` and `.`, so they can't point outside the directory
+     */
+    fn path(&self, name:&str) -> Result<PathBuf, FormatError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid {
+            return Err(FORMAT.error(FormatErrorKind::InvalidName(name.to_string())));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, SLOT_EXTENSION)))
+    }
+}
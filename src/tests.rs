@@ -1,21 +1,40 @@
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use crate::{Machine, TOM, NUM_REG};
-    use std::num::Wrapping;
+    use crate::{hypervisor_controller, Machine, StepOutcome, Error};
+    use crate::constants::{TOM, NUM_REG, RECENTMEMACCESS_WRITE_BIT};
+    use crate::console::{MemoryInput, MemoryOutput, ScriptedInput};
+    use crate::instruction::{decode, Instruction, Operand, OPCODES};
+    use crate::assembler::{assemble, assemble_to_bytes};
+    use crate::errors::{AsmError, AsmErrorKind};
+    use crate::disassembler::{disassemble, disassemble_with};
+    use crate::analysis::analyze;
+    use crate::debugger::{Access, Condition, RunMode, StopReason, WatchTarget};
+    use crate::errors::{FileFormat, FormatError, FormatErrorKind, LoadError};
+    use crate::snapshot::{Snapshot, SlotStore, MAGIC};
+    use crate::trace::{TraceFilter, TraceReader, TraceRecord, Tracer};
+    use crate::timeline::Timeline;
+    use crate::replay::{Divergence, Recorder, Replay};
+    use crate::script::Script;
+    use crate::loader::{parse_image, ImageFormat, LoadOptions};
+    use crate::utils::{format_timestamp, words_from_bytes};
 
     #[test]
     fn test_mem_rw() {
         let mut m0 = Machine::new();
 
-        assert_eq!((m0[(TOM - 1) as u16]), 0); // last u16 in memory
-        assert_eq!((m0[(0) as u16]), 0);
+        assert_eq!(m0.read_word((TOM - 1) as u16), Ok(0)); // last u16 in memory
+        assert_eq!(m0.read_word(0), Ok(0));
 
         m0.mem[TOM-1] = 0x0F0F;
-        m0.mem[0] = 0xAA00;
+        m0.mem[0] = 0x00AA;
 
-        assert_eq!((m0[(TOM - 1) as u16]), 0x0F0F);
-        assert_eq!((m0[(0) as u16]), 0xAA00);
+        assert_eq!(m0.read_word((TOM - 1) as u16), Ok(0x0F0F));
+        assert_eq!(m0.read_word(0), Ok(0x00AA));
+
+        m0.write_word(1, 0x1234).unwrap();
+        assert_eq!(m0.mem[1], 0x1234);
     }
 
     #[test]
@@ -40,317 +59,1273 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_mem_read_invalid() {
+    fn test_register_accessors() {
+        let mut m0 = Machine::new();
+        m0.set_register(7, 25734).unwrap();
+        assert_eq!(m0.register(7), Some(25734));
+        assert_eq!(m0.registers[7], 25734);
+        assert_eq!(m0.peek(0x8007), Ok(25734));
+        assert_eq!(m0.register(8), None);
+        assert_eq!(m0.set_register(8, 1), Err(Error::AddressOutOfRange(0x8008)));
+    }
+
+    #[test]
+    fn test_state_word_order() {
+        // saved states keep the old byte-swapped layout so existing state0.bin files still load
         let mut m0 = Machine::new();
-        assert_eq!((m0[(TOM) as u16]), 0);
+        m0.write_word(0, 0x0013).unwrap();
+        m0.set_register(1, 0x0102).unwrap();
+        let json = serde_json::to_string(&m0).unwrap();
+        assert!(json.contains("\"mem\":[4864,0,"));
+        assert!(json.contains("\"registers\":[0,513,0,"));
+
+        let m1:Machine = serde_json::from_str(&json).unwrap();
+        assert_eq!(m1.read_word(0), Ok(0x0013));
+        assert_eq!(m1.register(1), Some(0x0102));
+    }
+
+    #[test]
+    fn test_mem_read_invalid() {
+        let m0 = Machine::new();
+        assert_eq!(m0.read_word(TOM as u16), Err(Error::AddressOutOfRange(TOM as u16)));
     }
 
     #[test]
-    #[should_panic]
     fn test_mem_read_invalid_1() {
         let mut m0 = Machine::new();
-        assert_eq!((m0[(TOM+1) as u16]), 0);
+        assert_eq!(m0.read_word((TOM+1) as u16), Err(Error::AddressOutOfRange((TOM+1) as u16)));
+        assert_eq!(m0.write_word((TOM+1) as u16, 0), Err(Error::AddressOutOfRange((TOM+1) as u16)));
     }
 
     #[test]
     fn test_halt() {
         let mut m0 = Machine::new();
-        assert_eq!(m0.is_halted(), false);
+        assert!(!m0.is_halted());
         m0.halt();
-        assert_eq!(m0.is_halted(), true);
+        assert!(m0.is_halted());
     }
 
     #[test]
     fn test_halt_program() {
         let mut m0 = Machine::new();
         m0.mem[0] = 0x00;
-        assert_eq!(m0.is_halted(), false);
-        m0.fetch_and_execute();
-        assert_eq!(m0.is_halted(), true);
+        assert!(!m0.is_halted());
+        assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::Halted));
+        assert!(m0.is_halted());
     }
 
     #[test]
-    #[should_panic]
     fn test_halt_program_invalid() {
         let mut m0 = Machine::new();
-        m0.mem[0] = 0x00FF; // unknown opcode
-        m0.mem[1] = 0xFF00;
+        m0.mem[0] = 0xFF00; // unknown opcode
+        m0.mem[1] = 0x00FF;
         m0.mem[2] = 0x0000;
-        assert_eq!(m0.is_halted(), false);
-        m0.fetch_and_execute();
-        assert_eq!(m0.is_halted(), false);
-        m0.fetch_and_execute();
-        assert_eq!(m0.is_halted(), false);
-        m0.fetch_and_execute();
-        assert_eq!(m0.is_halted(), true);
+        assert!(!m0.is_halted());
+        assert_eq!(m0.fetch_and_execute(), Err(Error::UnknownOpcode { pc: 0, opcode: 0xFF00 }));
+        assert!(!m0.is_halted());
+    }
+
+    #[test]
+    fn test_pop_empty_stack() {
+        let prog:[u16; 3] = [ 0x0003, 0x8000, 0x0000 ];
+        //                       POP       a    HALT
+        let mut m0 = Machine::new();
+        m0.mem[..3].copy_from_slice(&prog);
+        assert_eq!(m0.run(), Err(Error::EmptyStack { pc: 0, opcode: 3 }));
+    }
+
+    #[test]
+    fn test_invalid_operand() {
+        let prog:[u16; 5] = [ 0x0015, 0x0001, 0x8010, 0x0005, 0x0000 ];
+        //                       NOP     SET  0x8010  0x0005    HALT
+        let mut m0 = Machine::new();
+        m0.mem[..5].copy_from_slice(&prog);
+        assert_eq!(m0.run(), Err(Error::MemoryInvalid { pc: 1, opcode: 1, operand: 0x8010 }));
+    }
+
+    #[test]
+    fn test_mod_by_zero() {
+        let prog:[u16; 5] = [ 0x000B, 0x8000, 0x00FF, 0x0000, 0x0000 ];
+        //                       MOD       a  0x00FF  0x0000    HALT
+        let mut m0 = Machine::new();
+        m0.mem[..5].copy_from_slice(&prog);
+        assert_eq!(m0.run(), Err(Error::DivideByZero { pc: 0, opcode: 0x0B }));
+    }
+
+    #[test]
+    fn test_pc_out_of_range() {
+        let prog:[u16; 2] = [ 0x0006, 0x7FFF ];
+        //                       JMP  0x7FFF
+        let mut m0 = Machine::new();
+        m0.mem[..2].copy_from_slice(&prog);
+        m0.mem[TOM - 1] = 0x0015; // NOP, then fall off the top of memory
+        assert_eq!(m0.run(), Err(Error::MemoryInvalid { pc: 0x8000, opcode: 0, operand: 0x8000 }));
     }
 
     #[test]
     fn test_add() {
-        let mut prog:[u16; 7] = [ 0x0900, 0x0080, 0x0180, 0x0400, 0x1300, 0x0080, 0x0000 ];
+        let prog:[u16; 7] = [ 0x0009, 0x8000, 0x8001, 0x0004, 0x0013, 0x8000, 0x0000 ];
         //                       add       a     (<b> +   4)     out     <a>         HLT
         let mut m0 = Machine::new();
-        for n in 0..7 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 4);
+        m0.mem[..7].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 4);
     }
 
 /*    #[test]
     fn test_add_2() {
-        let mut prog:[u16; 7] = [ 0x0900, 0xFF79, 0x0800, 0x0400, 0x1300, 0x0080, 0x0000 ];
+        let mut prog:[u16; 7] = [ 0x0009, 0x79FF, 0x0008, 0x0004, 0x0013, 0x8000, 0x0000 ];
         //                           add  0x79FF   (8 +   4)        out   0x8000   HLT
         rmem 0xFF79
         let mut m0 = Machine::new();
-        for n in 0..6 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x79FF), 12);
+        m0.mem[..6].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x79FF).unwrap(), 12);
     }
  */
 
     #[test]
     fn test_example_program_2() {
-        let prog:[u16; 4] = [ 0x1300, 0x0300, 0x0000, 0x4100 ];
-        // OUT <0x0003> HLT 'A'
-        let mut m0 = Machine::new();
-        for n in 0..4 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        // TODO: assert that stdout == 'A' somehow
+        let prog:[u16; 4] = [ 0x0013, 0x0041, 0x0000, 0x0041 ];
+        // OUT 'A' HLT 'A'
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(output.clone()));
+        m0.mem[..4].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(output.contents(), "A");
     }
 
     #[test]
     fn test_set() {
-        let prog:[u16; 4] = [ 0x0100, 0x0480, 0xFF00, 0x0000 ];
+        let prog:[u16; 4] = [ 0x0001, 0x8004, 0x00FF, 0x0000 ];
         // SET e 0x00FF HLT
         let mut m0 = Machine::new();
-        for n in 0..4 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8004), 0x00FF);
-        assert_eq!(m0.registers[4], 0xFF00);
+        m0.mem[..4].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8004).unwrap(), 0x00FF);
+        assert_eq!(m0.registers[4], 0x00FF);
     }
 
     #[test]
     fn test_push() {
-        let prog:[u16; 8] = [ 0x0200, 0xAA00, 0x0200, 0xFF00, 0x0100, 0x0080, 0xCC00, 0x0000 ];
+        let prog:[u16; 8] = [ 0x0002, 0x00AA, 0x0002, 0x00FF, 0x0001, 0x8000, 0x00CC, 0x0000 ];
         //                      PUSH  0x00AA    PUSH  0x00FF     SET       a  0x00CC    HALT
         let mut m0 = Machine::new();
-        for n in 0..8 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
+        m0.mem[..8].copy_from_slice(&prog);
+        m0.run().unwrap();
         assert_eq!(m0.stack[0], 0x00AA);
         assert_eq!(m0.stack[1], 0x00FF);
-        assert_eq!(m0.peek(0x8000), 0x00CC);
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x00CC);
     }
 
     #[test]
     fn test_push_pop() {
-        let prog:[u16; 9] = [ 0x0200, 0xAA00, 0x0200, 0xFF00, 0x0300, 0x0080, 0x0300, 0x0001, 0x0000 ];
+        let prog:[u16; 9] = [ 0x0002, 0x00AA, 0x0002, 0x00FF, 0x0003, 0x8000, 0x0003, 0x0100, 0x0000 ];
         //                      PUSH  0x00AA    PUSH  0x00FF     POP  0x8000     POP  0x0100    HALT
         let mut m0 = Machine::new();
-        for n in 0..9 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x00FF);
-        assert_eq!(m0.peek(0x0100), 0x00AA);
+        m0.mem[..9].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x00FF);
+        assert_eq!(m0.peek(0x0100).unwrap(), 0x00AA);
     }
 
     #[test]
     fn test_eq() {
-        let prog:[u16; 12] = [ 0x0100, 0x0080, 0xFF00, 0x0400, 0x0001, 0xAA00, 0xAA00, 0x0400, 0x0080, 0xAA00, 0xAB00, 0x0000 ];
+        let prog:[u16; 12] = [ 0x0001, 0x8000, 0x00FF, 0x0004, 0x0100, 0x00AA, 0x00AA, 0x0004, 0x8000, 0x00AA, 0x00AB, 0x0000 ];
         //                       SET       A   0x00FF      EQ  0x0100  0x00AA  0x00AA      EQ       A  0x00AA  0x00AB,   HALT
         let mut m0 = Machine::new();
-        for n in 0..12 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x0100), 0x0001);
-        assert_eq!(m0.peek(0x8000), 0x0000);
+        m0.mem[..12].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x0100).unwrap(), 0x0001);
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x0000);
     }
 
     #[test]
     fn test_gt() {
-        let prog:[u16; 12] = [ 0x0100, 0x0080, 0xFFFF, 0x0500, 0x0001, 0xAAAA, 0xAAAA, 0x0500, 0x0080, 0xAAAA, 0xAAA9, 0x0000 ];
-        //                       SET       A   0xFFFF      LT  0x0100  0xAAAA  0xAAAA      LT       A  0xAAAA  0xA9AA,   HALT
+        let prog:[u16; 12] = [ 0x0001, 0x8000, 0x7FFF, 0x0005, 0x0100, 0x2AAA, 0x2AAA, 0x0005, 0x8000, 0x2AAA, 0x2AA9, 0x0000 ];
+        //                       SET       A   0x7FFF      GT  0x0100  0x2AAA  0x2AAA      GT       A  0x2AAA  0x2AA9,   HALT
         let mut m0 = Machine::new();
-        for n in 0..12 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x0100), 0x0000);
-        assert_eq!(m0.peek(0x8000), 0x0001);
+        m0.mem[..12].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x0100).unwrap(), 0x0000);
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x0001);
     }
 
     #[test]
     fn test_jmp() {
-        let prog:[u16; 7] = [ 0x0600, 0x0300, 0x0000, 0x0100, 0x0080, 0xFFFF, 0x0000 ];
-        //                       JMP  0x0003    HALT     SET       A  0xFFFF    HALT
+        let prog:[u16; 7] = [ 0x0006, 0x0003, 0x0000, 0x0001, 0x8000, 0x7FFF, 0x0000 ];
+        //                       JMP  0x0003    HALT     SET       A  0x7FFF    HALT
         let mut m0 = Machine::new();
-        for n in 0..7 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0xFFFF);
+        m0.mem[..7].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x7FFF);
     }
 
     #[test]
     fn test_jt() {
-        let prog:[u16; 13] = [ 0x0700, 0x0100, 0x0600, 0x0100, 0x0080, 0xFFFF, 0x0700, 0x0000, 0x0C00, 0x0100, 0x0180, 0xFFFF, 0x0000 ];
-        //                        JT  0x0001  0x0006     SET       a  0xFFFF      JT  0x0000   0x000C     SET       b  0xFFFF    HALT
+        let prog:[u16; 13] = [ 0x0007, 0x0001, 0x0006, 0x0001, 0x8000, 0x7FFF, 0x0007, 0x0000, 0x000C, 0x0001, 0x8001, 0x7FFF, 0x0000 ];
+        //                        JT  0x0001  0x0006     SET       a  0x7FFF      JT  0x0000   0x000C     SET       b  0x7FFF    HALT
         let mut m0 = Machine::new();
-        for n in 0..13 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x0000);
-        assert_eq!(m0.peek(0x8001), 0xFFFF);
+        m0.mem[..13].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x0000);
+        assert_eq!(m0.peek(0x8001).unwrap(), 0x7FFF);
     }
 
     #[test]
     fn test_jf() {
-        let prog:[u16; 13] = [ 0x0800, 0x0000, 0x0600, 0x0100, 0x0080, 0xFFFF, 0x0800, 0x0100, 0x0C00, 0x0100, 0x0180, 0xFFFF, 0x0000 ];
-        //                        JF  0x0000  0x0006     SET       a  0xFFFF       JF  0x0001  0x000C     SET       b  0xFFFF    HALT
+        let prog:[u16; 13] = [ 0x0008, 0x0000, 0x0006, 0x0001, 0x8000, 0x7FFF, 0x0008, 0x0001, 0x000C, 0x0001, 0x8001, 0x7FFF, 0x0000 ];
+        //                        JF  0x0000  0x0006     SET       a  0x7FFF       JF  0x0001  0x000C     SET       b  0x7FFF    HALT
         let mut m0 = Machine::new();
-        for n in 0..13 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x0000);
-        assert_eq!(m0.peek(0x8001), 0xFFFF);
+        m0.mem[..13].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x0000);
+        assert_eq!(m0.peek(0x8001).unwrap(), 0x7FFF);
     }
 
     #[test]
     fn test_mult() {
-        let mut prog:[u16; 8] = [ 0x0100, 0x0080, 0xFF00, 0x0A00, 0x0180, 0x0080, 0x0400, 0x0000 ];
+        let prog:[u16; 8] = [ 0x0001, 0x8000, 0x00FF, 0x000A, 0x8001, 0x8000, 0x0004, 0x0000 ];
         //                           SET       a  0x00FF    MULT       b       a  0x0004    HALT
         let mut m0 = Machine::new();
-        for n in 0..8 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8001), 0x00FF * 4);
+        m0.mem[..8].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8001).unwrap(), 0x00FF * 4);
     }
 
     #[test]
     fn test_mod() {
-        let mut prog:[u16; 5] = [ 0x0B00, 0x0080, 0xFF00, 0x0A00, 0x0000 ];
+        let prog:[u16; 5] = [ 0x000B, 0x8000, 0x00FF, 0x000A, 0x0000 ];
         //                           MOD       a  0x00FF  0x000A    HALT
 
         let mut m0 = Machine::new();
-        for n in 0..5 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 5);
+        m0.mem[..5].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 5);
     }
 
     #[test]
     fn test_and() {
-        let mut prog:[u16; 5] = [ 0x0C00, 0x0080, 0xAA00, 0xDEDE, 0x0000 ];
-        //                           AND       a  0x00AA  0xDEDE    HALT
+        let prog:[u16; 5] = [ 0x000C, 0x8000, 0x00AA, 0x5EDE, 0x0000 ];
+        //                           AND       a  0x00AA  0x5EDE    HALT
         let mut m0 = Machine::new();
-        for n in 0..5 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x00AA & (0xDEDE % TOM as u16));
+        m0.mem[..5].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x00AA & 0x5EDE);
     }
 
     #[test]
     fn test_or() {
-        let mut prog:[u16; 5] = [ 0x0D00, 0x0080, 0xAA00, 0xDE00, 0x0000 ];
+        let prog:[u16; 5] = [ 0x000D, 0x8000, 0x00AA, 0x00DE, 0x0000 ];
         //                            OR       a  0x00AA  0x00DE    HALT
         let mut m0 = Machine::new();
-        for n in 0..5 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 254);
+        m0.mem[..5].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 254);
     }
 
     #[test]
     fn test_not() {
-        let mut prog:[u16; 4] = [ 0x0E00, 0x0080, 0xAA00, 0x0000 ];
+        let prog:[u16; 4] = [ 0x000E, 0x8000, 0x00AA, 0x0000 ];
         //                           NOT       a  0x00AA    HALT
         let mut m0 = Machine::new();
-        for n in 0..4 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), (0xFF00 | 0x0055) % TOM as u16);
+        m0.mem[..4].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), (0xFF00 | 0x0055) % TOM as u16);
     }
 
 
     #[test]
     fn test_rmem() {
-        let mut prog:[u16; 5] = [ 0x0F00, 0x0080, 0x0400, 0x0000, 0xFF00 ];
+        let prog:[u16; 5] = [ 0x000F, 0x8000, 0x0004, 0x0000, 0x00FF ];
         //                          RMEM       a  0x0004    HALT  0x00FF
         let mut m0 = Machine::new();
-        for n in 0..5 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x00FF);
+        m0.mem[..5].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x00FF);
     }
 
 
     #[test]
     fn test_wmem() {
-        let mut prog:[u16; 4] = [ 0x1000, 0x0400, 0xFF00, 0x0000 ];
+        let prog:[u16; 4] = [ 0x0010, 0x0004, 0x00FF, 0x0000 ];
         //                          WMEM  0x0004  0x00FF    HALT
         let mut m0 = Machine::new();
-        for n in 0..4 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x0004), 0x00FF);
+        m0.mem[..4].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x0004).unwrap(), 0x00FF);
     }
 
     #[test]
     fn test_call() {
-        let mut prog:[u16; 10] = [ 0x1100, 0x0600, 0x0100, 0x0180, 0xAA00, 0x0000, 0x0100, 0x0080, 0xFF00, 0x0000 ];
+        let prog:[u16; 10] = [ 0x0011, 0x0006, 0x0001, 0x8001, 0x00AA, 0x0000, 0x0001, 0x8000, 0x00FF, 0x0000 ];
         //                           CALL  0x0006     SET       b  0x00AA    HALT     SET       a  0x00FF    HALT
         let mut m0 = Machine::new();
-        for n in 0..10 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x00FF);
-        assert_eq!(m0.peek(0x8001), 0x0000);
+        m0.mem[..10].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x00FF);
+        assert_eq!(m0.peek(0x8001).unwrap(), 0x0000);
     }
 
     #[test]
     fn test_ret() {
-        let mut prog:[u16; 11] = [ 0x1100, 0x0600, 0x0100, 0x0180, 0xAA00, 0x0000, 0x0100, 0x0080, 0xFF00, 0x1200, 0x0000 ];
+        let prog:[u16; 11] = [ 0x0011, 0x0006, 0x0001, 0x8001, 0x00AA, 0x0000, 0x0001, 0x8000, 0x00FF, 0x0012, 0x0000 ];
         //                           CALL  0x0006     SET       b  0x00AA    HALT     SET       a  0x00FF     RET    HALT
         let mut m0 = Machine::new();
-        for n in 0..11 {
-            m0.mem[n] = prog[n];
-        }
-        m0.run();
-        assert_eq!(m0.peek(0x8000), 0x00FF);
-        assert_eq!(m0.peek(0x8001), 0x00AA);
+        m0.mem[..11].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 0x00FF);
+        assert_eq!(m0.peek(0x8001).unwrap(), 0x00AA);
     }
 
-/*
     #[test]
     fn test_in() {
-        let mut prog:[u16; 3] = [ 0x1300, 0x0080, 0x0000 ];
-        //                            IN       a    HALT
+        let prog:[u16; 7] = [ 0x0014, 0x8000, 0x0014, 0x8001, 0x0013, 0x8001, 0x0000 ];
+        //                        IN       a      IN       b     OUT       b    HALT
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"hi")), Box::new(output.clone()));
+        m0.mem[..7].copy_from_slice(&prog);
+        m0.run().unwrap();
+        assert_eq!(m0.peek(0x8000).unwrap(), 'h' as u16);
+        assert_eq!(m0.peek(0x8001).unwrap(), 'i' as u16);
+        assert_eq!(output.contents(), "i");
+    }
+
+    #[test]
+    fn test_in_scripted() {
+        let prog:[u16; 7] = [ 0x0014, 0x8000, 0x0013, 0x8000, 0x0006, 0x0000, 0x0000 ];
+        //                        IN       a     OUT       a     JMP  0x0000    HALT
+        let input = ScriptedInput::new();
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(input.clone()), Box::new(output.clone()));
+        m0.mem[..7].copy_from_slice(&prog);
+
+        input.push_line(".look");
+        for _ in 0..18 {
+            assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::Running));
+        }
+        assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::WaitingForInput));
+
+        input.push_line("!");
+        assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(input.pending(), 0);
+        // '.' only escapes to the hypervisor on an interactive source
+        assert_eq!(output.contents(), ".look\n!\n");
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let words = assemble("
+                    set r0 5
+                    push r0
+                    add r1 r0 r0
+                    halt
+        ").unwrap();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        for (addr, word) in words.iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        for _ in 0..3 {
+            assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::Running));
+        }
+
+        let path = std::env::temp_dir().join(format!("synacor_state_{}.json", std::process::id()));
+        m0.save_state(&path).unwrap();
+        let mut m1 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        m1.load_state(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(m1.mem, m0.mem);
+        assert_eq!(m1.registers, m0.registers);
+        assert_eq!(m1.stack, vec![5]);
+        assert_eq!(m1.pc, m0.pc);
+        assert_eq!(m1.executed(), 3);
+        assert_eq!(m1.run(), Ok(StepOutcome::Halted));
+        assert!(m1.load_state("does/not/exist.json").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_of_wide_values() {
+        // `rmem` loads 0xFFFF into r0, then jumping to it faults with pc left on 0xFFFF
+        let mut m0 = machine_with("rmem r0 data\njmp r0\ndata: .data 0xFFFF");
+        assert!(m0.run().is_err());
+        assert_eq!((m0.register(0), m0.pc()), (Some(0xFFFF), 0xFFFF));
+
+        let snapshot = m0.snapshot();
+        for compress in [false, true].iter() {
+            assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes(*compress)).as_ref(), Ok(&snapshot));
+        }
+        let mut m1 = machine_with("halt");
+        m1.restore(&snapshot);
+        assert_eq!((m1.register(0), m1.pc()), (Some(0xFFFF), 0xFFFF));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let words = assemble("
+                    set r0 5
+                    push r0
+                    push 7
+                    in r2
+                    out r2
+                    halt
+        ").unwrap();
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(output.clone()));
+        for (addr, word) in words.iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        m0.write_word(0x7000, 0x1234).unwrap();
+        assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+        m0.queue_input(b"x");
+        let snapshot = m0.snapshot();
+        assert_eq!(snapshot.stack, vec![5, 7]);
+        assert_eq!(snapshot.executed, 3);
+
+        let compressed = snapshot.to_bytes(true);
+        let uncompressed = snapshot.to_bytes(false);
+        assert!(compressed.starts_with(MAGIC));
+        assert!(compressed.len() < 200);
+        assert!(uncompressed.len() > 2 * TOM);
+        assert_eq!(Snapshot::from_bytes(&compressed).as_ref(), Ok(&snapshot));
+        assert_eq!(Snapshot::from_bytes(&uncompressed).as_ref(), Ok(&snapshot));
+
+        // the restored machine picks up right where the first one left off, queued input included
+        let output1 = MemoryOutput::new();
+        let mut m1 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(output1.clone()));
+        m1.restore(&Snapshot::from_bytes(&compressed).unwrap());
+        assert_eq!(m1.run(), Ok(StepOutcome::Halted));
+        assert_eq!(output1.contents(), "x");
+        assert_eq!(m1.register(2), Some('x' as u16));
+        assert_eq!(m1.read_word(0x7000), Ok(0x1234));
+
+        let mut bad = compressed.clone();
+        bad[0] = b'X';
+        assert_eq!(Snapshot::from_bytes(&bad), Err(FileFormat::Snapshot.error(FormatErrorKind::BadMagic)));
+        let mut bad = compressed.clone();
+        bad[8] = 3;
+        assert_eq!(Snapshot::from_bytes(&bad), Err(FileFormat::Snapshot.error(FormatErrorKind::UnsupportedVersion(3))));
+        assert_eq!(Snapshot::from_bytes(&compressed[..compressed.len() - 1]), Err(FileFormat::Snapshot.error(FormatErrorKind::Truncated)));
+        let mut bad = uncompressed.clone();
+        let mem_len = MAGIC.len() + 2 * (4 + NUM_REG) + 8 + 4 + 2 * 2 + 4 + 1;    // where it is, after the stack and the queued `x`
+        bad[mem_len] ^= 1;  // an odd number of bytes
+        assert!(matches!(Snapshot::from_bytes(&bad), Err(FormatError { format: FileFormat::Snapshot, kind: FormatErrorKind::Corrupt(_) })));
+
+        // JSON state files from older versions still load, minus the queued input they never had
+        let json = serde_json::to_string(&m0).unwrap();
+        let migrated = Snapshot::from_bytes(json.as_bytes()).unwrap();
+        assert_eq!(migrated, Snapshot { pending_input: vec![], saved_at: 0, recent_output: vec![], ..snapshot.clone() });
+
+        // version 1 didn't have the time and output at the end
+        let mut v1 = compressed[..compressed.len() - 12].to_vec();
+        v1[8] = 1;
+        assert_eq!(Snapshot::from_bytes(&v1), Ok(Snapshot { saved_at: 0, ..snapshot }));
+    }
+
+    #[test]
+    fn test_save_slots() {
+        let words = assemble("
+                    out 'a'
+                    out 10
+                    out 'b'
+                    out 10
+                    in r0
+                    halt
+        ").unwrap();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        for (addr, word) in words.iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(m0.recent_output(), b"a\nb\n");
+
+        let snapshot = m0.snapshot();
+        assert_eq!(snapshot.recent_output, b"a\nb\n");
+        assert!(snapshot.saved_at > 0);
+
+        let dir = std::env::temp_dir().join(format!("synacor_slots_{}", std::process::id()));
+        let store = SlotStore::new(&dir);
+        assert_eq!(store.list(), Ok(vec![]));   // the directory doesn't exist yet
+        store.save("before-input", &snapshot).unwrap();
+        store.save("older", &Snapshot { saved_at: 1, ..snapshot.clone() }).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a snapshot").unwrap();
+
+        let names:Vec<String> = store.list().unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["older", "before-input"]);
+        assert_eq!(store.load("before-input"), Ok(snapshot.clone()));
+        assert_eq!(store.load("missing"), Err(FileFormat::Snapshot.error(FormatErrorKind::NotFound("missing".to_string()))));
+        assert_eq!(store.save("../escape", &snapshot), Err(FileFormat::Snapshot.error(FormatErrorKind::InvalidName("../escape".to_string()))));
+
+        store.delete("older").unwrap();
+        assert_eq!(store.delete("older"), Err(FileFormat::Snapshot.error(FormatErrorKind::NotFound("older".to_string()))));
+        assert_eq!(store.list().unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the output comes back with the snapshot
+        let mut m1 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        m1.restore(&snapshot);
+        assert_eq!(m1.recent_output(), b"a\nb\n");
+
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(1709999142), "2024-03-09 15:45:42");
+    }
+
+    #[test]
+    fn test_trace() {
+        #[derive(Clone, Default)]
+        struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl std::io::Write for SharedBuffer {
+            fn write(&mut self, buf:&[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let words = assemble("set r0 'A'\nout r0\nhalt").unwrap();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        for (addr, word) in words.iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        let trace = SharedBuffer::default();
+        m0.set_trace(Some(Box::new(trace.clone())));
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(String::from_utf8(trace.0.borrow().clone()).unwrap(), "0x0000:\tset\tr0\t0x0041\n0x0003:\tout\tr0\n0x0005:\thalt\n");
+    }
+
+    #[test]
+    fn test_trace_recorder() {
+        let source = "set r1 0x100\nwmem r1 7\ncall sub\nhalt\nsub: add r0 r0 1\nret";
+        let mut m0 = machine_with(source);
+        m0.set_tracer(Some(Tracer::ring(16)));
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        let records:Vec<TraceRecord> = m0.tracer().unwrap().records().into_iter().copied().collect();
+        assert_eq!(records.iter().map(|record| record.pc).collect::<Vec<u16>>(), vec![0, 3, 6, 9, 13, 8]);
+        assert_eq!((records[1].values, records[1].write), ([0x100, 7, 0], Some((0x100, 7))));
+        assert_eq!((records[3].executed, records[3].values, records[3].write), (3, [0, 0, 1], Some((TOM as u16, 1))));
+        assert_eq!(records[3].to_string(), "         3  0x0009:\tadd\tr0\tr0\t0x0001\t; r0=0x0000 r0=0x0000 r0<-0x0001");
+        assert_eq!(records[5].to_string(), "         5  0x0008:\thalt");
+
+        // the ring buffer keeps the latest records
+        let mut m0 = machine_with(source);
+        m0.set_tracer(Some(Tracer::ring(2)));
+        m0.run().unwrap();
+        assert_eq!(m0.tracer().unwrap().records().iter().map(|record| record.pc).collect::<Vec<u16>>(), vec![13, 8]);
+
+        // filters
+        let mut filter = TraceFilter::default();
+        filter.add("addr", "0-8").unwrap();
+        filter.add("window", "1-").unwrap();
+        assert_eq!(filter.to_string(), "addr 0000-0008, window 1-");
+        let mut m0 = machine_with(source);
+        m0.set_tracer(Some(Tracer::ring(16).with_filter(filter.clone())));
+        m0.run().unwrap();
+        assert_eq!(m0.tracer().unwrap().records().iter().map(|record| record.pc).collect::<Vec<u16>>(), vec![3, 6, 8]);
+        filter.add("op", "call").unwrap();
+        assert!(filter.matches(2, 6, Instruction::Call(Operand::Literal(9)).opcode()));
+        assert!(!filter.matches(1, 3, Instruction::Wmem(Operand::Register(1), Operand::Literal(7)).opcode()));
+        assert!(filter.add("op", "jump").is_err());
+        assert!(filter.add("addr", "8-0").is_err());
+        assert!(filter.add("window", "10").is_err());
+        assert!(filter.add("size", "10").is_err());
+
+        // a binary log reads back as the same records
+        let path = std::env::temp_dir().join(format!("synacor_trace_{}.log", std::process::id()));
+        let mut m0 = machine_with(source);
+        m0.set_tracer(Some(Tracer::binary(Box::new(std::fs::File::create(&path).unwrap())).unwrap()));
+        m0.run().unwrap();
+        m0.set_tracer(None);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let read:Vec<TraceRecord> = TraceReader::new(&bytes[..]).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, records);
+
+        assert_eq!(TraceReader::new(&bytes[..bytes.len() - 1]).unwrap().last(), Some(Err(FileFormat::TraceLog.error(FormatErrorKind::Truncated))));
+        assert!(matches!(TraceReader::new(&b"SYNSNAP\0\x01\x00"[..]), Err(FormatError { format: FileFormat::TraceLog, kind: FormatErrorKind::BadMagic })));
+        assert!(matches!(TraceReader::new(&b"SYNTRACE\x02\x00"[..]), Err(FormatError { format: FileFormat::TraceLog, kind: FormatErrorKind::UnsupportedVersion(2) })));
+    }
+
+    #[test]
+    fn test_load_image() {
+        // the example program from arch-spec in every format
+        let expected:Vec<u16> = vec![9, 32768, 32769, 4, 19, 32768];
+        let binary:&[u8] = &[0x09, 0x00, 0x00, 0x80, 0x01, 0x80, 0x04, 0x00, 0x13, 0x00, 0x00, 0x80];
+        let decimal:&[u8] = b"9,32768,32769,4,19,32768\n";
+        let intel_hex:&[u8] = b":0C00000009000080018004001300008053\n:00000001FF\n";
+
+        assert_eq!(ImageFormat::detect(binary), ImageFormat::Binary);
+        assert_eq!(ImageFormat::detect(decimal), ImageFormat::Decimal);
+        assert_eq!(ImageFormat::detect(intel_hex), ImageFormat::IntelHex);
+        for image in [binary, decimal, intel_hex].iter() {
+            assert_eq!(parse_image(image, &LoadOptions::default()).unwrap(), expected);
+        }
+
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        m0.registers[0] = 1;
+        m0.load_image(decimal).unwrap();
+        assert_eq!(m0.read_word(1), Ok(32768));
+        assert_eq!(m0.peek(0x8000), Ok(0));
+        assert_eq!(m0.pc, 0);
+
+        let strict = LoadOptions { format: None, strict: true };
+        assert_eq!(parse_image(&binary[..3], &LoadOptions::default()), Err(LoadError::OddLength(3)));
+        assert_eq!(parse_image(&vec![0u8; (TOM + 1) * 2], &LoadOptions::default()), Err(LoadError::TooLarge(TOM + 1)));
+        assert_eq!(parse_image(b"1, 2, 65535", &LoadOptions::default()), Ok(vec![1, 2, 65535]));
+        assert_eq!(parse_image(b"1, 2, 65535", &strict), Err(LoadError::InvalidWord { addr: 2, value: 65535 }));
+        assert_eq!(parse_image(b"1,\n2,70000", &strict), Err(LoadError::Syntax { line: 2, message: "`70000` isn't a word (0..65535)".to_string() }));
+        assert!(matches!(parse_image(b":0C00000009000080018004001300008054\n", &strict), Err(LoadError::Syntax { line: 1, .. })));
+        assert_eq!(parse_image(b":0100000200FD\n", &strict), Err(LoadError::Syntax { line: 1, message: "a type 02 record holds 2 bytes, not 1".to_string() }));
+        assert_eq!(parse_image(b":0100000600F9\n", &strict), Err(LoadError::Syntax { line: 1, message: "unsupported record type".to_string() }));
+
+        // a failed load leaves the machine alone
+        assert!(m0.load_image_with(b"1,2,40000", &strict).is_err());
+        assert_eq!(m0.read_word(1), Ok(32768));
+    }
+
+    #[test]
+    fn test_decode_sizes() {
+        // sizes straight from the opcode listing in arch-spec
+        let sizes:[u16; 22] = [ 1, 3, 2, 2, 4, 4, 2, 3, 3, 4, 4, 4, 4, 4, 3, 3, 3, 2, 1, 2, 2, 1 ];
+        for opcode in 0..22 {
+            let mem:[u16; 4] = [ opcode, 0x8000, 1, 2 ];
+            let (instruction, size) = decode(&mem, 0).unwrap();
+            assert_eq!(size, sizes[opcode as usize]);
+            assert_eq!(instruction.size(), size);
+            assert_eq!(instruction.opcode(), opcode);
+            assert_eq!(instruction.mnemonic(), OPCODES[opcode as usize].0);
+            assert_eq!(instruction.encode(), mem[..size as usize].to_vec());
+        }
+    }
+
+    #[test]
+    fn test_decode_operands() {
+        let mem:[u16; 4] = [ 9, 0x8000, 0x8007, 0x7FFF ];
+        let (instruction, _) = decode(&mem, 0).unwrap();
+        assert_eq!(instruction, Instruction::Add(Operand::Register(0), Operand::Register(7), Operand::Literal(0x7FFF)));
+        assert_eq!(instruction.to_string(), "add\tr0\tr7\t0x7FFF");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(decode(&[ 22 ], 0), Err(Error::UnknownOpcode { pc: 0, opcode: 22 }));
+        assert_eq!(decode(&[ 0, 1, 0x8008 ], 1), Err(Error::MemoryInvalid { pc: 1, opcode: 1, operand: 0x8008 }));
+        // runs off the end of the image
+        assert_eq!(decode(&[ 0, 6 ], 1), Err(Error::MemoryInvalid { pc: 1, opcode: 6, operand: 2 }));
+    }
+
+    #[test]
+    fn test_jmp_register() {
+        let prog:[u16; 7] = [ 0x0001, 0x8000, 0x0005, 0x0006, 0x8000, 0x0000, 0x0015 ];
+        //                       SET       a  0x0005     JMP       a    HALT    NOOP
         let mut m0 = Machine::new();
-        for n in 0..3 {
-            m0.mem[n] = prog[n];
+        m0.mem[..7].copy_from_slice(&prog);
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(m0.pc, 6);
+    }
+
+    #[test]
+    fn test_asm_spec_example() {
+        // the example program from arch-spec
+        assert_eq!(assemble("add r0 r1 4\nout r0").unwrap(), vec![ 9, 32768, 32769, 4, 19, 32768 ]);
+        assert_eq!(assemble_to_bytes("add r0, r1, 4").unwrap(), vec![ 0x09, 0x00, 0x00, 0x80, 0x01, 0x80, 0x04, 0x00 ]);
+    }
+
+    #[test]
+    fn test_asm_labels_and_directives() {
+        let source = "
+            ; print the string at `text` until the terminating zero
+                    set r0 text
+            loop:   rmem r1 r0
+                    jf r1 done
+                    out r1
+                    add r0 r0 1
+                    jmp loop
+            done:   halt
+            text:   .string \"hi\\n\"
+                    .data 0, 'x', 0x7FFF, 65535, r7, done
+        ";
+        let words = assemble(source).unwrap();
+        assert_eq!(&words[..3], &[ 1, 32768, 18 ]);
+        assert_eq!(&words[15..17], &[ 6, 3 ]);   // jmp loop
+        assert_eq!(&words[18..], &[ 'h' as u16, 'i' as u16, '\n' as u16, 0, 'x' as u16, 0x7FFF, 0xFFFF, 32775, 17 ]);
+
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(output.clone()));
+        for (n, word) in words.iter().enumerate() {
+            m0.write_word(n as u16, *word).unwrap();
+        }
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(output.contents(), "hi\n");
+    }
+
+    #[test]
+    fn test_asm_errors() {
+        assert_eq!(assemble("noop\n  frob r0"), Err(AsmError::new(2, 3, AsmErrorKind::UnknownMnemonic("frob".to_string()))));
+        assert_eq!(assemble("add r0 1"), Err(AsmError::new(1, 1, AsmErrorKind::OperandCount { mnemonic: "add".to_string(), expected: 3, found: 2 })));
+        assert_eq!(assemble("set r0 32768"), Err(AsmError::new(1, 8, AsmErrorKind::LiteralOutOfRange(32768))));
+        assert_eq!(assemble(".data 65536"), Err(AsmError::new(1, 7, AsmErrorKind::LiteralOutOfRange(65536))));
+        assert_eq!(assemble("jmp nowhere"), Err(AsmError::new(1, 5, AsmErrorKind::UnknownLabel("nowhere".to_string()))));
+        assert_eq!(assemble("a: noop\na: noop"), Err(AsmError::new(2, 1, AsmErrorKind::DuplicateLabel("a".to_string()))));
+        assert_eq!(assemble("out 'ab'").unwrap_err().column, 5);
+        assert_eq!(assemble("set r8 1").unwrap_err().kind, AsmErrorKind::UnknownLabel("r8".to_string()));
+    }
+
+    #[test]
+    fn test_asm_org() {
+        assert_eq!(assemble("noop\n.org 3\nhalt").unwrap(), vec![ 21, 0, 0, 0 ]);
+        assert_eq!(assemble("noop\nnoop\n.org 1").unwrap_err().kind, AsmErrorKind::LiteralOutOfRange(1));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let words = assemble("
+            start:  call print
+                    jt r0 start
+                    halt
+            print:  out 'h'
+                    out 'i'
+                    out '\\n'
+                    ret
+                    .data 0x8008, 22
+                    .string \"text\"
+        ").unwrap();
+        let text = disassemble(&words, 0, words.len() as u16 - 1);
+        assert!(text.contains("sub_0006:\n"));
+        assert!(text.contains("loc_0000:\n"));
+        assert!(text.contains("call   sub_0006"));
+        assert!(text.contains("jt     r0 loc_0000"));
+        assert!(text.contains("; \"hi\\n\"\n    out    'h'"));
+        assert!(text.contains(".data 0x8008, 0x0016"));
+        assert!(text.contains(".string \"text\""));
+        assert_eq!(assemble(&text).unwrap(), words);
+
+        // a range that doesn't start at 0 keeps its addresses
+        let text = disassemble(&words, 6, 12);
+        assert!(text.starts_with(".org 0x0006\n"));
+        assert_eq!(&assemble(&text).unwrap()[6..], &words[6..13]);
+    }
+
+    #[test]
+    fn test_disassemble_escape_round_trip() {
+        // a bell in the middle of printed text comes out as \x07, which has to assemble back
+        let words = assemble("out 'h'\nout 7\nout 'i'\nhalt").unwrap();
+        let text = disassemble(&words, 0, words.len() as u16 - 1);
+        assert!(text.contains("\"h\\x07i\""), "{}", text);
+        assert_eq!(assemble(&text).unwrap(), words);
+
+        let text = format!(".string \"{}\"", "a\\x07\\xFF");
+        assert_eq!(assemble(&text).unwrap(), vec![97, 7, 255]);
+        assert!(assemble(".string \"\\x7\"").is_err());
+    }
+
+    #[test]
+    fn test_disassemble_challenge_round_trip() {
+        let words = words_from_bytes(include_bytes!("../challenge.bin"));
+        let text = disassemble(&words, 0, words.len() as u16 - 1);
+        assert_eq!(assemble(&text).unwrap(), words);
+    }
+
+    #[test]
+    fn test_analysis() {
+        let words = assemble("
+            start:  call func
+                    jmp after
+            table:  .data 9, 1, 2       ; looks like an add that swallows the halt
+            after:  halt
+            func:   jf r0 skip
+                    out 'x'
+            skip:   ret
+            extra:  out 'y'             ; only reachable through a register
+                    halt
+        ").unwrap();
+        let analysis = analyze(&words, &[]);
+
+        let blocks:Vec<(u16, u16, Vec<u16>)> = analysis.blocks().map(|b| (b.start, b.end, b.successors.clone())).collect();
+        assert_eq!(blocks, vec![(0, 4, vec![7]), (7, 8, vec![]), (8, 11, vec![11, 13]), (11, 13, vec![13]), (13, 14, vec![])]);
+        assert_eq!(analysis.block_containing(0).unwrap().calls, vec![8]);
+
+        let functions:Vec<(u16, Vec<u16>, Vec<u16>)> = analysis.functions().map(|f| (f.entry, f.blocks.clone(), f.calls.clone())).collect();
+        assert_eq!(functions, vec![(0, vec![0, 7], vec![8]), (8, vec![8, 11, 13], vec![])]);
+        assert_eq!(analysis.functions_containing(12).iter().map(|f| f.entry).collect::<Vec<u16>>(), vec![8]);
+        assert!(analysis.functions_containing(5).is_empty());
+
+        assert!(analysis.is_code(3));
+        assert!(!analysis.is_code(5));
+        assert!(!analysis.is_code(14));
+        assert_eq!(analysis.instruction_at(7), Some(Instruction::Halt));
+
+        // the linear sweep runs through the table, the analysis doesn't
+        assert!(!disassemble(&words, 0, 16).contains("halt                             ; 0x0007"));
+        let text = disassemble_with(&words, 0, words.len() as u16 - 1, &analysis);
+        assert!(text.contains(".data 0x0009, 0x0001, 0x0002"));
+        assert!(text.contains("loc_0007:\n    halt"));
+        assert_eq!(assemble(&text).unwrap(), words);
+
+        let cfg = analysis.cfg_dot();
+        assert!(cfg.starts_with("digraph cfg {"));
+        assert!(cfg.contains("b_0000 -> b_0007;"));
+        assert!(cfg.contains("b_0000 -> b_0008 [style=dashed];"));
+        assert!(analysis.call_graph_dot().contains("sub_0000 -> sub_0008;"));
+
+        let analysis = analyze(&words, &[14]);
+        assert!(analysis.is_code(14));
+        assert_eq!(analysis.functions_containing(16).iter().map(|f| f.entry).collect::<Vec<u16>>(), vec![14]);
+    }
+
+    /**
+     * A machine with `source` assembled into it and no input
+     */
+    fn machine_with(source:&str) -> Machine {
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        for (addr, word) in assemble(source).unwrap().iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
         }
-        m0.run();
+        m0
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut m0 = machine_with("
+                    set r7 0
+            loop:   add r7 r7 1
+                    eq r0 r7 3
+                    jf r0 loop
+                    halt
+        ");
+        let id = m0.debugger_mut().add_breakpoint(3, None);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id, addr: 3 })));
+        assert_eq!(m0.pc(), 3);
+        assert_eq!(m0.register(7), Some(0));    // add hasn't run yet
+
+        // resuming doesn't stop on the same breakpoint straight away
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id, addr: 3 })));
+        assert_eq!(m0.register(7), Some(1));
+
+        // a conditional breakpoint only stops when the condition holds
+        assert!(m0.debugger_mut().remove(id));
+        let condition:Condition = "r7==2".parse().unwrap();
+        let id = m0.debugger_mut().add_breakpoint(3, Some(condition));
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id, addr: 3 })));
+        assert_eq!(m0.register(7), Some(2));
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+
+        assert_eq!("r7 != 0".parse::<Condition>().unwrap().to_string(), "r7 != 0");
+        assert_eq!("[0AAC] >= 0x10".parse::<Condition>().unwrap().to_string(), "[0AAC] >= 16");
+        assert!("r7".parse::<Condition>().is_err());
+        assert!("r8 == 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut m0 = machine_with("
+                    set r1 5
+                    wmem 0x100 r1
+                    rmem r2 0x100
+                    add r3 r2 r2
+                    halt
+        ");
+        let write = m0.debugger_mut().add_watchpoint(WatchTarget::Memory(0x100), Access::Write);
+        let read = m0.debugger_mut().add_watchpoint(WatchTarget::Register(2), Access::Read);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Watchpoint {
+            id: write, target: WatchTarget::Memory(0x100), access: Access::Write, value: 5, pc: 3,
+        })));
+        assert_eq!(m0.read_word(0x100), Ok(5));     // the instruction has run
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Watchpoint {
+            id: read, target: WatchTarget::Register(2), access: Access::Read, value: 5, pc: 9,
+        })));
+        m0.debugger_mut().clear();
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(m0.register(3), Some(10));
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut m0 = machine_with("
+                    call func       ; 0
+                    set r0 1        ; 2
+                    halt            ; 5
+            func:   push 9          ; 6
+                    call inner      ; 8
+                    pop r1          ; 10
+                    ret             ; 12
+            inner:  set r2 2        ; 13
+                    ret             ; 16
+        ");
+        m0.set_run_mode(RunMode::Step(2));
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Stepped)));
+        assert_eq!(m0.pc(), 8);
+        assert_eq!(m0.executed(), 2);
+
+        // next runs the whole call
+        m0.set_run_mode(RunMode::Next);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Stepped)));
+        assert_eq!(m0.pc(), 10);
+        assert_eq!(m0.register(2), Some(2));
+
+        // next on anything else is a single step
+        m0.set_run_mode(RunMode::Next);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Stepped)));
+        assert_eq!(m0.pc(), 12);
+
+        // finish stops once func's ret pops its frame, not at inner's
+        let mut m1 = machine_with("
+                    call func
+                    halt
+            func:   call inner
+                    ret
+            inner:  ret
+        ");
+        m1.set_run_mode(RunMode::Step(2));
+        assert_eq!(m1.run(), Ok(StepOutcome::Stopped(StopReason::Stepped)));
+        assert_eq!(m1.stack().len(), 2);
+        m1.set_run_mode(RunMode::Finish);
+        assert_eq!(m1.run(), Ok(StepOutcome::Stopped(StopReason::Finished { addr: 5 })));
+        m1.set_run_mode(RunMode::Finish);
+        assert_eq!(m1.run(), Ok(StepOutcome::Stopped(StopReason::Finished { addr: 2 })));
+        assert_eq!(m1.run(), Ok(StepOutcome::Halted));
+    }
+
+
+    #[test]
+    fn test_hypervisor_reports() {
+        let mut m0 = machine_with("
+                    push 0x1234
+                    push 7
+                    set r1 0x22
+                    halt
+            text:   .data 'H' 'i' r0 r1
+        ");
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        m0.set_register(2, 40000 % 32768).unwrap();
+
+        let report = hypervisor_controller::registers_report(&m0);
+        assert!(report.contains("r1 0x0022    34"));
+        assert!(report.contains("r2 0x1C40  7232"));
+        assert!(report.contains("pc 0x0008"));
+        assert!(report.contains("[HLT M1 MEMR]"));
+        assert!(report.contains("stack depth 2: 0x0007 0x1234\n"));
+
+        let dump = hypervisor_controller::hexdump(&m0, 0x08, 0x0B);
+        assert_eq!(dump, "0008: 0048 0069 8000 8001                      Hi..      ; 000A=r0 000B=r1 pc->0008\n");
+        let dump = hypervisor_controller::hexdump(&m0, 0x20, 0x2F);
+        assert_eq!(dump.lines().count(), 2);
+        assert!(dump.starts_with("0020: 0000"));
+        assert!(dump.lines().next().unwrap().ends_with("; r1->0022"));
+    }
+
+
+    #[test]
+    fn test_hypervisor_commands() {
+        use crate::hypervisor_controller::{execute, parse_word, Flow, Session};
+
+        let mut m0 = machine_with("halt");
+        m0.set_register(1, 0x0AAC).unwrap();
+        assert_eq!(parse_word(&m0, "0AAC"), Ok(0x0AAC));
+        assert_eq!(parse_word(&m0, "0x0aac"), Ok(0x0AAC));
+        assert_eq!(parse_word(&m0, "#2732"), Ok(0x0AAC));
+        assert_eq!(parse_word(&m0, "r1"), Ok(0x0AAC));
+        assert_eq!(parse_word(&m0, "sub_0AAC"), Ok(0x0AAC));
+        assert!(parse_word(&m0, "r8").is_err());
+        assert!(parse_word(&m0, "#70000").is_err());
+
+        // full words, aliases and unambiguous prefixes all work
+        assert_eq!(execute(&mut m0, "write 100 #65"), Ok(Flow::Stay));
+        assert_eq!(execute(&mut m0, "w r2 0x41"), Ok(Flow::Stay));
+        assert_eq!(execute(&mut m0, "wr r1 r1"), Ok(Flow::Stay));
+        assert_eq!(m0.read_word(0x100), Ok(65));
+        assert_eq!(m0.register(2), Some(0x41));
+        assert_eq!(execute(&mut m0, "label spot 100"), Ok(Flow::Stay));
+        assert_eq!(execute(&mut m0, "b spot if r2 == 65"), Ok(Flow::Stay));
+        assert_eq!(m0.debugger().breakpoints().next().map(|(_, breakpoint)| breakpoint.addr), Some(0x100));
+        assert_eq!(execute(&mut m0, "st 3"), Ok(Flow::Resume));
+        assert_eq!(execute(&mut m0, "  "), Ok(Flow::Stay));
+
+        // mistakes are reported, not panics
+        let e = execute(&mut m0, "x zz 10").unwrap_err();
+        assert!(e.contains("`zz` isn't a number"));
+        assert!(e.contains("usage: examine SSSS EEEE"));
+        assert!(execute(&mut m0, "x 10").unwrap_err().contains("missing EEEE"));
+        assert!(execute(&mut m0, "x 8000 8001").unwrap_err().contains("outside of memory"));
+        assert!(execute(&mut m0, "w 100 9000").unwrap_err().contains("isn't a valid word"));
+        assert!(execute(&mut m0, "de").unwrap_err().contains("could be delete, debug"));
+        assert!(execute(&mut m0, "sl").unwrap_err().contains("unknown command"));
+        assert!(execute(&mut m0, "p extra").unwrap_err().contains("unexpected `extra`"));
+        assert!(execute(&mut m0, "label add").unwrap_err().contains("already means a number"));
+
+        // an empty line repeats the last command, !N picks one from the history
+        let mut session = Session::default();
+        assert_eq!(session.expand(""), Ok(String::new()));
+        assert_eq!(session.expand("step 2\n"), Ok("step 2".to_string()));
+        assert_eq!(session.expand("\n"), Ok("step 2".to_string()));
+        assert_eq!(session.expand("p"), Ok("p".to_string()));
+        assert_eq!(session.expand("!1"), Ok("step 2".to_string()));
+        assert_eq!(session.expand("!!"), Ok("step 2".to_string()));
+        assert!(session.expand("!9").is_err());
     }
-    */
+
+    #[test]
+    fn test_hypervisor_escape() {
+        struct Keyboard(MemoryInput);
+        impl crate::console::InputSource for Keyboard {
+            fn read_byte(&mut self) -> Option<u8> {
+                self.0.read_byte()
+            }
+            fn is_interactive(&self) -> bool {
+                true
+            }
+        }
+
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(Keyboard(MemoryInput::new(b"a.b\n.save x\nc\n"))), Box::new(output.clone()));
+        for (addr, word) in assemble("loop: in r0\nout r0\njmp loop").unwrap().iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+
+        // a `.` in the middle of a line is just input
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Interrupted)));
+        assert_eq!(output.contents(), "a.b\n");
+
+        // at the start of a line it stops on the `in`, and the guest never sees that line
+        assert_eq!(m0.pc(), 0);
+        let executed = m0.executed();
+        assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(output.contents(), "a.b\nc\n");
+        assert_eq!(m0.executed(), executed + 6);
+
+        // restoring a snapshot starts a new line, whatever was half read before
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(Keyboard(MemoryInput::new(b"a.\n"))), Box::new(output.clone()));
+        for (addr, word) in assemble("loop: in r0\nout r0\njmp loop").unwrap().iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        let snapshot = m0.snapshot();
+        for _ in 0..3 {
+            assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::Running));
+        }
+        m0.restore(&snapshot);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Interrupted)));
+        assert_eq!(output.contents(), "a");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut m0 = machine_with("set r0 1\nloop: add r0 r0 1\njmp loop\nhalt");
+        m0.debugger_mut().add_breakpoint(3, None);
+
+        // the flag is picked up before the next instruction, which hasn't run yet
+        m0.interrupt_handle().store(true, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Interrupted)));
+        assert_eq!((m0.pc(), m0.executed(), m0.register(0)), (0, 0, Some(0)));
+
+        // it's cleared, and a breakpoint on the interrupted instruction still gets its turn
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id: 1, addr: 3 })));
+        assert_eq!((m0.pc(), m0.executed(), m0.register(0)), (3, 1, Some(1)));
+
+        // an interrupt right after a breakpoint doesn't make it hit twice
+        m0.interrupt();
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Interrupted)));
+        assert_eq!(m0.fetch_and_execute(), Ok(StepOutcome::Running));
+        assert_eq!((m0.pc(), m0.executed(), m0.register(0)), (7, 2, Some(2)));
+    }
+
+    #[test]
+    fn test_run_fast() {
+        let source = "set r0 1\nloop: add r0 r0 1\neq r1 r0 1000\njf r1 loop\nout 'x'\nin r2\nhalt";
+        let mut m0 = machine_with(source);
+
+        // stops when the budget runs out, then carries on where it was
+        assert_eq!(m0.run_fast(10), Ok(StepOutcome::Running));
+        assert_eq!(m0.executed(), 10);
+        assert_eq!(m0.run_fast(u64::MAX), Ok(StepOutcome::WaitingForInput));
+        assert_eq!((m0.register(0), m0.pc(), m0.executed()), (Some(1000), 16, 1 + 999 * 3 + 1));
+        assert_eq!(m0.recent_output(), b"x");
+
+        // a breakpoint is still seen
+        let mut m0 = machine_with(source);
+        m0.debugger_mut().add_breakpoint(14, None);
+        assert_eq!(m0.run_fast(u64::MAX), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id: 1, addr: 14 })));
+        assert_eq!(m0.register(0), Some(1000));
+
+        m0.queue_input(b"\n");
+        assert_eq!(m0.run_fast(u64::MAX), Ok(StepOutcome::Halted));
+        assert_eq!(m0.run_fast(u64::MAX), Ok(StepOutcome::Halted));
+    }
+
+    #[test]
+    fn test_run_fast_after_breakpoint() {
+        // stop at the top of the loop, drop the breakpoint and go once around it the fast way
+        let mut m0 = machine_with("set r0 1\nloop: add r0 r0 1\neq r1 r0 1000\njf r1 loop\nhalt");
+        let id = m0.debugger_mut().add_breakpoint(3, None);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id, addr: 3 })));
+        m0.debugger_mut().remove(id);
+        assert_eq!(m0.run_fast(3), Ok(StepOutcome::Running));
+        assert_eq!((m0.pc(), m0.register(0)), (3, Some(2)));
+
+        // a new breakpoint there is hit the next time round, not skipped as if resuming
+        let id = m0.debugger_mut().add_breakpoint(3, None);
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Breakpoint { id, addr: 3 })));
+        assert_eq!(m0.register(0), Some(2));
+    }
+
+    #[test]
+    fn test_run_fast_with_debug() {
+        // only the slow path keeps track of memory accesses (and prints each instruction)
+        let mut m0 = machine_with("wmem 100 5\nhalt");
+        assert_eq!(m0.run_fast(1), Ok(StepOutcome::Running));
+        assert!(m0.recent_mem_access.is_empty());
+
+        let mut m0 = machine_with("wmem 100 5\nhalt");
+        m0.debug = true;
+        assert_eq!(m0.run_fast(1), Ok(StepOutcome::Running));
+        assert!(m0.recent_mem_access.contains(&(100, RECENTMEMACCESS_WRITE_BIT)));
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let source = "set r0 5\nloop: add r1 r1 r0\nwmem 0x100 r1\npush r1\npop r2\nadd r0 r0 0x7fff\njt r0 loop\nin r3\nhalt";
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"x")), Box::new(MemoryOutput::new()));
+        for (addr, word) in assemble(source).unwrap().iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        assert_eq!(m0.step_back(1), 0);
+        m0.set_timeline(Some(Timeline::new(100, 3)));
+
+        let state = |m0:&Machine| (m0.pc(), m0.registers, m0.stack().to_vec(), m0.mem[0x100], m0.executed(), m0.is_halted());
+        let mut states = vec![state(&m0)];
+        while m0.fetch_and_execute() == Ok(StepOutcome::Running) {
+            states.push(state(&m0));
+        }
+        states.push(state(&m0));
+        let n = states.len() - 1;
+        assert_eq!(m0.timeline().unwrap().len(), n);
+        assert!(m0.timeline().unwrap().checkpoints() > 1);
+
+        // who wrote what
+        let write = m0.last_write(0x100).unwrap();
+        assert_eq!((write.pc, write.old, write.new), (7, 14, 15));
+        assert_eq!(m0.last_write(TOM as u16 + 3).map(|write| (write.pc, write.new)), Some((21, 'x' as u16)));
+        assert_eq!(m0.last_write(0x200), None);
+
+        // one instruction at a time, all the way back
+        for back in 1..=n {
+            assert_eq!(m0.step_back(1), 1);
+            assert_eq!(state(&m0), states[n - back]);
+        }
+        assert_eq!(m0.step_back(1), 0);
+
+        // the byte the guest read is read again
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(state(&m0), states[n]);
+
+        // a long way back starts from a checkpoint
+        assert_eq!(m0.step_back(n - 2), n - 2);
+        assert_eq!(state(&m0), states[2]);
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(state(&m0), states[n]);
+        assert_eq!(m0.step_back(n + 5), n);
+        assert_eq!(state(&m0), states[0]);
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert_eq!(m0.register(3), Some('x' as u16));
+
+        // back to the last time through the loop, and then to before the previous wmem
+        let id = m0.debugger_mut().add_breakpoint(3, None);
+        assert_eq!(m0.reverse_continue(), Some(StopReason::Breakpoint { id, addr: 3 }));
+        assert_eq!(state(&m0), states[states.iter().rposition(|state| state.0 == 3).unwrap()]);
+        m0.debugger_mut().clear();
+        let id = m0.debugger_mut().add_watchpoint(WatchTarget::Memory(0x100), Access::Write);
+        let reason = m0.reverse_continue();
+        assert_eq!(reason, Some(StopReason::Watchpoint { id, target: WatchTarget::Memory(0x100), access: Access::Write, value: 14, pc: 7 }));
+        assert_eq!((m0.pc(), m0.mem[0x100]), (7, 12));
+
+        // going forwards, the same write stops it again
+        assert!(matches!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Watchpoint { value: 14, .. }))));
+        assert!(matches!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Watchpoint { value: 15, .. }))));
+        m0.debugger_mut().clear();
+        m0.debugger_mut().add_breakpoint(0, None);
+        assert_eq!(m0.reverse_continue(), Some(StopReason::Breakpoint { id: 3, addr: 0 }));
+        assert_eq!(m0.reverse_continue(), None);
+
+        // loading a snapshot starts the log over
+        m0.restore(&m0.snapshot());
+        assert!(m0.timeline().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let source = "loop: in r0\neq r1 r0 'q'\njt r1 end\nout r0\njmp loop\nend: halt";
+        let load = |source:&str, input:&[u8]| {
+            let mut m0 = Machine::with_io(Box::new(MemoryInput::new(input)), Box::new(MemoryOutput::new()));
+            for (addr, word) in assemble(source).unwrap().iter().enumerate() {
+                m0.write_word(addr as u16, *word).unwrap();
+            }
+            m0
+        };
+
+        let path = std::env::temp_dir().join(format!("synacor_replay_{}.rec", std::process::id()));
+        let mut m0 = load(source, b"ab\nq");
+        m0.set_recorder(Some(Recorder::new(Box::new(std::fs::File::create(&path).unwrap())).unwrap()));
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        m0.set_recorder(None);
+        let recording = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the input comes from the recording, not the input source
+        let mut m0 = load(source, b"");
+        m0.set_replay(Some(Replay::from_bytes(&recording).unwrap()));
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert!(m0.replay().unwrap().matched());
+        assert_eq!(m0.replay().unwrap().summary(), "replay matched: 4 bytes of input, 3 bytes of output");
+
+        // different output stops the guest once, then it carries on
+        let mut m0 = load(&source.replace("out r0", "out 'z'"), b"");
+        m0.set_replay(Some(Replay::from_bytes(&recording).unwrap()));
+        let divergence = Divergence::Output { executed: 3, offset: 0, expected: Some(b'a'), found: b'z' };
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Diverged(divergence))));
+        assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+        assert!(!m0.replay().unwrap().matched());
+
+        // so does input read at a different point
+        let mut m0 = load(&format!("noop\n{}", source), b"");
+        m0.set_replay(Some(Replay::from_bytes(&recording).unwrap()));
+        let divergence = Divergence::Input { executed: 1, offset: 0, expected: 0 };
+        assert_eq!(m0.run(), Ok(StepOutcome::Stopped(StopReason::Diverged(divergence))));
+
+        // running out of recorded input is the end of the replay
+        let mut m0 = load(source, b"");
+        m0.set_replay(Some(Replay::from_bytes(&recording[..recording.len() - 30]).unwrap()));
+        assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(m0.replay().unwrap().remaining_input(), 0);
+
+        assert_eq!(Replay::from_bytes(b"SYNSNAP\0\x01\x00").unwrap_err(), FileFormat::Recording.error(FormatErrorKind::BadMagic));
+        assert_eq!(Replay::from_bytes(&recording[..recording.len() - 1]).unwrap_err(), FileFormat::Recording.error(FormatErrorKind::Truncated));
+        assert_eq!(Replay::from_bytes(b"SYNPLAY\0\x02\x00").unwrap_err(), FileFormat::Recording.error(FormatErrorKind::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn test_input_script() {
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"z")), Box::new(output.clone()));
+        for (addr, word) in assemble("loop: in r0\nout r0\njmp loop").unwrap().iter().enumerate() {
+            m0.write_word(addr as u16, *word).unwrap();
+        }
+        let script = Script::parse("ab\n!set r1 300\n!set r3 r0\n!w r2 10\n!label spot 2\n!bogus\ncd\n!!e");
+        assert_eq!(script.remaining(), 8);
+        m0.set_script(Some(script));
+
+        // the commands run once the guest has read "ab\n" and wants the next line, then the
+        // input source takes over
+        assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+        assert_eq!(output.contents(), "ab\ncd\n!e\nz");
+        assert_eq!((m0.register(1), m0.register(2), m0.register(3)), (Some(300), Some(0x10), Some(b'\n' as u16)));
+        assert_eq!(m0.session.labels.get("spot"), Some(&2));
+        assert!(m0.script().is_none());
+
+        assert!(hypervisor_controller::execute(&mut m0, "set r1 70000").unwrap_err().contains("too big"));
+        assert_eq!(hypervisor_controller::execute(&mut m0, "set r1 0x10"), Ok(hypervisor_controller::Flow::Stay));
+        assert_eq!(m0.register(1), Some(0x10));
+    }
+
 }
@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use crate::snapshot::Snapshot;
+
+/**
+ * Something an instruction did that has to be put back to undo it
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Write { addr:u16, old:u16 },    // memory, or a register for TOM..TOM+7
+    Pushed,                         // undone by popping
+    Popped(u16),                    // undone by pushing the value back
+    Input(u8),                      // a byte the guest read, undone by queueing it again
+}
+
+/**
+ * One executed instruction and what it changed
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub executed:u64,   // instructions executed before this one
+    pub pc:u16,
+    pub(crate) status:u16,
+    pub(crate) mid_line:bool,
+    pub changes:Vec<Change>,
+}
+
+/**
+ * Who last wrote an address, see `Timeline::last_write`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastWrite {
+    pub executed:u64,
+    pub pc:u16,
+    pub old:u16,
+    pub new:u16,
+}
+
+/**
+ * The undo log of a machine: the last `capacity` instructions it executed and what they
+ * changed, plus a full snapshot every `interval` instructions so a long step back can start
+ * from one instead of undoing every instruction
+ */
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    steps:VecDeque<Step>,
+    checkpoints:VecDeque<Snapshot>,
+    capacity:usize,
+    interval:u64,
+    current:Option<Step>,   // the instruction being executed
+}
+
+impl Timeline {
+    pub fn new(capacity:usize, interval:u64) -> Self {
+        Timeline {
+            steps: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+            current: None,
+        }
+    }
+
+    /**
+     * Number of instructions that can be undone
+     */
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /**
+     * The instruction count the machine can go back to, if anything was recorded
+     */
+    pub fn oldest(&self) -> Option<u64> {
+        self.steps.front().map(|step| step.executed)
+    }
+
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /**
+     * The latest instruction that wrote `addr` (TOM..TOM+7 for a register), given what's
+     * there now
+     */
+    pub fn last_write(&self, addr:u16, current:u16) -> Option<LastWrite> {
+        self.steps.iter().rev().find_map(|step| {
+            step.changes.iter().rev().find_map(|change| match change {
+                Change::Write { addr: written, old } if *written == addr => {
+                    Some(LastWrite { executed: step.executed, pc: step.pc, old: *old, new: current })
+                },
+                _ => None,
+            })
+        })
+    }
+
+    /**
+     * Forgets everything recorded
+     */
+    pub(crate) fn clear(&mut self) {
+        self.steps.clear();
+        self.checkpoints.clear();
+        self.current = None;
+    }
+
+    pub(crate) fn wants_checkpoint(&self, executed:u64) -> bool {
+        self.checkpoints.back().is_none_or(|checkpoint| executed >= checkpoint.executed + self.interval)
+    }
+
+    pub(crate) fn checkpoint(&mut self, snapshot:Snapshot) {
+        self.checkpoints.push_back(snapshot);
+    }
+
+    pub(crate) fn begin(&mut self, executed:u64, pc:u16, status:u16, mid_line:bool) {
+        self.current = Some(Step { executed, pc, status, mid_line, changes: Vec::new() });
+    }
+
+    pub(crate) fn change(&mut self, change:Change) {
+        if let Some(step) = self.current.as_mut() {
+            step.changes.push(change);
+        }
+    }
+
+    /**
+     * Keeps the instruction being executed. The oldest one goes if the log is full, along
+     * with checkpoints from before what's left
+     */
+    pub(crate) fn commit(&mut self) {
+        if let Some(step) = self.current.take() {
+            if self.steps.len() == self.capacity {
+                self.steps.pop_front();
+            }
+            self.steps.push_back(step);
+        }
+        let oldest = self.oldest().unwrap_or(0);
+        while self.checkpoints.front().is_some_and(|checkpoint| checkpoint.executed < oldest) {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /**
+     * Forgets the instruction being executed, which didn't get to change anything
+     */
+    pub(crate) fn discard(&mut self) {
+        self.current = None;
+    }
+
+    /**
+     * Takes the latest instruction off the log, along with checkpoints from after it
+     */
+    pub(crate) fn pop(&mut self) -> Option<Step> {
+        let step = self.steps.pop_back()?;
+        while self.checkpoints.back().is_some_and(|checkpoint| checkpoint.executed > step.executed) {
+            self.checkpoints.pop_back();
+        }
+        Some(step)
+    }
+
+    /**
+     * The earliest checkpoint no further back than `n` instructions from the end of the log,
+     * and how many instructions it skips
+     */
+    pub(crate) fn checkpoint_within(&self, n:usize) -> Option<(&Snapshot, usize)> {
+        let target = self.steps.len().checked_sub(n).and_then(|i| self.steps.get(i))?.executed;
+        let checkpoint = self.checkpoints.iter().find(|checkpoint| checkpoint.executed >= target)?;
+        let skipped = self.steps.iter().rev().take_while(|step| step.executed >= checkpoint.executed).count();
+        Some((checkpoint, skipped))
+    }
+}
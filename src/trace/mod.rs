@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use crate::constants::TOM;
+use crate::errors::{FileFormat, FormatError, FormatErrorKind};
+use crate::instruction::{Instruction, Operand, OPCODES};
+
+/**
+ * Every binary trace log starts with these bytes
+ */
+pub const MAGIC:&[u8; 8] = b"SYNTRACE";
+pub const VERSION:u16 = 1;
+const FORMAT:FileFormat = FileFormat::TraceLog;
+
+const WRITE_FLAG:u8 = 0x80;     // in a record's opcode byte, set if a write follows
+
+/**
+ * One executed instruction.
+ *
+ * In a binary log, after the magic and a u16 version, each record is (little-endian):
+ *
+ * ```text
+ * executed    u64, instructions executed before this one
+ * pc          u16
+ * opcode      u8, bit 7 set if the instruction wrote somewhere
+ * operands    u16 each, as they're encoded in memory
+ * values      u16 each
+ * write       u16 address, u16 value, only if bit 7 of the opcode is set
+ * ```
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub executed:u64,
+    pub pc:u16,
+    pub instruction:Instruction,
+    pub values:[u16; 3],    // what each operand was worth before the instruction ran
+    pub write:Option<(u16, u16)>,   // (address, value). Addresses from TOM on are registers
+}
+
+impl TraceRecord {
+    pub fn to_bytes(&self, bytes:&mut Vec<u8>) {
+        let operands = self.instruction.operands();
+        let flag = if self.write.is_some() { WRITE_FLAG } else { 0 };
+        bytes.extend_from_slice(&self.executed.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.push(self.instruction.opcode() as u8 | flag);
+        for operand in operands.iter() {
+            bytes.extend_from_slice(&operand.to_word().to_le_bytes());
+        }
+        for value in self.values.iter().take(operands.len()) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        if let Some((addr, value)) = self.write {
+            bytes.extend_from_slice(&addr.to_le_bytes());
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>10}  {:#06X}:\t{}", self.executed, self.pc, self.instruction)?;
+        let mut notes:Vec<String> = Vec::new();
+        for (operand, value) in self.instruction.operands().iter().zip(self.values.iter()) {
+            if let Operand::Register(reg) = operand {
+                notes.push(format!("r{}={:#06X}", reg, value));
+            }
+        }
+        match self.write {
+            Some((addr, value)) if addr as usize >= TOM => notes.push(format!("r{}<-{:#06X}", addr as usize - TOM, value)),
+            Some((addr, value)) => notes.push(format!("[{:#06X}]<-{:#06X}", addr, value)),
+            None => {},
+        }
+        if !notes.is_empty() {
+            write!(f, "\t; {}", notes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Which instructions get recorded. An instruction has to be in one of the address ranges
+ * (if there are any), be one of the opcodes (if there are any) and fall in the window
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub ranges:Vec<(u16, u16)>,     // inclusive
+    pub opcodes:Vec<u16>,
+    pub window:Option<(u64, Option<u64>)>,  // instruction counts, the end is exclusive
+}
+
+impl TraceFilter {
+    pub fn matches(&self, executed:u64, pc:u16, opcode:u16) -> bool {
+        (self.ranges.is_empty() || self.ranges.iter().any(|(start, end)| (*start..=*end).contains(&pc)))
+            && (self.opcodes.is_empty() || self.opcodes.contains(&opcode))
+            && self.window.is_none_or(|(from, to)| executed >= from && to.is_none_or(|to| executed < to))
+    }
+
+    /**
+     * Adds a filter given as text: `addr SSSS[-EEEE]` (hex), `op NAME` or `window N-[M]`
+     * (instruction counts, M exclusive)
+     */
+    pub fn add(&mut self, kind:&str, value:&str) -> Result<(), String> {
+        match kind {
+            "addr" => {
+                let (start, end) = value.split_once('-').unwrap_or((value, value));
+                let (start, end) = (parse_hex(start)?, parse_hex(end)?);
+                if start > end {
+                    return Err(format!("`{}` ends before it starts", value));
+                }
+                self.ranges.push((start, end));
+            },
+            "op" => match OPCODES.iter().position(|(mnemonic, _)| *mnemonic == value) {
+                Some(opcode) => self.opcodes.push(opcode as u16),
+                None => return Err(format!("`{}` isn't an instruction", value)),
+            },
+            "window" => {
+                let parse = |count:&str| count.parse::<u64>().map_err(|_| format!("`{}` isn't an instruction count", count));
+                let (from, to) = value.split_once('-').ok_or_else(|| format!("`{}` isn't N-M or N-", value))?;
+                let to = if to.is_empty() { None } else { Some(parse(to)?) };
+                self.window = Some((parse(from)?, to));
+            },
+            _ => return Err(format!("unknown trace filter `{}` (addr, op or window)", kind)),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TraceFilter {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let mut parts:Vec<String> = Vec::new();
+        for (start, end) in self.ranges.iter() {
+            parts.push(format!("addr {:04X}-{:04X}", start, end));
+        }
+        for opcode in self.opcodes.iter() {
+            parts.push(format!("op {}", OPCODES[*opcode as usize].0));
+        }
+        if let Some((from, to)) = self.window {
+            parts.push(format!("window {}-{}", from, to.map_or(String::new(), |to| to.to_string())));
+        }
+        if parts.is_empty() {
+            write!(f, "everything")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+fn parse_hex(token:&str) -> Result<u16, String> {
+    match u16::from_str_radix(token.strip_prefix("0x").unwrap_or(token), 16) {
+        Ok(addr) if (addr as usize) < TOM => Ok(addr),
+        _ => Err(format!("`{}` isn't an address in hex", token)),
+    }
+}
+
+enum Sink {
+    Ring { records:VecDeque<TraceRecord>, capacity:usize },
+    Binary(Box<dyn Write>),
+    Text(Box<dyn Write>),
+}
+
+/**
+ * Records the instructions a machine executes that get through its filter, either into a
+ * ring buffer that keeps the latest ones, a binary log or a text file
+ */
+pub struct Tracer {
+    filter:TraceFilter,
+    sink:Sink,
+}
+
+impl Tracer {
+    /**
+     * Keeps the last `capacity` records in memory
+     */
+    pub fn ring(capacity:usize) -> Self {
+        Tracer { filter: TraceFilter::default(), sink: Sink::Ring { records: VecDeque::new(), capacity: capacity.max(1) } }
+    }
+
+    /**
+     * Writes a binary log to `out`, starting with the header
+     */
+    pub fn binary(mut out:Box<dyn Write>) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Tracer { filter: TraceFilter::default(), sink: Sink::Binary(out) })
+    }
+
+    /**
+     * Writes `pc` and the instruction to `out`, a line each, like the debug output
+     */
+    pub fn text(out:Box<dyn Write>) -> Self {
+        Tracer { filter: TraceFilter::default(), sink: Sink::Text(out) }
+    }
+
+    pub fn with_filter(mut self, filter:TraceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> &TraceFilter {
+        &self.filter
+    }
+
+    /**
+     * The records in the ring buffer, oldest first. Empty for the other kinds of tracer
+     */
+    pub fn records(&self) -> Vec<&TraceRecord> {
+        match &self.sink {
+            Sink::Ring { records, .. } => records.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let kind = match &self.sink {
+            Sink::Ring { records, capacity } => format!("ring buffer ({} of {} records)", records.len(), capacity),
+            Sink::Binary(_) => "binary log".to_string(),
+            Sink::Text(_) => "text file".to_string(),
+        };
+        format!("tracing {} to a {}", self.filter, kind)
+    }
+
+    pub(crate) fn wants(&self, executed:u64, pc:u16, opcode:u16) -> bool {
+        self.filter.matches(executed, pc, opcode)
+    }
+
+    pub(crate) fn record(&mut self, record:TraceRecord) {
+        // a trace that can't be written to shouldn't stop the guest
+        match &mut self.sink {
+            Sink::Ring { records, capacity } => {
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            },
+            Sink::Binary(out) => {
+                let mut bytes:Vec<u8> = Vec::with_capacity(24);
+                record.to_bytes(&mut bytes);
+                let _ = out.write_all(&bytes);
+            },
+            Sink::Text(out) => {
+                let _ = writeln!(out, "{:#06X}:\t{}", record.pc, record.instruction);
+            },
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if let Sink::Binary(out) | Sink::Text(out) = &mut self.sink {
+            let _ = out.flush();
+        }
+    }
+}
+
+/**
+ * Reads the records of a binary log one at a time
+ */
+pub struct TraceReader<R:Read> {
+    input:R,
+}
+
+impl<R:Read> TraceReader<R> {
+    /**
+     * Checks the header of the log in `input`
+     */
+    pub fn new(mut input:R) -> Result<Self, FormatError> {
+        let mut header = [0u8; 10];
+        input.read_exact(&mut header).map_err(truncated)?;
+        if &header[..8] != MAGIC {
+            return Err(FORMAT.error(FormatErrorKind::BadMagic));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version == 0 || version > VERSION {
+            return Err(FORMAT.error(FormatErrorKind::UnsupportedVersion(version)));
+        }
+        Ok(TraceReader { input })
+    }
+
+    fn next_record(&mut self) -> Result<Option<TraceRecord>, FormatError> {
+        let mut executed = [0u8; 8];
+        match self.input.read(&mut executed[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => self.input.read_exact(&mut executed[1..]).map_err(truncated)?,
+            Err(e) => return Err(FORMAT.io(e)),
+        }
+        let pc = self.u16()?;
+        let mut opcode = [0u8];
+        self.input.read_exact(&mut opcode).map_err(truncated)?;
+        let (opcode, has_write) = ((opcode[0] & !WRITE_FLAG) as u16, opcode[0] & WRITE_FLAG != 0);
+
+        let count = OPCODES.get(opcode as usize).map(|(_, count)| *count)
+            .ok_or_else(|| FORMAT.error(FormatErrorKind::Corrupt(format!("unknown opcode {}", opcode))))?;
+        let mut operands:Vec<Operand> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let word = self.u16()?;
+            operands.push(Operand::from_word(word).ok_or_else(|| FORMAT.error(FormatErrorKind::Corrupt(format!("invalid operand {:#06X}", word))))?);
+        }
+        let mut values = [0u16; 3];
+        for value in values.iter_mut().take(count) {
+            *value = self.u16()?;
+        }
+        let write = if has_write { Some((self.u16()?, self.u16()?)) } else { None };
+
+        Ok(Some(TraceRecord {
+            executed: u64::from_le_bytes(executed),
+            pc,
+            instruction: Instruction::from_parts(opcode, &operands).ok_or_else(|| FORMAT.error(FormatErrorKind::Corrupt(format!("bad instruction at {:#06X}", pc))))?,
+            values,
+            write,
+        }))
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        let mut bytes = [0u8; 2];
+        self.input.read_exact(&mut bytes).map_err(truncated)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+}
+
+impl<R:Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord, FormatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+fn truncated(e:io::Error) -> FormatError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => FORMAT.error(FormatErrorKind::Truncated),
+        _ => FORMAT.io(e),
+    }
+}
@@ -7,11 +7,7 @@ pub fn clear_bit(data:&mut u16, bit_position:u16) {
 }
 
 pub fn get_bit(data:&u16, bit_position:u16) -> bool {
-    if (data & (1 << bit_position)) > 0 {
-        true
-    } else {
-        false
-    }
+    (data & (1 << bit_position)) > 0
 }
 
 /**
@@ -20,5 +16,86 @@ pub fn get_bit(data:&u16, bit_position:u16) -> bool {
  * e.g., 0x00FE returns as 0xFE00, and 0xFE00 returns as 0x00FE
  */
 pub fn swap_endian(ushort:u16) -> u16 {
-    (ushort << 8) | (ushort >> 8)
+    ushort.rotate_right(8)
+}
+
+/**
+ * Converts an image in the 16-bit little-endian format (low byte, high byte) to words.
+ * A trailing odd byte is ignored
+ */
+pub fn words_from_bytes(bytes:&[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2)
+        .map(|pair| (pair[0] as u16) | ((pair[1] as u16) << 8))
+        .collect()
+}
+
+/**
+ * Converts words to the 16-bit little-endian image format
+ */
+pub fn words_to_bytes(words:&[u16]) -> Vec<u8> {
+    let mut bytes:Vec<u8> = Vec::with_capacity(words.len() * 2);
+    for word in words {
+        bytes.push((word & 0x00FF) as u8);
+        bytes.push((word >> 8) as u8);
+    }
+    bytes
+}
+
+/**
+ * Formats seconds since the Unix epoch as a UTC date and time, e.g. `2024-03-09 17:05:42`
+ */
+pub fn format_timestamp(secs:u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // days to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+/**
+ * A small xorshift generator for making up test programs and fuzz inputs. Not for anything
+ * that needs real randomness
+ */
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state:u64,
+}
+
+impl Rng {
+    pub fn new(seed:u64) -> Self {
+        // xorshift gets stuck on 0, and nearby seeds should give unrelated sequences
+        Rng { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /**
+     * A number in 0..n
+     */
+    pub fn below(&mut self, n:u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    pub fn word(&mut self) -> u16 {
+        self.next_u64() as u16
+    }
+
+    pub fn chance(&mut self, percent:u64) -> bool {
+        self.below(100) < percent
+    }
 }
@@ -0,0 +1,340 @@
+//! Spec conformance: small assembled programs run against `Machine`, checked against what
+//! `arch-spec` says they should print and leave in registers, memory and the stack
+
+use synacor_cpu::assembler::{assemble, assemble_to_bytes};
+use synacor_cpu::console::{MemoryInput, MemoryOutput};
+use synacor_cpu::{Engine, Error, Machine, StepOutcome};
+
+/**
+ * Assembles `source`, runs it to the end with `input` and returns the machine and its output.
+ * It's run with `run` and then with each engine of `run_fast`, which all have to end up the same
+ */
+fn run_with(source:&str, input:&[u8]) -> (Machine, String) {
+    let start = |engine:Engine| {
+        let output = MemoryOutput::new();
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(input)), Box::new(output.clone()));
+        m0.load_image(&assemble_to_bytes(source).unwrap()).unwrap();
+        m0.set_engine(engine);
+        (m0, output)
+    };
+
+    let (mut m0, output) = start(Engine::Interpreter);
+    assert_eq!(m0.run(), Ok(StepOutcome::Halted), "program didn't halt:\n{}", source);
+    for engine in [Engine::Interpreter, Engine::Blocks].iter() {
+        let (mut fast, fast_output) = start(*engine);
+        assert_eq!(fast.run_fast(u64::MAX), Ok(StepOutcome::Halted), "{:?} didn't halt:\n{}", engine, source);
+        assert_eq!(fast.snapshot().registers, m0.snapshot().registers, "{:?}:\n{}", engine, source);
+        assert_eq!(fast.snapshot().mem, m0.snapshot().mem, "{:?}:\n{}", engine, source);
+        assert_eq!((fast.pc(), fast.stack(), fast.executed()), (m0.pc(), m0.stack(), m0.executed()), "{:?}:\n{}", engine, source);
+        assert_eq!(fast_output.contents(), output.contents(), "{:?}:\n{}", engine, source);
+    }
+    (m0, output.contents())
+}
+
+fn run(source:&str) -> (Machine, String) {
+    run_with(source, b"")
+}
+
+/**
+ * Runs `op r0 B C` with `b` and `c` as literals and from registers, in every combination,
+ * and checks r0 each time
+ */
+fn check_binary(op:&str, b:u16, c:u16, expected:u16) {
+    let variants = [
+        format!("{} r0 {} {}\nhalt", op, b, c),
+        format!("set r1 {}\n{} r0 r1 {}\nhalt", b, op, c),
+        format!("set r2 {}\n{} r0 {} r2\nhalt", c, op, b),
+        format!("set r1 {}\nset r2 {}\n{} r0 r1 r2\nhalt", b, c, op),
+    ];
+    for source in variants.iter() {
+        let (m0, _) = run(source);
+        assert_eq!(m0.register(0), Some(expected), "{} {} {}:\n{}", op, b, c, source);
+    }
+}
+
+/**
+ * Same for `op r0 B`
+ */
+fn check_unary(op:&str, b:u16, expected:u16) {
+    let variants = [
+        format!("{} r0 {}\nhalt", op, b),
+        format!("set r1 {}\n{} r0 r1\nhalt", b, op),
+    ];
+    for source in variants.iter() {
+        let (m0, _) = run(source);
+        assert_eq!(m0.register(0), Some(expected), "{} {}:\n{}", op, b, source);
+    }
+}
+
+#[test]
+fn halt() {
+    let (m0, output) = run("halt\nout 'x'");
+    assert_eq!(output, "");
+    assert_eq!(m0.pc(), 1);
+    assert_eq!(m0.executed(), 1);
+}
+
+#[test]
+fn set() {
+    check_unary("set", 0, 0);
+    check_unary("set", 32767, 32767);
+
+    let (m0, _) = run("set r7 12\nset r3 r7\nhalt");
+    assert_eq!(m0.register(3), Some(12));
+    assert_eq!(m0.register(7), Some(12));
+}
+
+#[test]
+fn push_and_pop() {
+    let (m0, _) = run("push 1\nset r0 2\npush r0\npop r1\npop r2\nhalt");
+    assert_eq!(m0.register(1), Some(2));
+    assert_eq!(m0.register(2), Some(1));
+    assert!(m0.stack().is_empty());
+
+    let (m0, _) = run("push 3\npush 4\nhalt");
+    assert_eq!(m0.stack(), &[3, 4]);
+}
+
+/**
+ * Runs `source` with `run` and each engine of `run_fast`, expecting `error` from all of them
+ */
+fn check_fault(source:&str, error:Error) {
+    let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+    m0.load_image(&assemble_to_bytes(source).unwrap()).unwrap();
+    assert_eq!(m0.run(), Err(error.clone()), "{}", source);
+    for engine in [Engine::Interpreter, Engine::Blocks].iter() {
+        let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+        m0.load_image(&assemble_to_bytes(source).unwrap()).unwrap();
+        m0.set_engine(*engine);
+        assert_eq!(m0.run_fast(u64::MAX), Err(error.clone()), "{:?}: {}", engine, source);
+    }
+}
+
+#[test]
+fn pop_on_empty_stack_is_an_error() {
+    check_fault("noop\npop r0\nhalt", Error::EmptyStack { pc: 1, opcode: 3 });
+}
+
+#[test]
+fn eq() {
+    check_binary("eq", 5, 5, 1);
+    check_binary("eq", 5, 6, 0);
+    check_binary("eq", 0, 32767, 0);
+}
+
+#[test]
+fn gt() {
+    check_binary("gt", 6, 5, 1);
+    check_binary("gt", 5, 5, 0);
+    check_binary("gt", 5, 6, 0);
+    check_binary("gt", 32767, 0, 1);
+}
+
+#[test]
+fn jmp() {
+    let (_, output) = run("jmp skip\nout 'x'\nskip: out 'y'\nhalt");
+    assert_eq!(output, "y");
+    let (_, output) = run("set r4 skip\njmp r4\nout 'x'\nskip: out 'y'\nhalt");
+    assert_eq!(output, "y");
+}
+
+#[test]
+fn jt_and_jf() {
+    // every combination of taken or not, and condition and target from a literal or a register
+    let cases = [("jt", 1, "y"), ("jt", 0, "xy"), ("jt", 32767, "y"), ("jf", 0, "y"), ("jf", 1, "xy")];
+    for (op, condition, expected) in cases.iter() {
+        let variants = [
+            format!("{} {} skip\nout 'x'\nskip: out 'y'\nhalt", op, condition),
+            format!("set r0 {}\n{} r0 skip\nout 'x'\nskip: out 'y'\nhalt", condition, op),
+            format!("set r1 skip\n{} {} r1\nout 'x'\nskip: out 'y'\nhalt", op, condition),
+            format!("set r0 {}\nset r1 skip\n{} r0 r1\nout 'x'\nskip: out 'y'\nhalt", condition, op),
+        ];
+        for source in variants.iter() {
+            let (_, output) = run(source);
+            assert_eq!(output, *expected, "{}", source);
+        }
+    }
+}
+
+#[test]
+fn add() {
+    check_binary("add", 2, 3, 5);
+    check_binary("add", 32758, 15, 5);     // the example in the spec
+    check_binary("add", 32767, 1, 0);
+    check_binary("add", 32767, 32767, 32766);
+}
+
+#[test]
+fn mult() {
+    check_binary("mult", 6, 7, 42);
+    check_binary("mult", 16384, 2, 0);
+    check_binary("mult", 32767, 32767, 1);
+    check_binary("mult", 300, 200, (300 * 200) % 32768);
+}
+
+#[test]
+fn modulo() {
+    check_binary("mod", 17, 5, 2);
+    check_binary("mod", 4, 5, 4);
+    check_binary("mod", 32767, 32767, 0);
+
+    // registers can be given 16-bit values with rmem, but math still comes out 15-bit
+    let (m0, _) = run("rmem r1 big\nrmem r2 bigger\nmod r0 r1 r2\nhalt\nbig: .data 40000\nbigger: .data 50000");
+    assert_eq!(m0.register(0), Some(40000 % 32768));
+}
+
+#[test]
+fn mod_by_zero_is_an_error() {
+    check_fault("mod r0 1 0\nhalt", Error::DivideByZero { pc: 0, opcode: 11 });
+}
+
+#[test]
+fn and_or() {
+    check_binary("and", 0b1100, 0b1010, 0b1000);
+    check_binary("and", 32767, 12345, 12345);
+    check_binary("or", 0b1100, 0b1010, 0b1110);
+    check_binary("or", 16384, 1, 16385);
+}
+
+#[test]
+fn not() {
+    check_unary("not", 0, 32767);
+    check_unary("not", 32767, 0);
+    check_unary("not", 0b101, 32767 - 0b101);
+}
+
+#[test]
+fn rmem() {
+    let (m0, _) = run("rmem r0 data\nset r1 data\nrmem r2 r1\nhalt\ndata: .data 1234");
+    assert_eq!(m0.register(0), Some(1234));
+    assert_eq!(m0.register(2), Some(1234));
+
+    // a value that looks like a register is still just a value
+    let (m0, _) = run("set r1 7\nrmem r0 data\nhalt\ndata: .data 32769");
+    assert_eq!(m0.register(0), Some(32769));
+}
+
+#[test]
+fn wmem() {
+    let variants = [
+        "wmem data 42\nhalt\ndata: .data 0",
+        "set r0 data\nwmem r0 42\nhalt\ndata: .data 0",
+        "set r1 42\nwmem data r1\nhalt\ndata: .data 0",
+        "set r0 data\nset r1 42\nwmem r0 r1\nhalt\ndata: .data 0",
+    ];
+    for source in variants.iter() {
+        let (m0, _) = run(source);
+        let data = assemble(source).unwrap().len() as u16 - 1;
+        assert_eq!(m0.read_word(data), Ok(42), "{}", source);
+    }
+}
+
+#[test]
+fn self_modifying_code() {
+    // overwrites the operand of the second `out` before it runs
+    let (_, output) = run("wmem patch 'y'\nout 'x'\n.data 19\npatch: .data 'x'\nhalt");
+    assert_eq!(output, "xy");
+}
+
+#[test]
+fn call_and_ret() {
+    let (m0, output) = run("call sub\nout 'b'\nhalt\nsub: out 'a'\nret");
+    assert_eq!(output, "ab");
+    assert!(m0.stack().is_empty());
+
+    let (_, output) = run("set r5 sub\ncall r5\nout 'b'\nhalt\nsub: out 'a'\nret");
+    assert_eq!(output, "ab");
+
+    // `call` pushes the address of the next instruction
+    let (m0, _) = run("call sub\nhalt\nsub: halt");
+    assert_eq!(m0.stack(), &[2]);
+}
+
+#[test]
+fn ret_on_empty_stack_halts() {
+    let (m0, output) = run("out 'a'\nret\nout 'b'\nhalt");
+    assert_eq!(output, "a");
+    assert!(m0.is_halted());
+    assert_eq!(m0.executed(), 2);
+
+    // returning from a call empties the stack, and a second `ret` halts
+    let (_, output) = run("call sub\nout 'c'\nret\nout 'x'\nsub: out 'a'\nret");
+    assert_eq!(output, "ac");
+}
+
+#[test]
+fn out() {
+    let (_, output) = run("out 'h'\nset r0 'i'\nout r0\nout 10\nhalt");
+    assert_eq!(output, "hi\n");
+}
+
+#[test]
+fn read_in() {
+    let (m0, output) = run_with("in r0\nin r1\nout r1\nout r0\nin r2\nhalt", b"ab\n");
+    assert_eq!(output, "ba");
+    assert_eq!(m0.register(0), Some('a' as u16));
+    assert_eq!(m0.register(2), Some('\n' as u16));
+}
+
+#[test]
+fn read_in_waits_for_more_input() {
+    let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+    m0.load_image(&assemble_to_bytes("noop\nin r0\nhalt").unwrap()).unwrap();
+    assert_eq!(m0.run(), Ok(StepOutcome::WaitingForInput));
+    assert_eq!(m0.pc(), 1);
+
+    m0.queue_input(b"z");
+    assert_eq!(m0.run(), Ok(StepOutcome::Halted));
+    assert_eq!(m0.register(0), Some('z' as u16));
+}
+
+#[test]
+fn noop() {
+    let (m0, _) = run("noop\nnoop\nhalt");
+    assert_eq!(m0.pc(), 3);
+    assert_eq!(m0.executed(), 3);
+}
+
+#[test]
+fn registers_are_operands_in_every_slot() {
+    // the hint program from the spec: r0 = r1 + 4, then print r0
+    let (m0, output) = run("set r1 61\nadd r0 r1 4\nout r0\nhalt");
+    assert_eq!(output, "A");
+    assert_eq!(m0.register(0), Some(65));
+
+    // every register can be written and read back
+    let source:String = (0..8).map(|reg| format!("add r{} {} 1\n", reg, reg * 10)).collect::<String>()
+        + &(0..8).map(|reg| format!("out r{}\n", reg)).collect::<String>() + "halt";
+    let (m0, output) = run(&source);
+    for reg in 0..8 {
+        assert_eq!(m0.register(reg), Some(reg as u16 * 10 + 1));
+    }
+    assert_eq!(output.len(), 8);
+}
+
+#[test]
+fn invalid_operand_is_an_error() {
+    check_fault("noop\n.data 1, 32776, 5\nhalt", Error::MemoryInvalid { pc: 1, opcode: 1, operand: 32776 });
+}
+
+#[test]
+fn unknown_opcode_is_an_error() {
+    check_fault(".data 22", Error::UnknownOpcode { pc: 0, opcode: 22 });
+}
+
+#[test]
+fn self_modifying_loop() {
+    // rewrites the `add` inside the loop it's running, after the loop has been run a few times
+    let source = "
+        loop:   add r0 r0 1
+        patch:  add r1 r1 1
+                eq r2 r0 5
+                jf r2 skip
+                wmem patch 12
+        skip:   gt r3 r0 9
+                jf r3 loop
+                halt";
+    let (m0, _) = run(source);
+    assert_eq!(m0.register(0), Some(10));
+    assert_eq!(m0.register(1), Some(5 & 1));    // `and r1 r1 1` from the sixth time on
+}
@@ -0,0 +1,54 @@
+//! `Machine` against the reference interpreter on made-up programs
+
+use synacor_cpu::reference::{differential, differential_fast, random_program};
+use synacor_cpu::utils::Rng;
+use synacor_cpu::Engine;
+
+const PROGRAMS:u64 = 500;
+const STEPS:u64 = 2000;
+
+#[test]
+fn random_programs() {
+    let mut total:u64 = 0;
+    for seed in 0..PROGRAMS {
+        let mut rng = Rng::new(seed);
+        let length = 8 + rng.below(56) as usize;
+        let program = random_program(&mut rng, length);
+        let input:Vec<u8> = (0..rng.below(32)).map(|_| 32 + rng.below(95) as u8).collect();
+        match differential(&program, &input, STEPS) {
+            Ok(steps) => total += steps,
+            Err(mismatch) => panic!("seed {}: {}\nprogram: {:?}", seed, mismatch, program),
+        }
+        // a stride of one checks every instruction, a longer one lets whole blocks run
+        for (engine, stride) in [(Engine::Interpreter, 1), (Engine::Blocks, 1), (Engine::Blocks, 97)].iter() {
+            if let Err(mismatch) = differential_fast(&program, &input, STEPS, *engine, *stride) {
+                panic!("seed {}, run_fast with {:?} every {}: {}\nprogram: {:?}", seed, engine, stride, mismatch, program);
+            }
+        }
+    }
+    // most programs should get somewhere before halting or faulting
+    assert!(total > PROGRAMS * 20, "only {} steps in {} programs", total, PROGRAMS);
+}
+
+#[test]
+fn spec_example() {
+    // the program from the hints in `arch-spec`
+    assert_eq!(differential(&[9, 32768, 32769, 4, 19, 32768], b"", 10), Ok(3));
+    assert_eq!(differential_fast(&[9, 32768, 32769, 4, 19, 32768], b"", 10, Engine::Blocks, 1), Ok(3));
+}
+
+#[test]
+fn register_words_read_from_memory() {
+    // `rmem` picks up the word 32768 from the code, then uses it as an address (a register),
+    // or `and`s it with itself, which has to be kept to 15 bits
+    let programs:[&[u16]; 2] = [
+        &[15, 32768, 1, 15, 32769, 32768, 0],
+        &[15, 32768, 1, 12, 32769, 32768, 32768, 0],
+    ];
+    for program in programs.iter() {
+        assert_eq!(differential(program, b"", 10), Ok(3), "{:?}", program);
+        for engine in [Engine::Interpreter, Engine::Blocks].iter() {
+            assert_eq!(differential_fast(program, b"", 10, *engine, 1), Ok(3), "{:?} {:?}", engine, program);
+        }
+    }
+}
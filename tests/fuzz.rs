@@ -0,0 +1,63 @@
+//! The fuzz targets in `synacor_cpu::fuzz`, run offline on mutated seeds. `fuzz/` runs the
+//! same targets under libFuzzer for longer
+
+use synacor_cpu::assembler::assemble_to_bytes;
+use synacor_cpu::console::{MemoryInput, MemoryOutput};
+use synacor_cpu::fuzz::{fuzz, TARGETS};
+use synacor_cpu::utils::Rng;
+use synacor_cpu::Machine;
+
+const RUNS:u64 = 2000;
+
+const PROGRAM:&str = "
+        set r0 10
+loop:   add r1 r1 r0
+        mult r2 r1 32767
+        mod r3 r2 7
+        not r4 r3
+        and r5 r4 r1
+        or r6 r5 1
+        push r6
+        call sub
+        wmem data r1
+        rmem r7 data
+        in r0
+        jt r0 loop
+        halt
+sub:    pop r0
+        gt r0 r0 r3
+        eq r1 r0 0
+        jf r1 done
+        out 'x'
+done:   ret
+data:   .data 32775, 65535
+";
+
+fn seeds(target:&str) -> Vec<Vec<u8>> {
+    let image = assemble_to_bytes(PROGRAM).unwrap();
+    match target {
+        "load_image" => vec![
+            image,
+            b"9,32768,32769,4,19,32768".to_vec(),
+            b":0C00000009000080018004001300008053\n:00000001FF\n".to_vec(),
+        ],
+        "execute" => vec![[b"\x03ab\n".to_vec(), image].concat()],
+        "snapshot" => {
+            let mut m0 = Machine::with_io(Box::new(MemoryInput::new(b"")), Box::new(MemoryOutput::new()));
+            m0.load_image(&image).unwrap();
+            m0.queue_input(b"go\n");
+            vec![m0.snapshot().to_bytes(true)]
+        },
+        _ => vec![image],
+    }
+}
+
+#[test]
+fn targets_dont_panic() {
+    for (seed, (name, target)) in TARGETS.iter().enumerate() {
+        let mut rng = Rng::new(seed as u64);
+        if let Err((input, message)) = fuzz(*target, &seeds(name), RUNS, &mut rng) {
+            panic!("{} panicked: {}\ninput: {:?}", name, message, input);
+        }
+    }
+}